@@ -6,12 +6,22 @@ use url::Url;
 use valuescript_compiler::{Diagnostic, DiagnosticLevel, ResolvedPath};
 
 pub fn handle_diagnostics_cli(diagnostics: &HashMap<ResolvedPath, Vec<Diagnostic>>) {
-  for (file_path, file_diagnostics) in diagnostics {
-    handle_file_diagnostics_cli(&file_path.path, file_diagnostics);
+  if report_diagnostics_cli(diagnostics) > 0 {
+    std::process::exit(1);
   }
 }
 
-fn handle_file_diagnostics_cli(file_path: &String, diagnostics: &Vec<Diagnostic>) {
+/// Prints the diagnostics without exiting, and returns the number of errors.
+pub fn report_diagnostics_cli(diagnostics: &HashMap<ResolvedPath, Vec<Diagnostic>>) -> usize {
+  diagnostics
+    .iter()
+    .map(|(file_path, file_diagnostics)| {
+      report_file_diagnostics_cli(&file_path.path, file_diagnostics)
+    })
+    .sum()
+}
+
+fn report_file_diagnostics_cli(file_path: &String, diagnostics: &Vec<Diagnostic>) -> usize {
   let path = 'b: {
     if file_path == "(str)" {
       // TODO: Fix this hack
@@ -106,9 +116,7 @@ fn handle_file_diagnostics_cli(file_path: &String, diagnostics: &Vec<Diagnostic>
     println!();
   }
 
-  if total_error_count > 0 {
-    std::process::exit(1);
-  }
+  total_error_count
 }
 
 fn pos_to_line_col(text: &str, pos: u32) -> (u32, u32) {
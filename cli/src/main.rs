@@ -2,49 +2,174 @@ use std::{
   fs::{self, File},
   io::BufWriter,
   path::Path,
+  time::Duration,
 };
 
 use boolify::boolify;
-use handle_diagnostics_cli::handle_diagnostics_cli;
-use serde_json::to_string_pretty;
-use summon_compiler::{bristol_depth, compile, resolve_entry_path, CompileOk};
+use handle_diagnostics_cli::{handle_diagnostics_cli, report_diagnostics_cli};
+use serde_json::{to_string_pretty, to_value, Value};
+use summon_compiler::{
+  bristol_depth, compile_all_exports, compile_with_options, resolve_entry_path, Circuit,
+  CompileAllOk, CompileErr, CompileOk, CompileOptions, LogRecord, ResolvedPath, SchedulingStrategy,
+};
 
 mod handle_diagnostics_cli;
 
-fn main() {
-  let args: Vec<String> = std::env::args().collect();
+const USAGE: &str = "\
+Usage: summonc main.ts [options]
+
+Options:
+  --boolify-width WIDTH|auto  Produce a boolean circuit (auto uses the width from range analysis)
+  --error-codes               Add an error_code output for uncaught exceptions
+  --const NAME=VALUE          Bind the entry parameter NAME to VALUE during compilation
+  --entry NAME                Compile the export NAME instead of the default export
+  --all-exports               Compile every exported function into output/<export name>/
+  --max-recursion-depth N     Limit every function to N frames on the stack (see @maxDepth)
+  --max-steps N|none          Fail after N VM steps (default 100000000)
+  --max-branches N            Fail when more than N branches are alive at once
+  --max-signals N             Fail after creating N signals
+  --timeout SECONDS|none      Fail after running the program for SECONDS (default 300)
+  --max-loop-forks N          Report a loop as non-converging after it forks N times (default 10000)
+  --scheduler STRATEGY        How to choose the next branch to run: earliest-position (default),
+                              most-forks-first or merge-eagerly";
+
+/// The default for `--max-steps`, so that programs that never finish (eg loops bounded by signals)
+/// fail instead of running forever.
+const DEFAULT_MAX_STEPS: usize = 100_000_000;
+
+/// The default for `--timeout`, in seconds.
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+enum BoolifyWidth {
+  Fixed(usize),
+
+  /// Use the width found by range analysis.
+  Auto,
+}
 
-  if args.len() != 2 && args.len() != 4 {
-    eprintln!("Usage: summonc main.ts [--boolify-width WIDTH]");
-    std::process::exit(1);
+struct Args {
+  entry_path: String,
+  boolify_width: Option<BoolifyWidth>,
+  options: CompileOptions,
+  all_exports: bool,
+}
+
+fn parse_args() -> Args {
+  let mut entry_path = None;
+  let mut boolify_width = None;
+  let mut options = CompileOptions::default();
+  let mut all_exports = false;
+
+  options.limits.max_steps = Some(DEFAULT_MAX_STEPS);
+  options.limits.timeout = Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+
+  let mut args = std::env::args().skip(1);
+
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--boolify-width" => {
+        boolify_width = Some(match args.next().as_deref() {
+          Some("auto") => BoolifyWidth::Auto,
+          Some(width) => match width.parse::<usize>() {
+            Ok(width) => BoolifyWidth::Fixed(width),
+            Err(_) => exit_with_usage(),
+          },
+          None => exit_with_usage(),
+        });
+      }
+      "--error-codes" => options.error_codes = true,
+      "--const" => {
+        let (name, value) = match args.next().as_deref().and_then(parse_const) {
+          Some(const_) => const_,
+          None => exit_with_usage(),
+        };
+
+        options.consts.insert(name, value);
+      }
+      "--entry" => options.entry = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+      "--all-exports" => all_exports = true,
+      "--max-recursion-depth" => {
+        options.max_recursion_depth = match args.next().map(|depth| depth.parse::<usize>()) {
+          Some(Ok(depth)) if depth > 0 => Some(depth),
+          _ => exit_with_usage(),
+        };
+      }
+      "--max-steps" => {
+        options.limits.max_steps = match args.next().as_deref() {
+          Some("none") => None,
+          limit => Some(parse_limit(limit.map(str::to_string))),
+        };
+      }
+      "--max-branches" => options.limits.max_branches = Some(parse_limit(args.next())),
+      "--max-signals" => options.limits.max_signals = Some(parse_limit(args.next())),
+      "--scheduler" => {
+        options.scheduling_strategy = match args.next().as_deref() {
+          Some("earliest-position") => SchedulingStrategy::EarliestPosition,
+          Some("most-forks-first") => SchedulingStrategy::MostForksFirst,
+          Some("merge-eagerly") => SchedulingStrategy::MergeEagerly,
+          _ => exit_with_usage(),
+        };
+      }
+      "--max-loop-forks" => options.limits.max_loop_forks = Some(parse_limit(args.next())),
+      "--timeout" => {
+        options.limits.timeout = match args.next().as_deref() {
+          Some("none") => None,
+          Some(secs) => match secs.parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+            _ => exit_with_usage(),
+          },
+          None => exit_with_usage(),
+        };
+      }
+      _ if entry_path.is_none() && !arg.starts_with("--") => entry_path = Some(arg),
+      _ => exit_with_usage(),
+    }
   }
 
-  let boolify_width = if args.len() == 4 {
-    assert_eq!(args[2], "--boolify-width");
-    Some(args[3].parse::<usize>().unwrap())
-  } else {
-    None
-  };
+  if all_exports && options.entry.is_some() {
+    eprintln!("--entry and --all-exports can't be used together\n");
+    exit_with_usage();
+  }
 
-  let entry_point = resolve_entry_path(&args[1]);
+  Args {
+    entry_path: entry_path.unwrap_or_else(|| exit_with_usage()),
+    boolify_width,
+    options,
+    all_exports,
+  }
+}
 
-  let compile_result = compile(entry_point, |path| {
-    fs::read_to_string(path).map_err(|e| e.to_string())
-  });
+/// Parses `name=value`.
+fn parse_const(arg: &str) -> Option<(String, f64)> {
+  let (name, value) = arg.split_once('=')?;
 
-  let diagnostics = match &compile_result {
-    Ok(ok) => &ok.diagnostics,
-    Err(err) => &err.diagnostics,
-  };
+  Some((name.to_string(), value.parse().ok()?))
+}
 
-  handle_diagnostics_cli(diagnostics);
+/// Parses the value of a `--max-*` flag, which must be a positive integer.
+fn parse_limit(arg: Option<String>) -> usize {
+  match arg.map(|limit| limit.parse::<usize>()) {
+    Some(Ok(limit)) if limit > 0 => limit,
+    _ => exit_with_usage(),
+  }
+}
 
-  let CompileOk {
-    circuit,
-    diagnostics,
-  } = compile_result.expect("Error should have caused earlier exit");
+fn exit_with_usage() -> ! {
+  eprintln!("{}", USAGE);
+  std::process::exit(1);
+}
 
-  handle_diagnostics_cli(&diagnostics);
+fn main() {
+  let Args {
+    entry_path,
+    boolify_width,
+    options,
+    all_exports,
+  } = parse_args();
+
+  let entry_point = resolve_entry_path(&entry_path);
+
+  let read_file = |path: &str| fs::read_to_string(path).map_err(|e| e.to_string());
 
   let output_dir = Path::new("output");
 
@@ -54,8 +179,101 @@ fn main() {
 
   fs::create_dir(output_dir).unwrap();
 
+  if all_exports {
+    let CompileAllOk {
+      entries,
+      diagnostics,
+    } = match compile_all_exports(entry_point.clone(), &options, read_file) {
+      Ok(ok) => ok,
+      Err(err) => exit_with_error(err, &entry_point),
+    };
+
+    handle_diagnostics_cli(&diagnostics);
+
+    let mut failed_exports = Vec::<String>::new();
+
+    for (export_name, compile_result) in entries {
+      println!("\n{}:", export_name);
+
+      match compile_result {
+        Ok(CompileOk {
+          circuit,
+          diagnostics,
+          logs,
+        }) => {
+          print_logs(&logs);
+          handle_diagnostics_cli(&diagnostics);
+
+          let export_dir = output_dir.join(&export_name);
+          fs::create_dir(&export_dir).unwrap();
+          write_circuit(&circuit, &export_dir, &boolify_width);
+        }
+        Err(err) => {
+          // Report the failure without exiting so that the other exports are still compiled
+          print_logs(err.logs());
+          report_diagnostics_cli(&err.into_diagnostics(&entry_point));
+          failed_exports.push(export_name);
+        }
+      }
+    }
+
+    if !failed_exports.is_empty() {
+      println!("\nFailed to compile: {}", failed_exports.join(", "));
+      std::process::exit(1);
+    }
+
+    return;
+  }
+
+  let CompileOk {
+    circuit,
+    diagnostics,
+    logs,
+  } = match compile_with_options(entry_point.clone(), &options, read_file) {
+    Ok(ok) => ok,
+    Err(err) => exit_with_error(err, &entry_point),
+  };
+
+  print_logs(&logs);
+  handle_diagnostics_cli(&diagnostics);
+
+  write_circuit(&circuit, output_dir, &boolify_width);
+}
+
+fn exit_with_error(err: CompileErr, entry_point: &ResolvedPath) -> ! {
+  print_logs(err.logs());
+  handle_diagnostics_cli(&err.into_diagnostics(entry_point));
+
+  // Diagnostics containing errors should have already caused an exit
+  std::process::exit(1);
+}
+
+/// Prints what the program logged with `summon.log`, in the order it was run.
+fn print_logs(logs: &[LogRecord]) {
+  for log in logs {
+    eprintln!("{}", log);
+  }
+}
+
+fn write_circuit(circuit: &Circuit, dir: &Path, boolify_width: &Option<BoolifyWidth>) {
   let mut bristol_circuit = circuit.to_bristol();
 
+  let boolify_width = match boolify_width {
+    Some(BoolifyWidth::Fixed(width)) => Some(*width),
+    Some(BoolifyWidth::Auto) => match circuit.bit_width {
+      Some(width) => {
+        println!("Inferred boolify width: {}", width);
+        Some(width)
+      }
+      None => {
+        eprintln!("Could not infer boolify width because some values are unbounded");
+        eprintln!("Annotate the input ranges (eg `x: number /* 0..100 */`) or use a fixed width");
+        std::process::exit(1);
+      }
+    },
+    None => None,
+  };
+
   if let Some(boolify_width) = boolify_width {
     bristol_circuit = boolify(&bristol_circuit, boolify_width)
   }
@@ -67,17 +285,21 @@ fn main() {
     bristol_depth(&bristol_circuit),
   );
 
+  let circuit_path = dir.join("circuit.txt");
+
   bristol_circuit
-    .write_bristol(&mut BufWriter::new(
-      File::create("output/circuit.txt").unwrap(),
-    ))
+    .write_bristol(&mut BufWriter::new(File::create(&circuit_path).unwrap()))
     .unwrap();
-  println!("output/circuit.txt");
-
-  fs::write(
-    "output/circuit_info.json",
-    to_string_pretty(&bristol_circuit.info).unwrap(),
-  )
-  .unwrap();
-  println!("output/circuit_info.json");
+  println!("{}", circuit_path.display());
+
+  let mut info = to_value(&bristol_circuit.info).unwrap();
+
+  if let Value::Object(info) = &mut info {
+    info.extend(circuit.extra_info());
+  }
+
+  let info_path = dir.join("circuit_info.json");
+
+  fs::write(&info_path, to_string_pretty(&info).unwrap()).unwrap();
+  println!("{}", info_path.display());
 }
@@ -1,16 +1,22 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, rc::Rc};
 
 use valuescript_vm::{
-  operations::{op_minus, op_mul, op_plus, op_triple_eq_impl},
-  type_error_builtin::ToTypeError,
+  binary_op::BinaryOp,
+  operations::{op_minus, op_mul, op_not, op_or, op_plus, op_triple_eq_impl},
   unary_op::UnaryOp,
   vs_object::VsObject,
   vs_value::{ToDynamicVal, ToVal, Val, VsType},
-  LoadFunctionResult, ValTrait,
+  IterationResult, LoadFunctionResult, ValTrait,
 };
 
 use crate::{
   circuit_signal::{CircuitSignal, CircuitSignalData},
+  compile_error::{CircuitError, MergeFailure},
+  function_set::{Candidate, FunctionSet},
+  generator::{GeneratorCandidate, GeneratorSet},
+  oblivious_vec::ObliviousVec,
+  optional_key::OptionalKey,
+  optional_signal::{nullish_kind, zero_like, OptionalSignal},
   val_dynamic_downcast::val_dynamic_downcast,
 };
 
@@ -40,23 +46,281 @@ use crate::{
  * should also allow `x` to be any composite structure such as an array of N
  * signals, or any complex structure (nested arrays, objects, etc). We just
  * require that the structure is the same and we can merge element by element.
- * If the structure is different, we abort with a compilation error.
+ * If the structure is different, the result is a `CouldNotMerge` value, which
+ * becomes a compilation error if it is used. `site` is only used to explain
+ * that error.
  */
-pub fn arithmetic_merge(left_flag: &Val, left: &Val, right_flag: &Val, right: &Val) -> Val {
-  assert!(left_flag.typeof_() == VsType::Bool);
-  assert!(right_flag.typeof_() == VsType::Bool);
+pub fn arithmetic_merge(
+  site: &MergeSite,
+  left_flag: &Val,
+  left: &Val,
+  right_flag: &Val,
+  right: &Val,
+) -> Result<Val, Val> {
+  for flag in [left_flag, right_flag] {
+    if flag.typeof_() != VsType::Bool {
+      return Err(
+        CircuitError::Internal(format!("Branch flag {} is not a boolean", flag.codify())).to_val(),
+      );
+    }
+  }
 
   if quick_val_eq(left, right) {
-    return left.clone();
+    return Ok(left.clone());
   }
 
-  arithmetic_merge_impl(&gen_direct_merge(left_flag, right_flag), left, right)
+  arithmetic_merge_impl(
+    &gen_direct_merge(left_flag, right_flag),
+    &MergePath::Root(site),
+    left,
+    right,
+  )
 }
 
-fn gen_direct_merge<'a>(
-  left_flag: &'a Val,
-  right_flag: &'a Val,
-) -> Box<dyn Fn(&'a Val, &'a Val) -> Val + 'a> {
+/**
+ * Merges the values of several branches at once, such as the cases of a `switch`. The branches must
+ * be exclusive (at most one flag is true), so circuit values are merged into the sum of each value
+ * times its flag, rather than merging two at a time and nesting the flags. Arrays and objects with
+ * the same structure are merged element by element, and other values are merged two at a time with
+ * `arithmetic_merge`.
+ */
+pub fn arithmetic_merge_many(site: &MergeSite, branches: &[(Val, Val)]) -> Result<Val, Val> {
+  match branches {
+    [] => Err(CircuitError::Internal("No branches to merge".to_string()).to_val()),
+    [(_, value)] => Ok(value.clone()),
+    [(left_flag, left), (right_flag, right)] => {
+      arithmetic_merge(site, left_flag, left, right_flag, right)
+    }
+    _ => {
+      for (flag, _) in branches {
+        if flag.typeof_() != VsType::Bool {
+          return Err(
+            CircuitError::Internal(format!("Branch flag {} is not a boolean", flag.codify()))
+              .to_val(),
+          );
+        }
+      }
+
+      arithmetic_merge_many_impl(&MergePath::Root(site), branches)
+    }
+  }
+}
+
+fn arithmetic_merge_many_impl(path: &MergePath, branches: &[(Val, Val)]) -> Result<Val, Val> {
+  let (first_flag, first) = &branches[0];
+  let values = || branches.iter().map(|(_, value)| value);
+
+  if values().all(|value| quick_val_eq(first, value)) {
+    return Ok(first.clone());
+  }
+
+  let type_ = first.typeof_();
+
+  if values().all(|value| is_circuit_ish(value) && value.typeof_() == type_) {
+    let mut sum = op_mul(first_flag, first)?;
+
+    for (flag, value) in &branches[1..] {
+      sum = op_plus(&sum, &op_mul(flag, value)?)?;
+    }
+
+    return set_type(&sum, type_);
+  }
+
+  // The values at `key` of each branch
+  let column = |key: &dyn Fn(&Val) -> Option<Val>| {
+    branches
+      .iter()
+      .map(|(flag, value)| Some((flag.clone(), key(value)?)))
+      .collect::<Option<Vec<_>>>()
+  };
+
+  if let Val::Array(first_arr) = first {
+    let len = first_arr.elements.len();
+
+    let same_len = values().all(|value| match value {
+      Val::Array(arr) => arr.elements.len() == len,
+      _ => false,
+    });
+
+    if same_len {
+      return Ok(
+        (0..len)
+          .map(|i| {
+            let elements = column(&|value| match value {
+              Val::Array(arr) => Some(arr.elements[i].clone()),
+              _ => None,
+            });
+
+            arithmetic_merge_many_impl(&MergePath::Index(path, i), &elements.unwrap_or_default())
+          })
+          .collect::<Result<Vec<_>, _>>()?
+          .to_val(),
+      );
+    }
+  }
+
+  if let Val::Object(first_obj) = first {
+    let same_keys = values().all(|value| match value {
+      Val::Object(obj) => {
+        obj.symbol_map.is_empty()
+          && obj.string_map.len() == first_obj.string_map.len()
+          && obj
+            .string_map
+            .iter()
+            .all(|(key, value)| first_obj.string_map.contains_key(key) && !is_optional_key(value))
+      }
+      _ => false,
+    });
+
+    if same_keys {
+      let field = |key: &dyn Fn(&VsObject) -> Val| {
+        column(&|value| match value {
+          Val::Object(obj) => Some(key(obj)),
+          _ => None,
+        })
+        .unwrap_or_default()
+      };
+
+      let mut string_map = BTreeMap::<String, Val>::new();
+
+      for key in first_obj.string_map.keys() {
+        let values = field(&|obj| obj.string_map[key].clone());
+        let key_path = MergePath::Key(path, key.clone());
+
+        string_map.insert(key.clone(), arithmetic_merge_many_impl(&key_path, &values)?);
+      }
+
+      let prototypes = field(&|obj| obj.prototype.clone());
+
+      return Ok(
+        VsObject {
+          string_map,
+          symbol_map: BTreeMap::new(),
+          prototype: arithmetic_merge_many_impl(&MergePath::Prototype(path), &prototypes)?,
+        }
+        .to_val(),
+      );
+    }
+  }
+
+  // Anything else is merged two at a time
+  let mut flag = first_flag.clone();
+  let mut merged = first.clone();
+
+  for (branch_flag, value) in &branches[1..] {
+    merged = arithmetic_merge_impl(&gen_direct_merge(&flag, branch_flag), path, &merged, value)?;
+
+    flag = op_or(&flag, branch_flag)?;
+  }
+
+  Ok(merged)
+}
+
+/// `if_true` when `cond` is true, otherwise `if_false`. `cond` is a boolean (usually a signal).
+pub fn select(cond: &Val, if_true: &Val, if_false: &Val) -> Result<Val, Val> {
+  match cond {
+    Val::Bool(true) => return Ok(if_true.clone()),
+    Val::Bool(false) => return Ok(if_false.clone()),
+    _ => {}
+  }
+
+  let site = MergeSite {
+    target: MergeTarget::Selection,
+    fn_name: None,
+    fork_pos: None,
+    merge_pos: None,
+  };
+
+  let not_cond = op_not(cond)?;
+
+  arithmetic_merge(&site, cond, if_true, &not_cond, if_false)
+}
+
+/// Where a merge happens. Bytecode positions are used because the bytecode doesn't have a source
+/// map.
+#[derive(Clone, Debug)]
+pub struct MergeSite {
+  pub target: MergeTarget,
+
+  /// The function whose frame is being merged, which is needed to name its registers.
+  pub fn_name: Option<Rc<str>>,
+
+  /// The conditional jump that created the branches, if known.
+  pub fork_pos: Option<usize>,
+
+  /// Where the branches met again (`None` for merges at the end of the entry function).
+  pub merge_pos: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MergeTarget {
+  Register(usize),
+  ReturnValue,
+  This,
+
+  /// A value chosen by `select`.
+  Selection,
+}
+
+impl std::fmt::Display for MergeTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MergeTarget::Register(i) => write!(f, "register %{}", i),
+      MergeTarget::ReturnValue => write!(f, "return value"),
+      MergeTarget::This => write!(f, "this"),
+      MergeTarget::Selection => write!(f, "selected value"),
+    }
+  }
+}
+
+/// The location of a value within the merge target. This is only turned into a string if the merge
+/// fails.
+enum MergePath<'a> {
+  Root(&'a MergeSite),
+  Index(&'a MergePath<'a>, usize),
+  Key(&'a MergePath<'a>, String),
+  Prototype(&'a MergePath<'a>),
+  GeneratorRegister(&'a MergePath<'a>, usize),
+}
+
+impl MergePath<'_> {
+  fn site(&self) -> &MergeSite {
+    match self {
+      MergePath::Root(site) => site,
+      MergePath::Index(parent, _)
+      | MergePath::Key(parent, _)
+      | MergePath::Prototype(parent)
+      | MergePath::GeneratorRegister(parent, _) => parent.site(),
+    }
+  }
+
+  fn to_path_string(&self) -> String {
+    match self {
+      MergePath::Root(_) => String::new(),
+      MergePath::Index(parent, i) => format!("{}[{}]", parent.to_path_string(), i),
+      MergePath::Key(parent, key) => format!("{}.{}", parent.to_path_string(), key),
+      MergePath::Prototype(parent) => format!("{}.__proto__", parent.to_path_string()),
+      MergePath::GeneratorRegister(parent, i) => {
+        format!("{}.<generator register %{}>", parent.to_path_string(), i)
+      }
+    }
+  }
+
+  fn could_not_merge(&self, left: &Val, right: &Val) -> Val {
+    CouldNotMerge {
+      left: left.clone(),
+      right: right.clone(),
+      site: self.site().clone(),
+      path: self.to_path_string(),
+    }
+    .to_dynamic_val()
+  }
+}
+
+/// Merges two circuit-ish values according to the branch flags.
+type DirectMerge<'a> = Box<dyn Fn(&Val, &Val) -> Result<Val, Val> + 'a>;
+
+fn gen_direct_merge<'a>(left_flag: &'a Val, right_flag: &'a Val) -> DirectMerge<'a> {
   if let Some(direct_merge) = optimized_direct_merge(false, left_flag, right_flag) {
     return direct_merge;
   }
@@ -65,12 +329,8 @@ fn gen_direct_merge<'a>(
     return direct_merge;
   }
 
-  Box::new(|left, right| {
-    op_plus(
-      &op_mul(left_flag, left).unwrap(),
-      &op_mul(right_flag, right).unwrap(),
-    )
-    .unwrap()
+  Box::new(|left: &Val, right: &Val| {
+    op_plus(&op_mul(left_flag, left)?, &op_mul(right_flag, right)?)
   })
 }
 
@@ -78,7 +338,7 @@ fn optimized_direct_merge<'a>(
   swap: bool,
   left_flag: &'a Val,
   right_flag: &'a Val,
-) -> Option<Box<dyn Fn(&'a Val, &'a Val) -> Val + 'a>> {
+) -> Option<DirectMerge<'a>> {
   let (left_flag, right_flag) = if swap {
     (right_flag, left_flag)
   } else {
@@ -92,14 +352,10 @@ fn optimized_direct_merge<'a>(
         // out = left_flag * left + right_flag * right
         //     = (1 - right_flag) * left + right_flag * right
         //     = left + right_flag * (right - left)
-        return Some(Box::new(move |left, right| {
+        return Some(Box::new(move |left: &Val, right: &Val| {
           let (left, right) = if swap { (right, left) } else { (left, right) };
 
-          op_plus(
-            left,
-            &op_mul(right_flag, &op_minus(right, left).unwrap()).unwrap(),
-          )
-          .unwrap()
+          op_plus(left, &op_mul(right_flag, &op_minus(right, left)?)?)
         }));
       }
     }
@@ -108,94 +364,401 @@ fn optimized_direct_merge<'a>(
   None
 }
 
-fn set_type(val: &Val, type_: VsType) -> Val {
+/// Gives the result of a direct merge the type of the merged values. Direct merges of booleans
+/// produce numbers, since they are computed arithmetically.
+fn set_type(val: &Val, type_: VsType) -> Result<Val, Val> {
   if val.typeof_() == type_ {
-    return val.clone();
+    return Ok(val.clone());
   }
 
-  match val {
-    Val::Bool(b) => match type_ {
-      VsType::Number => match b {
-        true => Val::Number(1.0),
-        false => Val::Number(0.0),
-      },
-      _ => panic!("Unexpected type_ {}", type_),
-    },
-    Val::Number(n) => match type_ {
-      VsType::Bool => {
-        if *n == 1.0 {
-          Val::Bool(true)
-        } else if *n == 0.0 {
-          Val::Bool(false)
-        } else {
-          panic!("Cannot convert to bool: {}", n)
-        }
-      }
-      _ => panic!("Unexpected type_ {}", type_),
-    },
-    Val::Dynamic(dynamic) => match dynamic.as_any().downcast_ref::<CircuitSignal>() {
-      Some(signal) => {
-        let mut signal = signal.clone();
-        signal.type_ = type_;
-        signal.to_dynamic_val()
-      }
-      _ => panic!("Unexpected non-signal dynamic"),
-    },
-    _ => panic!("Unexpected val case"),
-  }
+  let res = match (val, type_) {
+    (Val::Bool(b), VsType::Number) => Some(Val::Number(if *b { 1.0 } else { 0.0 })),
+    (Val::Number(n), VsType::Bool) if *n == 1.0 || *n == 0.0 => Some(Val::Bool(*n == 1.0)),
+    (Val::Dynamic(_), _) => val_dynamic_downcast::<CircuitSignal>(val).map(|signal| {
+      let mut signal = signal.clone();
+      signal.type_ = type_;
+      signal.to_dynamic_val()
+    }),
+    _ => None,
+  };
+
+  res.ok_or_else(|| {
+    CircuitError::Internal(format!(
+      "Cannot convert merged value {} to {}",
+      val.codify(),
+      type_
+    ))
+    .to_val()
+  })
 }
 
-fn arithmetic_merge_impl<'a>(
-  direct_merge: &impl Fn(&'a Val, &'a Val) -> Val,
-  left: &'a Val,
-  right: &'a Val,
-) -> Val {
+fn arithmetic_merge_impl(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  path: &MergePath,
+  left: &Val,
+  right: &Val,
+) -> Result<Val, Val> {
   if quick_val_eq(left, right) {
-    return left.clone();
+    return Ok(left.clone());
+  }
+
+  // Keep the original failure, since that's the one that explains the problem
+  for val in [left, right] {
+    if val_dynamic_downcast::<CouldNotMerge>(val).is_some() {
+      return Ok(val.clone());
+    }
+  }
+
+  if let Some(merged) = merge_optionals(direct_merge, path, left, right)? {
+    return Ok(merged);
   }
 
   if is_circuit_ish(left) && is_circuit_ish(right) {
     let type_ = left.typeof_();
 
-    assert!(
-      right.typeof_() == type_,
-      "Cannot merge {} with {}",
-      type_,
-      right.typeof_()
-    );
+    if right.typeof_() != type_ {
+      return Ok(path.could_not_merge(left, right));
+    }
 
-    assert!(type_ == VsType::Bool || type_ == VsType::Number);
+    return set_type(&direct_merge(left, right)?, type_);
+  }
+
+  if left.typeof_() == VsType::Function && right.typeof_() == VsType::Function {
+    return merge_functions(direct_merge, left, right);
+  }
 
-    return set_type(&direct_merge(left, right), type_);
+  if let Some(merged) = merge_generators(direct_merge, path, left, right) {
+    return merged;
+  }
+
+  // A generator may be done in one branch but not the other, so `done` can be a signal, which
+  // `IterationResult` doesn't allow
+  if [left, right]
+    .iter()
+    .any(|val| val_dynamic_downcast::<IterationResult>(val).is_some())
+  {
+    if let (Some(left), Some(right)) = (iteration_object(left), iteration_object(right)) {
+      return arithmetic_merge_impl(direct_merge, path, &left, &right);
+    }
   }
 
   match (left, right) {
     (Val::Array(left_arr), Val::Array(right_arr)) => {
       if left_arr.elements.len() != right_arr.elements.len() {
-        return CouldNotMerge(left.clone(), right.clone()).to_dynamic_val();
+        return Ok(path.could_not_merge(left, right));
       }
 
-      return (0..left_arr.elements.len())
-        .map(|i| arithmetic_merge_impl(direct_merge, &left_arr.elements[i], &right_arr.elements[i]))
-        .collect::<Vec<_>>()
-        .to_val();
+      return Ok(
+        (0..left_arr.elements.len())
+          .map(|i| {
+            arithmetic_merge_impl(
+              direct_merge,
+              &MergePath::Index(path, i),
+              &left_arr.elements[i],
+              &right_arr.elements[i],
+            )
+          })
+          .collect::<Result<Vec<_>, _>>()?
+          .to_val(),
+      );
     }
-    (Val::Object(left), Val::Object(right)) => {
-      return VsObject {
-        string_map: arithmetic_merge_map(direct_merge, &left.string_map, &right.string_map),
-        symbol_map: arithmetic_merge_map(direct_merge, &left.symbol_map, &right.symbol_map),
-        prototype: arithmetic_merge_impl(direct_merge, &left.prototype, &right.prototype),
-      }
-      .to_val()
+    (Val::Object(left_obj), Val::Object(right_obj)) => {
+      let string_map = arithmetic_merge_map(
+        direct_merge,
+        path,
+        |key| key.clone(),
+        &left_obj.string_map,
+        &right_obj.string_map,
+      )?;
+
+      let symbol_map = arithmetic_merge_map(
+        direct_merge,
+        path,
+        |_| "[symbol]".to_string(),
+        &left_obj.symbol_map,
+        &right_obj.symbol_map,
+      )?;
+
+      return Ok(
+        VsObject {
+          string_map,
+          symbol_map,
+          prototype: arithmetic_merge_impl(
+            direct_merge,
+            &MergePath::Prototype(path),
+            &left_obj.prototype,
+            &right_obj.prototype,
+          )?,
+        }
+        .to_val(),
+      );
     }
     _ => {}
   };
 
+  if let (Some(left_vec), Some(right_vec)) = (
+    val_dynamic_downcast::<ObliviousVec>(left),
+    val_dynamic_downcast::<ObliviousVec>(right),
+  ) {
+    if left_vec.capacity() != right_vec.capacity() {
+      return Ok(path.could_not_merge(left, right));
+    }
+
+    // Unused slots are always zero, so the slots can be merged even if the lengths differ
+    return Ok(
+      ObliviousVec {
+        slots: (0..left_vec.capacity())
+          .map(|i| {
+            arithmetic_merge_impl(
+              direct_merge,
+              &MergePath::Index(path, i),
+              &left_vec.slots[i],
+              &right_vec.slots[i],
+            )
+          })
+          .collect::<Result<_, _>>()?,
+        len: arithmetic_merge_impl(
+          direct_merge,
+          &MergePath::Key(path, "length".to_string()),
+          &left_vec.len,
+          &right_vec.len,
+        )?,
+      }
+      .to_dynamic_val(),
+    );
+  }
+
   if let Ok(true) = op_triple_eq_impl(left, right) {
-    return left.clone();
+    return Ok(left.clone());
   }
 
-  CouldNotMerge(left.clone(), right.clone()).to_dynamic_val()
+  Ok(path.could_not_merge(left, right))
+}
+
+/// Merges different functions into a `FunctionSet`, so that calling the result calls the function
+/// from the branch that was taken.
+fn merge_functions(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  left: &Val,
+  right: &Val,
+) -> Result<Val, Val> {
+  let cond = |left_cond: &Val, right_cond: &Val| {
+    set_type(&direct_merge(left_cond, right_cond)?, VsType::Bool)
+  };
+
+  let mut candidates = Vec::<Candidate>::new();
+
+  for candidate in FunctionSet::candidates_of(left) {
+    candidates.push(Candidate {
+      cond: cond(&candidate.cond, &false.to_val())?,
+      fn_: candidate.fn_,
+    });
+  }
+
+  for candidate in FunctionSet::candidates_of(right) {
+    candidates.push(Candidate {
+      cond: cond(&false.to_val(), &candidate.cond)?,
+      fn_: candidate.fn_,
+    });
+  }
+
+  Ok(FunctionSet::from_candidates(candidates))
+}
+
+/// Merges generators. Generators from each side that are suspended at the same point are merged
+/// register by register, and the rest become separate candidates of a `GeneratorSet`. Returns
+/// `None` if either side isn't a generator.
+fn merge_generators(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  path: &MergePath,
+  left: &Val,
+  right: &Val,
+) -> Option<Result<Val, Val>> {
+  let left_candidates = GeneratorSet::candidates_of(left)?;
+  let right_candidates = GeneratorSet::candidates_of(right)?;
+
+  Some(merge_generator_candidates(
+    direct_merge,
+    path,
+    left_candidates,
+    right_candidates,
+  ))
+}
+
+fn merge_generator_candidates(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  path: &MergePath,
+  left_candidates: Vec<GeneratorCandidate>,
+  right_candidates: Vec<GeneratorCandidate>,
+) -> Result<Val, Val> {
+  let mut right_candidates = right_candidates.into_iter().map(Some).collect::<Vec<_>>();
+  let mut candidates = vec![];
+
+  let cond = |left_cond: &Val, right_cond: &Val| {
+    set_type(&direct_merge(left_cond, right_cond)?, VsType::Bool)
+  };
+
+  for left in left_candidates {
+    let mut merged = None;
+
+    for right_slot in &mut right_candidates {
+      let right = match right_slot {
+        Some(right) => right,
+        None => continue,
+      };
+
+      let generator = left.generator.merge(&right.generator, |i, left, right| {
+        arithmetic_merge_impl(
+          direct_merge,
+          &MergePath::GeneratorRegister(path, i),
+          left,
+          right,
+        )
+      })?;
+
+      if let Some(generator) = generator {
+        merged = Some(GeneratorCandidate {
+          cond: cond(&left.cond, &right.cond)?,
+          generator,
+        });
+
+        *right_slot = None;
+        break;
+      }
+    }
+
+    candidates.push(match merged {
+      Some(merged) => merged,
+      None => GeneratorCandidate {
+        cond: cond(&left.cond, &false.to_val())?,
+        generator: left.generator,
+      },
+    });
+  }
+
+  for right in right_candidates.into_iter().flatten() {
+    candidates.push(GeneratorCandidate {
+      cond: cond(&false.to_val(), &right.cond)?,
+      generator: right.generator,
+    });
+  }
+
+  Ok(GeneratorSet::from_candidates(candidates))
+}
+
+/// `{ value, done }` as a plain object, if `val` is an `IterationResult` or such an object from an
+/// earlier merge.
+fn iteration_object(val: &Val) -> Option<Val> {
+  let iteration_result = match val_dynamic_downcast::<IterationResult>(val) {
+    Some(iteration_result) => iteration_result,
+    None => {
+      return match val {
+        Val::Object(obj) if obj.string_map.contains_key("done") => Some(val.clone()),
+        _ => None,
+      }
+    }
+  };
+
+  Some(
+    VsObject {
+      string_map: BTreeMap::from([
+        ("value".to_string(), iteration_result.value.clone()),
+        ("done".to_string(), iteration_result.done.to_val()),
+      ]),
+      symbol_map: BTreeMap::new(),
+      prototype: Val::Void,
+    }
+    .to_val(),
+  )
+}
+
+/**
+ * Merges values where one side may be `undefined` / `null`, producing an `OptionalSignal`.
+ * Returns `None` if neither side is nullish or optional.
+ */
+fn merge_optionals(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  path: &MergePath,
+  left: &Val,
+  right: &Val,
+) -> Result<Option<Val>, Val> {
+  let optional_parts = |val: &Val| {
+    val_dynamic_downcast::<OptionalSignal>(val).map(|optional| {
+      (
+        optional.is_defined.clone(),
+        optional.value.clone(),
+        optional.nullish.clone(),
+      )
+    })
+  };
+
+  let left_optional = optional_parts(left);
+  let right_optional = optional_parts(right);
+
+  let (left_nullish, right_nullish) = (left.is_nullish(), right.is_nullish());
+
+  if left_optional.is_none() && right_optional.is_none() && left_nullish == right_nullish {
+    return Ok(None);
+  }
+
+  // The defined value of either side, used to give nullish sides a payload with the same structure
+  let template = match (&left_optional, &right_optional, left_nullish) {
+    (Some((_, value, _)), _, _) | (None, Some((_, value, _)), _) => value.clone(),
+    (None, None, true) => right.clone(),
+    (None, None, false) => left.clone(),
+  };
+
+  let parts = |val: &Val, optional: Option<(Val, Val, Val)>| match optional {
+    Some((is_defined, value, nullish)) => (is_defined, value, Some(nullish)),
+    // Void is kept (rather than treated as `undefined`) so that merging the exception of a
+    // `finally` block with no exception can still be thrown conditionally
+    None if val.is_nullish() => (
+      false.to_val(),
+      zero_like(&template),
+      Some(match val {
+        Val::Void => Val::Void,
+        _ => nullish_kind(val),
+      }),
+    ),
+    None => (true.to_val(), val.clone(), None),
+  };
+
+  let (left_defined, left_value, left_nullish) = parts(left, left_optional);
+  let (right_defined, right_value, right_nullish) = parts(right, right_optional);
+
+  let nullish = match (left_nullish, right_nullish) {
+    (Some(left_nullish), Some(right_nullish)) => {
+      let (left_nullish, right_nullish) = match quick_val_eq(&left_nullish, &right_nullish) {
+        true => (left_nullish, right_nullish),
+        false => (nullish_kind(&left_nullish), nullish_kind(&right_nullish)),
+      };
+
+      if !quick_val_eq(&left_nullish, &right_nullish) {
+        // undefined vs null
+        return Ok(Some(path.could_not_merge(left, right)));
+      }
+
+      left_nullish
+    }
+    (Some(nullish), None) | (None, Some(nullish)) => nullish,
+    (None, None) => Val::Undefined,
+  };
+
+  let is_defined = arithmetic_merge_impl(
+    direct_merge,
+    &MergePath::Key(path, "isDefined".to_string()),
+    &left_defined,
+    &right_defined,
+  )?;
+
+  let value = arithmetic_merge_impl(direct_merge, path, &left_value, &right_value)?;
+
+  Ok(Some(
+    match OptionalSignal::new_val(is_defined, value, nullish) {
+      Ok(merged) => merged,
+      Err(_) => path.could_not_merge(left, right),
+    },
+  ))
 }
 
 fn quick_val_eq(left: &Val, right: &Val) -> bool {
@@ -229,32 +792,124 @@ fn is_circuit_ish(val: &Val) -> bool {
   }
 }
 
-fn arithmetic_merge_map<'a, K: std::cmp::Ord + Clone>(
-  direct_merge: &impl Fn(&'a Val, &'a Val) -> Val,
-  left: &'a BTreeMap<K, Val>,
-  right: &'a BTreeMap<K, Val>,
-) -> BTreeMap<K, Val> {
-  if left.len() != right.len() {
-    panic!("Could not merge");
-  }
-
+/// Merges the union of the keys. Keys that only exist in one branch become optional values, so
+/// that objects can be built up inside signal-dependent branches.
+fn arithmetic_merge_map<K: std::cmp::Ord + Clone>(
+  direct_merge: &impl Fn(&Val, &Val) -> Result<Val, Val>,
+  path: &MergePath,
+  key_name: impl Fn(&K) -> String,
+  left: &BTreeMap<K, Val>,
+  right: &BTreeMap<K, Val>,
+) -> Result<BTreeMap<K, Val>, Val> {
   let mut res = BTreeMap::<K, Val>::new();
 
-  for (k, left_value) in left {
-    match right.get(k) {
-      Some(right_value) => res.insert(
-        k.clone(),
-        arithmetic_merge_impl(direct_merge, left_value, right_value),
-      ),
-      None => panic!("Could not merge"),
+  for k in left.keys().chain(right.keys()) {
+    if res.contains_key(k) {
+      continue;
+    }
+
+    let key_path = MergePath::Key(path, key_name(k));
+
+    let (left_value, right_value) = match (left.get(k), right.get(k)) {
+      (Some(left_value), Some(right_value))
+        if !is_optional_key(left_value) && !is_optional_key(right_value) =>
+      {
+        (left_value, right_value)
+      }
+      (left_value, right_value) => {
+        // The key is only present in some branches
+        let (left_present, left_value) = OptionalKey::parts(left_value);
+        let (right_present, right_value) = OptionalKey::parts(right_value);
+
+        let is_present = arithmetic_merge_impl(
+          direct_merge,
+          &MergePath::Key(&key_path, "isPresent".to_string()),
+          &left_present,
+          &right_present,
+        )?;
+
+        let value = arithmetic_merge_impl(direct_merge, &key_path, &left_value, &right_value)?;
+
+        res.insert(k.clone(), OptionalKey::new_val(is_present, value));
+        continue;
+      }
     };
+
+    res.insert(
+      k.clone(),
+      arithmetic_merge_impl(direct_merge, &key_path, left_value, right_value)?,
+    );
   }
 
-  res
+  Ok(res)
+}
+
+fn is_optional_key(val: &Val) -> bool {
+  val_dynamic_downcast::<OptionalKey>(val).is_some()
 }
 
+/// The result of merging values that don't have the same structure. Using this value causes a
+/// `CircuitError::CouldNotMerge`.
 #[derive(Clone)]
-pub struct CouldNotMerge(pub Val, pub Val);
+pub struct CouldNotMerge {
+  pub left: Val,
+  pub right: Val,
+  pub site: MergeSite,
+
+  /// Where the mismatch is within the merge target, eg `.items[3]`.
+  pub path: String,
+}
+
+impl CouldNotMerge {
+  pub fn to_error(&self) -> CircuitError {
+    CircuitError::CouldNotMerge(Box::new(MergeFailure {
+      target: format!("{}{}", self.site.target, self.path),
+      variable: None,
+      reason: mismatch_reason(&self.left, &self.right),
+      fn_name: self.site.fn_name.as_deref().map(str::to_string),
+      register: match self.site.target {
+        MergeTarget::Register(i) => Some(i),
+        _ => None,
+      },
+      path: self.path.clone(),
+      fork_pos: self.site.fork_pos,
+      merge_pos: self.site.merge_pos,
+      fork_location: None,
+      merge_location: None,
+      location: None,
+    }))
+  }
+}
+
+/// Describes how two values differ structurally, eg `array of length 3 vs array of length 4`.
+fn mismatch_reason(left: &Val, right: &Val) -> String {
+  format!("{} vs {}", describe_shape(left), describe_shape(right))
+}
+
+fn describe_shape(val: &Val) -> String {
+  match val {
+    Val::Array(array) => format!("array of length {}", array.elements.len()),
+    Val::Object(object) => format!(
+      "object with keys {{{}}}",
+      object
+        .string_map
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    Val::String(_) => format!("string {}", val.codify()),
+    Val::Dynamic(_) if val_dynamic_downcast::<ObliviousVec>(val).is_some() => {
+      let vec = val_dynamic_downcast::<ObliviousVec>(val).unwrap();
+      format!("summon.vec with capacity {}", vec.capacity())
+    }
+    Val::Dynamic(_) if is_circuit_ish(val) => format!("{} signal", val.typeof_()),
+    _ => match val.typeof_() {
+      VsType::Object | VsType::Function | VsType::Class => val.typeof_().to_string(),
+      _ => val.codify(),
+    },
+  }
+}
 
 impl ValTrait for CouldNotMerge {
   fn typeof_(&self) -> valuescript_vm::vs_value::VsType {
@@ -302,7 +957,7 @@ impl ValTrait for CouldNotMerge {
   }
 
   fn sub(&self, _key: &Val) -> Result<Val, Val> {
-    Ok(Val::Undefined)
+    Err(self.to_error().to_val())
   }
 
   fn has(&self, _key: &Val) -> Option<bool> {
@@ -310,20 +965,42 @@ impl ValTrait for CouldNotMerge {
   }
 
   fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
-    Err("Cannot assign subscript to CouldNotMerge".to_type_error())
+    Err(self.to_error().to_val())
+  }
+
+  fn override_binary_op(
+    &self,
+    _op: BinaryOp,
+    _left: &Val,
+    _right: &Val,
+  ) -> Option<Result<Val, Val>> {
+    Some(Err(self.to_error().to_val()))
+  }
+
+  fn override_unary_op(&self, _op: UnaryOp, _input: &Val) -> Option<Result<Val, Val>> {
+    Some(Err(self.to_error().to_val()))
   }
 
   fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "CouldNotMerge({}, {})", self.0.pretty(), self.1.pretty())
+    write!(
+      f,
+      "CouldNotMerge({}, {})",
+      self.left.pretty(),
+      self.right.pretty()
+    )
   }
 
   fn codify(&self) -> String {
-    format!("CouldNotMerge({}, {})", self.0.codify(), self.1.codify())
+    format!(
+      "CouldNotMerge({}, {})",
+      self.left.codify(),
+      self.right.codify()
+    )
   }
 }
 
 impl std::fmt::Display for CouldNotMerge {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "CouldNotMerge({}, {})", self.0, self.1)
+    write!(f, "CouldNotMerge({}, {})", self.left, self.right)
   }
 }
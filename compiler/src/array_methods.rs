@@ -0,0 +1,759 @@
+use std::mem::take;
+
+use valuescript_vm::{
+  binary_op::BinaryOp,
+  native_frame_function::NativeFrameFunction,
+  native_function::ThisWrapper,
+  operations::{op_greater, op_minus, op_mul, op_not, op_or, op_plus, op_triple_eq},
+  type_error_builtin::ToTypeError,
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  CallResult, FrameStepOk, FrameStepResult, LoadFunctionResult, StackFrame, StackFrameTrait,
+  ValTrait,
+};
+
+use crate::{
+  arithmetic_merge::select,
+  circuit_signal::{CircuitSignal, CircuitSignalData},
+  oblivious_vec::ObliviousVec,
+  optional_signal::truthiness,
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/**
+ * Array methods that test elements, replacing the VM's versions so that the tests can depend on
+ * signals:
+ *
+ * - `filter` returns a `summon.vec` (see `ObliviousVec`) when the length depends on signals
+ * - `find` returns an optional value
+ * - `findIndex`, `indexOf` and `includes` return signals
+ *
+ * The predicate is called for every element unless a constant match is found first. Once `find` or
+ * `findIndex` has a match that depends on a signal, the branch is split (see `fork_flag`), so
+ * that the remaining calls only happen when nothing has matched yet.
+ *
+ * `sort` is also replaced (see `SortFrame`), and so are `forEach`, `map` and `reduce` (see
+ * `IterationFrame`).
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArrayMethod {
+  Filter,
+  Find,
+  FindIndex,
+  Includes,
+  IndexOf,
+}
+
+/// The replacement for `array[key]` if it is one of the methods above.
+pub fn array_method(array: &Val, key: &Val) -> Option<Val> {
+  if !matches!(array, Val::Array(_)) {
+    return None;
+  }
+
+  let key = match key {
+    Val::String(key) => key,
+    _ => return None,
+  };
+
+  Some(match &**key {
+    "filter" => FILTER.to_val(),
+    "find" => FIND.to_val(),
+    "findIndex" => FIND_INDEX.to_val(),
+    "includes" => INCLUDES.to_val(),
+    "indexOf" => INDEX_OF.to_val(),
+    "sort" => SORT.to_val(),
+    "forEach" => FOR_EACH.to_val(),
+    "map" => MAP.to_val(),
+    "reduce" => REDUCE.to_val(),
+    _ => return None,
+  })
+}
+
+static FILTER: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(ArrayMethodFrame::new(ArrayMethod::Filter)),
+};
+
+static FIND: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(ArrayMethodFrame::new(ArrayMethod::Find)),
+};
+
+static FIND_INDEX: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(ArrayMethodFrame::new(ArrayMethod::FindIndex)),
+};
+
+static INCLUDES: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(ArrayMethodFrame::new(ArrayMethod::Includes)),
+};
+
+static INDEX_OF: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(ArrayMethodFrame::new(ArrayMethod::IndexOf)),
+};
+
+static SORT: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::<SortFrame>::default(),
+};
+
+static FOR_EACH: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(IterationFrame::new(IterationMethod::ForEach)),
+};
+
+static MAP: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(IterationFrame::new(IterationMethod::Map)),
+};
+
+static REDUCE: NativeFrameFunction = NativeFrameFunction {
+  make_frame: || Box::new(IterationFrame::new(IterationMethod::Reduce)),
+};
+
+/// The outcome of calling a callback from one of the frames below.
+enum Callback {
+  /// A native callback, which returns straight away.
+  Returned(Val),
+
+  /// The callback's frame, which needs to be pushed. Its result arrives via `apply_call_result`.
+  Push(StackFrame),
+}
+
+fn call_callback(fn_: &Val, args: Vec<Val>) -> Result<Callback, Val> {
+  match fn_.load_function() {
+    LoadFunctionResult::NotAFunction => {
+      Err(format!("{} is not a function", fn_.codify()).to_type_error())
+    }
+    LoadFunctionResult::NativeFunction(native_fn) => Ok(Callback::Returned(native_fn(
+      ThisWrapper::new(true, &mut Val::Undefined),
+      args,
+    )?)),
+    LoadFunctionResult::StackFrame(mut frame) => {
+      for arg in args {
+        frame.write_param(arg);
+      }
+
+      Ok(Callback::Push(frame))
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct ArrayMethodFrame {
+  method: ArrayMethod,
+  this: Val,
+  elements: Vec<Val>,
+  params: Vec<Val>,
+
+  /// Whether each element passed the test so far (booleans or boolean signals).
+  matches: Vec<Val>,
+
+  /// The result of the last predicate call, which hasn't been added to `matches` yet.
+  predicate_result: Option<Val>,
+
+  /**
+   * When `find` or `findIndex` calls its predicate after a match that depends on a signal, the
+   * call should only happen if that element didn't match. Otherwise, side effects in the
+   * predicate (eg `summon.assert`) would happen even after the element is found.
+   *
+   * This is the condition for the call that was just pushed (that the last element didn't match).
+   * The branch running this frame takes it and splits: this frame calls the predicate under the
+   * condition, and the alt branch skips the remaining elements (see `skip_remaining`).
+   */
+  pub fork_flag: Option<Val>,
+}
+
+impl ArrayMethodFrame {
+  fn new(method: ArrayMethod) -> ArrayMethodFrame {
+    ArrayMethodFrame {
+      method,
+      this: Val::Undefined,
+      elements: vec![],
+      params: vec![],
+      matches: vec![],
+      predicate_result: None,
+      fork_flag: None,
+    }
+  }
+
+  /// Treats the remaining elements as not matching, without calling the predicate.
+  pub fn skip_remaining(&mut self) {
+    self.matches.resize(self.elements.len(), false.to_val());
+  }
+
+  fn param(&self, i: usize) -> Val {
+    self.params.get(i).cloned().unwrap_or(Val::Undefined)
+  }
+
+  /// `find` and `findIndex` can stop once an element is known to match.
+  fn is_done(&self) -> bool {
+    if self.matches.len() == self.elements.len() {
+      return true;
+    }
+
+    matches!(self.method, ArrayMethod::Find | ArrayMethod::FindIndex)
+      && matches!(self.matches.last(), Some(Val::Bool(true)))
+  }
+
+  /// Calls the predicate on the next element.
+  fn call_predicate(&mut self) -> FrameStepResult {
+    let predicate = self.param(0);
+    let i = self.matches.len();
+
+    let args = vec![
+      self.elements[i].clone(),
+      (i as f64).to_val(),
+      self.this.clone(),
+    ];
+
+    match call_callback(&predicate, args)? {
+      Callback::Returned(res) => {
+        self.matches.push(truthiness(&res)?);
+
+        Ok(FrameStepOk::Continue)
+      }
+      Callback::Push(frame) => {
+        if let (ArrayMethod::Find | ArrayMethod::FindIndex, Some(last_match)) =
+          (self.method, self.matches.last())
+        {
+          // Constant matches either stop the search (`is_done`) or don't affect the call
+          if !matches!(last_match, Val::Bool(_)) {
+            self.fork_flag = Some(op_not(last_match)?);
+          }
+        }
+
+        Ok(FrameStepOk::Push(frame))
+      }
+    }
+  }
+
+  /// The index that `indexOf` and `includes` start searching from.
+  fn search_start(&self) -> Result<usize, Val> {
+    let len = self.elements.len() as f64;
+
+    let from_index = match self.param(1) {
+      Val::Undefined => 0.0,
+      Val::Number(from_index) => from_index.trunc(),
+      from_index => {
+        return Err(
+          format!(
+            "fromIndex of indexOf/includes must be a constant number (got {})",
+            from_index.codify()
+          )
+          .to_type_error(),
+        )
+      }
+    };
+
+    let from_index = match from_index < 0.0 {
+      true => (len + from_index).max(0.0),
+      false => from_index.min(len),
+    };
+
+    Ok(from_index as usize)
+  }
+
+  fn result(&self) -> Result<Val, Val> {
+    let elements = &self.elements;
+    let matches = &self.matches;
+
+    Ok(match self.method {
+      ArrayMethod::Filter => {
+        if matches.iter().all(|m| matches!(m, Val::Bool(_))) {
+          elements
+            .iter()
+            .zip(matches)
+            .filter(|(_, m)| matches!(m, Val::Bool(true)))
+            .map(|(element, _)| element.clone())
+            .collect::<Vec<_>>()
+            .to_val()
+        } else {
+          ObliviousVec::compact(elements, matches)?.to_dynamic_val()
+        }
+      }
+      ArrayMethod::Find => first_match(matches, Val::Undefined, |i| elements[i].clone())?,
+      ArrayMethod::FindIndex | ArrayMethod::IndexOf => {
+        first_match(matches, (-1.0).to_val(), |i| (i as f64).to_val())?
+      }
+      ArrayMethod::Includes => {
+        let mut res = false.to_val();
+
+        for m in matches {
+          res = op_or(&res, m)?;
+        }
+
+        res
+      }
+    })
+  }
+}
+
+/// `value(i)` for the first `i` that matches, otherwise `none`. When the matches depend on
+/// signals, this is built from the last element backwards so that earlier matches take priority.
+fn first_match(matches: &[Val], none: Val, value: impl Fn(usize) -> Val) -> Result<Val, Val> {
+  let mut res = none;
+
+  for (i, m) in matches.iter().enumerate().rev() {
+    res = select(m, &value(i), &res)?;
+  }
+
+  Ok(res)
+}
+
+impl StackFrameTrait for ArrayMethodFrame {
+  fn write_this(&mut self, _const: bool, this: Val) -> Result<(), Val> {
+    self.elements = match &this {
+      Val::Array(array) => array.elements.clone(),
+      _ => return Err("Expected an array".to_type_error()),
+    };
+
+    self.this = this;
+
+    Ok(())
+  }
+
+  fn write_param(&mut self, param: Val) {
+    self.params.push(param);
+  }
+
+  fn step(&mut self) -> FrameStepResult {
+    if let Some(predicate_result) = take(&mut self.predicate_result) {
+      self.matches.push(truthiness(&predicate_result)?);
+    }
+
+    if let ArrayMethod::Includes | ArrayMethod::IndexOf = self.method {
+      let search = self.param(0);
+      let search_start = self.search_start()?;
+
+      for (i, element) in self.elements.iter().enumerate() {
+        self.matches.push(match i < search_start {
+          true => false.to_val(),
+          false => op_triple_eq(element, &search)?,
+        });
+      }
+    }
+
+    if !self.is_done() {
+      return self.call_predicate();
+    }
+
+    Ok(FrameStepOk::Pop(CallResult {
+      return_: self.result()?,
+      this: self.this.clone(),
+    }))
+  }
+
+  fn apply_call_result(&mut self, call_result: CallResult) {
+    self.predicate_result = Some(call_result.return_);
+  }
+
+  fn get_call_result(&mut self) -> CallResult {
+    panic!("Not appropriate for ArrayMethodFrame")
+  }
+
+  fn can_catch_exception(&self, _exception: &Val) -> bool {
+    false
+  }
+
+  fn catch_exception(&mut self, _exception: &mut Val) {
+    panic!("Not appropriate for ArrayMethodFrame");
+  }
+
+  fn clone_to_stack_frame(&self) -> StackFrame {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn std::any::Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+    self
+  }
+}
+
+/**
+ * `arr.sort(compare)`, using a Batcher odd-even merge sort network so that the comparisons can
+ * depend on signals. Each comparison becomes a compare-and-swap of the two elements, so arrays of
+ * objects are sorted by whatever key the comparator uses.
+ *
+ * Ties are broken by the original positions of the elements, so that the sort is stable (like the
+ * VM's sort) when the comparisons don't depend on signals. Once elements have been swapped under a
+ * signal, their original positions aren't known, so elements that compare equal may be reordered.
+ */
+#[derive(Clone, Default)]
+struct SortFrame {
+  this: Val,
+  elements: Vec<Val>,
+  compare: Option<Val>,
+
+  /// The original position of each element, if it doesn't depend on signals.
+  positions: Vec<Option<usize>>,
+
+  /// The comparisons of the network, which are done in order.
+  pairs: Vec<(usize, usize)>,
+  next_pair: usize,
+
+  /// The result of comparing `pairs[next_pair - 1]`, which hasn't been applied yet.
+  compare_result: Option<Val>,
+}
+
+impl SortFrame {
+  /// Swaps the elements of `pairs[next_pair - 1]` if `compare_result > 0`, or if they are equal
+  /// and out of their original order.
+  fn apply_compare_result(&mut self, compare_result: Val) -> Result<(), Val> {
+    let (i, j) = self.pairs[self.next_pair - 1];
+
+    let swap = match (&compare_result, self.positions[i], self.positions[j]) {
+      (Val::Number(result), Some(left_pos), Some(right_pos)) if *result == 0.0 => {
+        (left_pos > right_pos).to_val()
+      }
+      _ => is_positive(&compare_result)?,
+    };
+
+    match swap {
+      Val::Bool(true) => self.positions.swap(i, j),
+      Val::Bool(false) => {}
+      _ => {
+        self.positions[i] = None;
+        self.positions[j] = None;
+      }
+    }
+
+    let (left, right) = (&self.elements[i], &self.elements[j]);
+
+    let (new_left, new_right) = match (left.typeof_(), right.typeof_()) {
+      (VsType::Number, VsType::Number) if !matches!(swap, Val::Bool(_)) => {
+        // Share the difference between both sides:
+        //   new_left = left + swap * (right - left)
+        //   new_right = right - swap * (right - left)
+        let delta = op_mul(&swap, &op_minus(right, left)?)?;
+        (op_plus(left, &delta)?, op_minus(right, &delta)?)
+      }
+      _ => (select(&swap, right, left)?, select(&swap, left, right)?),
+    };
+
+    self.elements[i] = new_left;
+    self.elements[j] = new_right;
+
+    Ok(())
+  }
+
+  /// Compares the next pair of elements.
+  fn call_compare(&mut self, compare: &Val) -> FrameStepResult {
+    let (i, j) = self.pairs[self.next_pair];
+    self.next_pair += 1;
+
+    let args = vec![self.elements[i].clone(), self.elements[j].clone()];
+
+    match call_callback(compare, args)? {
+      Callback::Returned(res) => {
+        self.apply_compare_result(res)?;
+
+        Ok(FrameStepOk::Continue)
+      }
+      Callback::Push(frame) => Ok(FrameStepOk::Push(frame)),
+    }
+  }
+
+  /// Without a comparator, elements are sorted by their string representations (like the VM),
+  /// which requires them to be known at compile time.
+  fn sort_by_string(&mut self) -> Result<(), Val> {
+    if let Some(element) = self.elements.iter().find(|e| !e.is_primitive()) {
+      return Err(
+        format!(
+          "Sorting {} requires a comparator, eg `arr.sort((a, b) => a - b)`",
+          element.codify()
+        )
+        .to_type_error(),
+      );
+    }
+
+    // `undefined` goes at the end
+    self
+      .elements
+      .sort_by_key(|e| (matches!(e, Val::Undefined), e.to_string()));
+
+    Ok(())
+  }
+}
+
+/// `result > 0`, simplifying `(a - b) > 0` to `a > b` so that a comparator like `(a, b) => a - b`
+/// costs a single comparison.
+fn is_positive(result: &Val) -> Result<Val, Val> {
+  if let Some(signal) = val_dynamic_downcast::<CircuitSignal>(result) {
+    if let CircuitSignalData::BinaryOp(BinaryOp::Minus, a, b) = &signal.data {
+      return op_greater(a, b);
+    }
+  }
+
+  op_greater(result, &0.0.to_val())
+}
+
+/// The comparisons of a Batcher odd-even merge sort network for `n` elements.
+///
+/// https://en.wikipedia.org/wiki/Batcher_odd%E2%80%93even_mergesort
+fn batcher_pairs(n: usize) -> Vec<(usize, usize)> {
+  let mut pairs = vec![];
+  let mut p = 1;
+
+  while p < n {
+    let mut k = p;
+
+    while k >= 1 {
+      let mut j = k % p;
+
+      while j + k < n {
+        for i in 0..k.min(n - j - k) {
+          if (i + j) / (p * 2) == (i + j + k) / (p * 2) {
+            pairs.push((i + j, i + j + k));
+          }
+        }
+
+        j += 2 * k;
+      }
+
+      k /= 2;
+    }
+
+    p *= 2;
+  }
+
+  pairs
+}
+
+impl StackFrameTrait for SortFrame {
+  fn write_this(&mut self, _const: bool, this: Val) -> Result<(), Val> {
+    self.elements = match &this {
+      Val::Array(array) => array.elements.clone(),
+      _ => return Err("Expected an array".to_type_error()),
+    };
+
+    self.pairs = batcher_pairs(self.elements.len());
+    self.positions = (0..self.elements.len()).map(Some).collect();
+    self.this = this;
+
+    Ok(())
+  }
+
+  fn write_param(&mut self, param: Val) {
+    if self.compare.is_none() {
+      self.compare = Some(param);
+    }
+  }
+
+  fn step(&mut self) -> FrameStepResult {
+    if let Some(compare_result) = take(&mut self.compare_result) {
+      self.apply_compare_result(compare_result)?;
+    }
+
+    match self.compare.clone() {
+      None | Some(Val::Undefined) => self.sort_by_string()?,
+      Some(compare) => {
+        if self.next_pair < self.pairs.len() {
+          return self.call_compare(&compare);
+        }
+      }
+    }
+
+    let sorted = take(&mut self.elements).to_val();
+
+    Ok(FrameStepOk::Pop(CallResult {
+      return_: sorted.clone(),
+      this: sorted,
+    }))
+  }
+
+  fn apply_call_result(&mut self, call_result: CallResult) {
+    self.compare_result = Some(call_result.return_);
+  }
+
+  fn get_call_result(&mut self) -> CallResult {
+    panic!("Not appropriate for SortFrame")
+  }
+
+  fn can_catch_exception(&self, _exception: &Val) -> bool {
+    false
+  }
+
+  fn catch_exception(&mut self, _exception: &mut Val) {
+    panic!("Not appropriate for SortFrame");
+  }
+
+  fn clone_to_stack_frame(&self) -> StackFrame {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn std::any::Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+    self
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IterationMethod {
+  ForEach,
+  Map,
+  Reduce,
+}
+
+/**
+ * `forEach`, `map` and `reduce`, which call the callback once per element. The callbacks run as
+ * ordinary frames on top of this one, so callbacks that branch on signals fork and merge like any
+ * other function call, and `scores.reduce((a, b) => a + b, 0)` works when the scores are signals.
+ */
+#[derive(Clone)]
+struct IterationFrame {
+  method: IterationMethod,
+  this: Val,
+  elements: Vec<Val>,
+  params: Vec<Val>,
+
+  /// The index of the next element to pass to the callback.
+  next_index: usize,
+
+  /// The results of `map` so far.
+  results: Vec<Val>,
+
+  /// The accumulator of `reduce`, which is `None` until the initial value has been chosen.
+  acc: Option<Val>,
+
+  /// The result of the last callback, which hasn't been applied yet.
+  callback_result: Option<Val>,
+}
+
+impl IterationFrame {
+  fn new(method: IterationMethod) -> IterationFrame {
+    IterationFrame {
+      method,
+      this: Val::Undefined,
+      elements: vec![],
+      params: vec![],
+      next_index: 0,
+      results: vec![],
+      acc: None,
+      callback_result: None,
+    }
+  }
+
+  fn apply_callback_result(&mut self, callback_result: Val) {
+    match self.method {
+      IterationMethod::ForEach => {}
+      IterationMethod::Map => self.results.push(callback_result),
+      IterationMethod::Reduce => self.acc = Some(callback_result),
+    }
+  }
+
+  /// Like JavaScript, `reduce` starts with the first element when there's no initial value.
+  fn init_acc(&mut self) -> Result<(), Val> {
+    if let Some(initial) = self.params.get(1) {
+      self.acc = Some(initial.clone());
+      return Ok(());
+    }
+
+    match self.elements.first() {
+      Some(first) => {
+        self.acc = Some(first.clone());
+        self.next_index = 1;
+
+        Ok(())
+      }
+      None => Err("Reduce of empty array with no initial value".to_type_error()),
+    }
+  }
+
+  /// Calls the callback on the next element.
+  fn call(&mut self) -> FrameStepResult {
+    let callback = self.params.first().cloned().unwrap_or(Val::Undefined);
+    let i = self.next_index;
+    self.next_index += 1;
+
+    let mut args = vec![
+      self.elements[i].clone(),
+      (i as f64).to_val(),
+      self.this.clone(),
+    ];
+
+    if let Some(acc) = &self.acc {
+      args.insert(0, acc.clone());
+    }
+
+    match call_callback(&callback, args)? {
+      Callback::Returned(res) => {
+        self.apply_callback_result(res);
+
+        Ok(FrameStepOk::Continue)
+      }
+      Callback::Push(frame) => Ok(FrameStepOk::Push(frame)),
+    }
+  }
+
+  fn result(&mut self) -> Val {
+    match self.method {
+      IterationMethod::ForEach => Val::Undefined,
+      IterationMethod::Map => take(&mut self.results).to_val(),
+      IterationMethod::Reduce => take(&mut self.acc).unwrap_or(Val::Undefined),
+    }
+  }
+}
+
+impl StackFrameTrait for IterationFrame {
+  fn write_this(&mut self, _const: bool, this: Val) -> Result<(), Val> {
+    self.elements = match &this {
+      Val::Array(array) => array.elements.clone(),
+      _ => return Err("Expected an array".to_type_error()),
+    };
+
+    self.this = this;
+
+    Ok(())
+  }
+
+  fn write_param(&mut self, param: Val) {
+    self.params.push(param);
+  }
+
+  fn step(&mut self) -> FrameStepResult {
+    if let Some(callback_result) = take(&mut self.callback_result) {
+      self.apply_callback_result(callback_result);
+    }
+
+    if self.method == IterationMethod::Reduce && self.acc.is_none() {
+      self.init_acc()?;
+    }
+
+    if self.next_index < self.elements.len() {
+      return self.call();
+    }
+
+    Ok(FrameStepOk::Pop(CallResult {
+      return_: self.result(),
+      this: self.this.clone(),
+    }))
+  }
+
+  fn apply_call_result(&mut self, call_result: CallResult) {
+    self.callback_result = Some(call_result.return_);
+  }
+
+  fn get_call_result(&mut self) -> CallResult {
+    panic!("Not appropriate for IterationFrame")
+  }
+
+  fn can_catch_exception(&self, _exception: &Val) -> bool {
+    false
+  }
+
+  fn catch_exception(&mut self, _exception: &mut Val) {
+    panic!("Not appropriate for IterationFrame");
+  }
+
+  fn clone_to_stack_frame(&self) -> StackFrame {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn std::any::Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+    self
+  }
+}
@@ -0,0 +1,89 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::circuit_vm_branch::CircuitVMBranch;
+
+/**
+ * Decides which branch `CircuitVM` runs next, which affects where branches meet and merge, and
+ * therefore the size of the circuit.
+ *
+ * Deeper stacks are always run first, and branches at the same location are always merged. This
+ * is needed so that branches only meet when their stacks match. Schedulers choose between the
+ * remaining branches, which are in the same function (or in functions at the same depth), at
+ * different positions.
+ */
+pub trait BranchScheduler {
+  /// Compares two branches at the same depth that are at different bytecode positions. The greater
+  /// branch is run first.
+  fn cmp(&self, a: &CircuitVMBranch, b: &CircuitVMBranch) -> Ordering;
+
+  /// Whether a waiting branch is merged as soon as another branch reaches the same location,
+  /// instead of when it is next run.
+  fn merges_eagerly(&self) -> bool {
+    false
+  }
+}
+
+/// The built-in `BranchScheduler`s. This is the public way to choose a scheduler, since
+/// `BranchScheduler` depends on the VM's internal branch state.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedulingStrategy {
+  /// Runs the branch at the earliest bytecode position first. Since branches jump forwards to the
+  /// end of an `if`, this brings them together where they meet.
+  #[default]
+  EarliestPosition,
+
+  /// Runs the branch with the most unmerged forks first, so that branches that were split most
+  /// recently meet again before older branches move on. This tends to keep fewer branches alive.
+  MostForksFirst,
+
+  /// Like `EarliestPosition`, but branches waiting at the same location are merged straight away.
+  /// For example, when a loop has an early `return`, the branches that return merge with each
+  /// other while the loop is still running.
+  MergeEagerly,
+}
+
+impl SchedulingStrategy {
+  pub(crate) fn scheduler(self) -> Rc<dyn BranchScheduler> {
+    match self {
+      SchedulingStrategy::EarliestPosition => Rc::new(EarliestPosition),
+      SchedulingStrategy::MostForksFirst => Rc::new(MostForksFirst),
+      SchedulingStrategy::MergeEagerly => Rc::new(MergeEagerly),
+    }
+  }
+}
+
+pub struct EarliestPosition;
+
+impl BranchScheduler for EarliestPosition {
+  fn cmp(&self, a: &CircuitVMBranch, b: &CircuitVMBranch) -> Ordering {
+    // Reversed, so that the earlier position is greater
+    pos(b).cmp(&pos(a))
+  }
+}
+
+pub struct MostForksFirst;
+
+impl BranchScheduler for MostForksFirst {
+  fn cmp(&self, a: &CircuitVMBranch, b: &CircuitVMBranch) -> Ordering {
+    a.fork_sites
+      .len()
+      .cmp(&b.fork_sites.len())
+      .then_with(|| EarliestPosition.cmp(a, b))
+  }
+}
+
+pub struct MergeEagerly;
+
+impl BranchScheduler for MergeEagerly {
+  fn cmp(&self, a: &CircuitVMBranch, b: &CircuitVMBranch) -> Ordering {
+    EarliestPosition.cmp(a, b)
+  }
+
+  fn merges_eagerly(&self) -> bool {
+    true
+  }
+}
+
+fn pos(branch: &CircuitVMBranch) -> Option<usize> {
+  branch.bytecode_frame().map(|frame| frame.decoder.pos)
+}
@@ -1,4 +1,11 @@
-use std::{cell::RefCell, collections::HashMap, fmt, ops::Index, rc::Rc, slice::SliceIndex};
+use std::{
+  cell::RefCell,
+  collections::{BTreeMap, HashMap},
+  fmt,
+  ops::Index,
+  rc::Rc,
+  slice::SliceIndex,
+};
 
 use valuescript_vm::vs_value::Val;
 
@@ -7,6 +14,10 @@ use crate::bytecode_decoder::BytecodeDecoder;
 pub struct Bytecode {
   pub code: Vec<u8>,
   pub cache: RefCell<HashMap<usize, Val>>,
+
+  /// The backward jumps that have been run, from the position of the `jmp` to its target. These
+  /// are the ends of loops (see `BytecodeStackFrame::is_loop_condition`).
+  pub back_edges: RefCell<BTreeMap<usize, usize>>,
 }
 
 impl<I: SliceIndex<[u8]>> Index<I> for Bytecode {
@@ -28,6 +39,7 @@ impl Bytecode {
     Bytecode {
       code,
       cache: RefCell::new(HashMap::new()),
+      back_edges: RefCell::new(BTreeMap::new()),
     }
   }
 }
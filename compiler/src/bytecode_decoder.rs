@@ -15,6 +15,7 @@ use valuescript_vm::BUILTIN_VALS;
 
 use crate::bytecode::Bytecode;
 use crate::cs_function::CsFunction;
+use crate::intrinsics::{intrinsic_val, IntrinsicFn};
 
 #[derive(Clone)]
 pub struct BytecodeDecoder {
@@ -304,6 +305,14 @@ impl BytecodeDecoder {
     let register_count = self.decode_byte() as usize;
     let parameter_count = self.decode_byte() as usize;
 
+    if let Some(meta_pos) = meta_pos {
+      let meta = self.clone_at(meta_pos).decode_meta();
+
+      if let Some(intrinsic_fn) = IntrinsicFn::from_fn_name(&meta.name) {
+        return intrinsic_val(intrinsic_fn);
+      }
+    }
+
     CsFunction {
       bytecode: self.bytecode.clone(),
       meta_pos,
@@ -1,11 +1,12 @@
-use std::mem::take;
+use std::{mem::take, rc::Rc};
 
 use valuescript_common::InstructionByte;
+use valuescript_vm::binary_op::BinaryOp;
 use valuescript_vm::cat_stack_frame::CatStackFrame;
 use valuescript_vm::internal_error_builtin::ToInternalError;
 use valuescript_vm::jsx_element::JsxElement;
 use valuescript_vm::native_function::ThisWrapper;
-use valuescript_vm::operations::{op_delete, op_not};
+use valuescript_vm::operations::{op_delete, op_not, op_or};
 use valuescript_vm::type_error_builtin::ToTypeError;
 use valuescript_vm::vs_object::VsObject;
 use valuescript_vm::vs_value::{ToDynamicVal, ToVal, VsType};
@@ -14,15 +15,41 @@ use valuescript_vm::{
 };
 use valuescript_vm::{vs_value::Val, StackFrameTrait};
 
+use crate::arithmetic_merge::{arithmetic_merge_many, CouldNotMerge, MergeSite, MergeTarget};
+use crate::array_methods::array_method;
 use crate::bytecode_decoder::{BytecodeDecoder, BytecodeType};
-use crate::circuit_signal::CircuitSignal;
+use crate::circuit_signal::{CircuitSignal, CircuitSignalData};
+use crate::compile_error::CircuitError;
+use crate::function_set::FunctionSet;
+use crate::intrinsics::{IntrinsicCall, IntrinsicFn};
+use crate::oblivious_vec::ObliviousVec;
+use crate::optional_key::{object_method, op_in_optional_key, read_property};
+use crate::optional_signal::{
+  op_nullish_coalesce_optional, op_optional_chain_optional, op_typeof_optional, OptionalSignal,
+};
 use crate::val_dynamic_downcast::val_dynamic_downcast;
 
+/// How many instructions `speculate` will run before giving up and forking instead.
+const SPECULATION_STEP_LIMIT: usize = 64;
+
 #[derive(Clone)]
 pub struct ForkInfo {
+  /// Position of the conditional jump (or the call of a `FunctionSet`).
+  pub pos: usize,
   pub flag: Val,
-  pub alt_flag: Val,
-  pub alt_frame: BytecodeStackFrame,
+
+  /// The other ways that execution continues. The flags of a fork are exclusive.
+  pub alts: Vec<AltFrame>,
+
+  /// Whether this is the condition of a loop, which is where a loop bounded by a signal forks
+  /// (see `LoopDetector`).
+  pub is_loop_condition: bool,
+}
+
+#[derive(Clone)]
+pub struct AltFrame {
+  pub flag: Val,
+  pub frame: BytecodeStackFrame,
 }
 
 #[derive(Clone)]
@@ -36,6 +63,18 @@ pub struct BytecodeStackFrame {
   pub return_target: Option<usize>,
   pub catch_setting: Option<CatchSetting>,
   pub fork_info: Option<Box<ForkInfo>>,
+  pub intrinsic_call: Option<Box<IntrinsicCall>>,
+
+  /// The function to use for the next call instead of the one in the bytecode. This is how the
+  /// remaining candidates of a `FunctionSet` are called after forking.
+  pub dispatch_fn: Option<Val>,
+
+  /// Where the function's bytecode starts, which identifies the function (eg for recursion
+  /// limits).
+  pub fn_start: usize,
+
+  /// The position of the function's metadata, which includes its name.
+  pub fn_meta_pos: Option<usize>,
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -45,6 +84,19 @@ pub struct CatchSetting {
 }
 
 impl BytecodeStackFrame {
+  /// The name of the function this frame is running.
+  pub fn fn_name(&self) -> String {
+    let name = match self.fn_meta_pos {
+      Some(meta_pos) => self.decoder.clone_at(meta_pos).decode_meta().name,
+      None => String::new(),
+    };
+
+    match name.is_empty() {
+      true => "<anonymous>".to_string(),
+      false => name,
+    }
+  }
+
   pub fn apply_unary_op(&mut self, op: fn(input: &Val) -> Result<Val, Val>) -> Result<(), Val> {
     let input = self.decoder.decode_val(&mut self.registers);
 
@@ -122,6 +174,396 @@ impl BytecodeStackFrame {
     }
   }
 
+  /// If `fn_` is an intrinsic, decodes the rest of the call into `self.intrinsic_call` for the VM
+  /// to handle.
+  pub fn intercept_intrinsic(&mut self, fn_: &Val, instruction_pos: usize) -> bool {
+    let intrinsic_fn = match val_dynamic_downcast::<IntrinsicFn>(fn_) {
+      Some(intrinsic_fn) => intrinsic_fn,
+      None => return false,
+    };
+
+    let mut args = self.decode_parameters();
+    let return_target = self.decoder.decode_register_index();
+
+    let call_site = match intrinsic_fn.takes_call_site && !args.is_empty() {
+      true => args.remove(0).to_index(),
+      false => None,
+    };
+
+    self.intrinsic_call = Some(Box::new(IntrinsicCall {
+      intrinsic: intrinsic_fn.intrinsic,
+      args,
+      return_target,
+      pos: instruction_pos,
+      call_site,
+    }));
+
+    true
+  }
+
+  /// Chooses the function to call. If `fn_` is a `FunctionSet`, this returns its first candidate
+  /// and forks, so that the alt branch repeats the call with the remaining candidates.
+  pub fn dispatch(&mut self, fn_: Val, instruction_pos: usize) -> Result<Val, Val> {
+    let fn_ = take(&mut self.dispatch_fn).unwrap_or(fn_);
+
+    let (first, rest) = match val_dynamic_downcast::<FunctionSet>(&fn_) {
+      Some(function_set) => function_set.split_first(),
+      None => return Ok(fn_),
+    };
+
+    let mut alt_frame = self.clone();
+    alt_frame.decoder.pos = instruction_pos;
+    alt_frame.dispatch_fn = Some(rest);
+
+    self.fork_info = Some(Box::new(ForkInfo {
+      pos: instruction_pos,
+      alts: vec![AltFrame {
+        flag: op_not(&first.cond)?,
+        frame: alt_frame,
+      }],
+      flag: first.cond,
+      is_loop_condition: false,
+    }));
+
+    Ok(first.fn_)
+  }
+
+  /**
+   * Throws an exception that is only defined under a signal, which happens when a `finally` block
+   * is reached both with and without an exception. This forks into a frame that repeats the
+   * `throw` with the exception (under `optional.is_defined`), while this frame continues.
+   *
+   * The exception is merged like any other value, so exceptions from different branches become a
+   * single exception whose fields are signals.
+   */
+  fn throw_optional(
+    &mut self,
+    instruction_pos: usize,
+    register: usize,
+    optional: &OptionalSignal,
+  ) -> Result<(), Val> {
+    let mut alt_frame = self.clone();
+    alt_frame.decoder.pos = instruction_pos;
+    alt_frame.registers[register] = optional.value.clone();
+
+    self.fork_info = Some(Box::new(ForkInfo {
+      pos: instruction_pos,
+      flag: op_not(&optional.is_defined)?,
+      alts: vec![AltFrame {
+        flag: optional.is_defined.clone(),
+        frame: alt_frame,
+      }],
+      is_loop_condition: false,
+    }));
+
+    Ok(())
+  }
+
+  /// Continues at both the next instruction (under `flag`) and `dst` (under `alt_flag`). This is
+  /// done in place when `speculate` succeeds, otherwise by forking.
+  fn branch(
+    &mut self,
+    instruction_pos: usize,
+    flag: Val,
+    alt_flag: Val,
+    dst: usize,
+  ) -> Result<(), Val> {
+    let mut alt_frame = self.clone();
+    alt_frame.decoder.pos = dst;
+
+    if self.fork_switch(instruction_pos, &alt_flag, dst)? {
+      return Ok(());
+    }
+
+    let sides = [
+      AltFrame {
+        flag: flag.clone(),
+        frame: self.clone(),
+      },
+      AltFrame {
+        flag: alt_flag.clone(),
+        frame: alt_frame.clone(),
+      },
+    ];
+
+    if let Some(merged) = self.speculate(instruction_pos, &sides) {
+      *self = merged;
+      return Ok(());
+    }
+
+    self.fork_info = Some(Box::new(ForkInfo {
+      pos: instruction_pos,
+      flag,
+      alts: vec![AltFrame {
+        flag: alt_flag,
+        frame: alt_frame,
+      }],
+      is_loop_condition: self.is_loop_condition(instruction_pos, dst),
+    }));
+
+    Ok(())
+  }
+
+  /**
+   * Whether the conditional jump at `pos` to `dst` is the condition of a loop. This is either the
+   * back-edge of a `do...while` loop (which jumps backwards), or the condition at the top of the
+   * loop, which jumps past the loop's back-edge (`jmp` to the top of the loop) when the loop ends:
+   *
+   * ```text
+   * loop:
+   *   op< %i %n %cond
+   *   jmpif_not %cond end   <- pos, dst = end
+   *   ...
+   *   jmp loop              <- back-edge, recorded when it runs
+   * end:
+   * ```
+   *
+   * Back-edges are only known once they have run, so the first iteration's condition isn't
+   * recognized, which only delays loop detection by one iteration. Other jumps in the loop, like
+   * `if (...) { return; }`, don't jump past a back-edge to the top of the loop.
+   */
+  fn is_loop_condition(&self, pos: usize, dst: usize) -> bool {
+    if dst <= pos {
+      return true;
+    }
+
+    self
+      .decoder
+      .bytecode
+      .back_edges
+      .borrow()
+      .range(pos + 1..dst)
+      .any(|(_, target)| *target <= pos)
+  }
+
+  /**
+   * Recognizes a `switch` on a signal, which compiles to a series of `JmpIf (x === value)`
+   * instructions (one for each case). Forking at each of these would nest the flags of the later
+   * cases inside the negations of the earlier ones. Instead, since the cases are exclusive, this
+   * computes every case flag up front (a one-hot vector) and continues in all the cases and the
+   * default at once. When they are simple enough for `speculate`, they are merged in a single step,
+   * summing each case's values times its flag. Otherwise this forks once into all of them.
+   *
+   * `case_flag` is the condition of the current `JmpIf` and `dst` is its target. Returns whether
+   * the fork was set up (there need to be at least two cases).
+   */
+  fn fork_switch(
+    &mut self,
+    instruction_pos: usize,
+    case_flag: &Val,
+    dst: usize,
+  ) -> Result<bool, Val> {
+    let (discriminant, first_value) = match case_test(case_flag) {
+      Some(case) => case,
+      None => return Ok(false),
+    };
+
+    let mut case_values = vec![first_value];
+
+    let mut first_case = self.clone();
+    first_case.decoder.pos = dst;
+
+    let mut alts = vec![AltFrame {
+      flag: case_flag.clone(),
+      frame: first_case,
+    }];
+
+    // The frame after the last case test that has been found
+    let mut frame = self.clone();
+    let mut probe = frame.clone();
+
+    for _ in 0..SPECULATION_STEP_LIMIT {
+      let mut decoder = probe.decoder.clone();
+
+      if decoder.decode_instruction() != InstructionByte::JmpIf {
+        match probe.speculative_step() {
+          Some(()) => continue,
+          None => break,
+        }
+      }
+
+      let mut registers = probe.registers.clone();
+      let cond = decoder.decode_val(&mut registers);
+      let case_dst = decoder.decode_pos();
+
+      match case_test(&cond) {
+        Some((d, value)) if d == discriminant && !case_values.contains(&value) => {
+          case_values.push(value);
+        }
+        _ => break,
+      }
+
+      probe.decoder = decoder;
+      probe.registers = registers;
+      frame = probe.clone();
+
+      let mut case_frame = frame.clone();
+      case_frame.decoder.pos = case_dst;
+
+      alts.push(AltFrame {
+        flag: cond,
+        frame: case_frame,
+      });
+    }
+
+    if alts.len() < 2 {
+      return Ok(false);
+    }
+
+    let mut any_case = alts[0].flag.clone();
+
+    for alt in &alts[1..] {
+      any_case = op_or(&any_case, &alt.flag)?;
+    }
+
+    let default_flag = op_not(&any_case)?;
+
+    let mut cases = alts.clone();
+
+    cases.push(AltFrame {
+      flag: default_flag.clone(),
+      frame: frame.clone(),
+    });
+
+    if let Some(merged) = self.speculate(instruction_pos, &cases) {
+      *self = merged;
+      return Ok(true);
+    }
+
+    frame.fork_info = Some(Box::new(ForkInfo {
+      pos: instruction_pos,
+      flag: default_flag,
+      alts,
+      is_loop_condition: false,
+    }));
+
+    *self = frame;
+
+    Ok(true)
+  }
+
+  /**
+   * Runs every side of a signal-dependent jump (both sides of a `JmpIf`, or all the cases of a
+   * `switch`) until they meet again, and merges the registers directly in a single step. This
+   * avoids forking the VM for simple expressions like `cond ? a : b`, `a && b`,
+   * `if (cond) { x += y; }` and `switch` statements that look up a value for each case.
+   *
+   * Returns `None` (so that the VM forks instead) if any side does anything other than simple
+   * operations on registers, jumps backwards, or takes too many steps. Errors while merging also
+   * return `None`, so that they are reported when the forked branches merge.
+   */
+  fn speculate(&self, instruction_pos: usize, sides: &[AltFrame]) -> Option<BytecodeStackFrame> {
+    let mut frames = sides
+      .iter()
+      .map(|side| side.frame.clone())
+      .collect::<Vec<_>>();
+
+    for _ in 0..SPECULATION_STEP_LIMIT * (frames.len() - 1) {
+      let first_pos = frames[0].decoder.pos;
+
+      if frames.iter().any(|frame| frame.decoder.pos != first_pos) {
+        // Step whichever side is furthest behind
+        let behind = frames.iter_mut().min_by_key(|frame| frame.decoder.pos)?;
+        behind.speculative_step()?;
+        continue;
+      }
+
+      if !frames[1..].iter().all(|frame| frames[0].can_merge(frame)) {
+        return None;
+      }
+
+      let merge_pos = Some(first_pos);
+      let fn_name = Some(Rc::<str>::from(frames[0].fn_name()));
+      let mut merged = frames[0].clone();
+
+      for i in 0..merged.registers.len() {
+        let values = sides
+          .iter()
+          .zip(&frames)
+          .map(|(side, frame)| (side.flag.clone(), frame.registers[i].clone()))
+          .collect::<Vec<_>>();
+
+        merged.registers[i] = arithmetic_merge_many(
+          &MergeSite {
+            target: MergeTarget::Register(i),
+            fn_name: fn_name.clone(),
+            fork_pos: Some(instruction_pos),
+            merge_pos,
+          },
+          &values,
+        )
+        .ok()?;
+      }
+
+      return Some(merged);
+    }
+
+    None
+  }
+
+  /// Executes the next instruction if it only operates on registers and moves forwards.
+  fn speculative_step(&mut self) -> Option<()> {
+    use InstructionByte::*;
+
+    let pos = self.decoder.pos;
+
+    let is_simple = matches!(
+      self.decoder.clone_at(pos).decode_instruction(),
+      Mov
+        | OpInc
+        | OpDec
+        | OpPlus
+        | OpMinus
+        | OpMul
+        | OpDiv
+        | OpMod
+        | OpExp
+        | OpEq
+        | OpNe
+        | OpTripleEq
+        | OpTripleNe
+        | OpAnd
+        | OpOr
+        | OpNot
+        | OpLess
+        | OpLessEq
+        | OpGreater
+        | OpGreaterEq
+        | OpNullishCoalesce
+        | OpOptionalChain
+        | OpBitAnd
+        | OpBitOr
+        | OpBitNot
+        | OpBitXor
+        | OpLeftShift
+        | OpRightShift
+        | OpRightShiftUnsigned
+        | TypeOf
+        | In
+        | Sub
+        | Jmp
+        | JmpIf
+        | JmpIfNot
+        | UnaryPlus
+        | UnaryMinus
+    );
+
+    if !is_simple {
+      return None;
+    }
+
+    match self.step() {
+      Ok(FrameStepOk::Continue) => {}
+      _ => return None,
+    }
+
+    if self.fork_info.is_some() || self.decoder.pos <= pos {
+      return None;
+    }
+
+    Some(())
+  }
+
   pub fn can_merge(&self, other: &BytecodeStackFrame) -> bool {
     if !std::ptr::eq(
       self.decoder.bytecode.as_ref(),
@@ -140,6 +582,10 @@ impl BytecodeStackFrame {
       return_target,
       catch_setting,
       fork_info: _,
+      intrinsic_call: _,
+      dispatch_fn: _,
+      fn_start,
+      fn_meta_pos: _,
     } = self;
 
     let self_fields = (
@@ -154,6 +600,7 @@ impl BytecodeStackFrame {
       this_target,
       return_target,
       catch_setting,
+      fn_start,
     );
 
     let BytecodeStackFrame {
@@ -166,6 +613,10 @@ impl BytecodeStackFrame {
       return_target,
       catch_setting,
       fork_info: _,
+      intrinsic_call: _,
+      dispatch_fn: _,
+      fn_start,
+      fn_meta_pos: _,
     } = other;
 
     let other_fields = (
@@ -180,6 +631,7 @@ impl BytecodeStackFrame {
       this_target,
       return_target,
       catch_setting,
+      fn_start,
     );
 
     self_fields == other_fields
@@ -203,6 +655,7 @@ impl StackFrameTrait for BytecodeStackFrame {
   fn step(&mut self) -> FrameStepResult {
     use InstructionByte::*;
 
+    let instruction_pos = self.decoder.pos;
     let instruction_byte = self.decoder.decode_instruction();
 
     match instruction_byte {
@@ -262,13 +715,13 @@ impl StackFrameTrait for BytecodeStackFrame {
       OpLessEq => self.apply_binary_op(operations::op_less_eq)?,
       OpGreater => self.apply_binary_op(operations::op_greater)?,
       OpGreaterEq => self.apply_binary_op(operations::op_greater_eq)?,
-      OpNullishCoalesce => self.apply_binary_op(operations::op_nullish_coalesce)?,
+      OpNullishCoalesce => self.apply_binary_op(op_nullish_coalesce_optional)?,
       OpOptionalChain => {
         let mut left = self.decoder.decode_val(&mut self.registers);
         let right = self.decoder.decode_val(&mut self.registers);
 
         if let Some(register_index) = self.decoder.decode_register_index() {
-          self.registers[register_index] = operations::op_optional_chain(&mut left, &right)?;
+          self.registers[register_index] = op_optional_chain_optional(&mut left, &right)?;
         }
       }
       OpBitAnd => self.apply_binary_op(operations::op_bit_and)?,
@@ -281,13 +734,18 @@ impl StackFrameTrait for BytecodeStackFrame {
       OpRightShift => self.apply_binary_op(operations::op_right_shift)?,
       OpRightShiftUnsigned => self.apply_binary_op(operations::op_right_shift_unsigned)?,
 
-      TypeOf => self.apply_unary_op(operations::op_typeof)?,
+      TypeOf => self.apply_unary_op(op_typeof_optional)?,
 
       InstanceOf => self.apply_binary_op(operations::op_instance_of)?,
-      In => self.apply_binary_op(operations::op_in)?,
+      In => self.apply_binary_op(op_in_optional_key)?,
 
       Call => {
         let fn_ = self.decoder.decode_val(&mut self.registers);
+        let fn_ = self.dispatch(fn_, instruction_pos)?;
+
+        if self.intercept_intrinsic(&fn_, instruction_pos) {
+          return Ok(FrameStepOk::Continue);
+        }
 
         match fn_.load_function() {
           LoadFunctionResult::NotAFunction => {
@@ -338,8 +796,27 @@ impl StackFrameTrait for BytecodeStackFrame {
 
             return Ok(FrameStepOk::Push(new_frame));
           }
-          LoadFunctionResult::NativeFunction(_native_fn) => {
-            return Err("TODO: apply native functions".to_internal_error());
+          LoadFunctionResult::NativeFunction(native_fn) => {
+            let this_target = self.decoder.decode_register_index();
+            let params = self.decode_parameters();
+
+            let mut this = match this_target {
+              Some(this_target) => self.registers[this_target].clone(),
+              None => Val::Undefined,
+            };
+
+            let res = native_fn(
+              ThisWrapper::new(instruction_byte == ConstApply, &mut this),
+              params,
+            )?;
+
+            if let Some(this_target) = this_target {
+              self.registers[this_target] = this;
+            }
+
+            if let Some(return_target) = self.decoder.decode_register_index() {
+              self.registers[return_target] = res;
+            };
           }
         }
       }
@@ -373,9 +850,10 @@ impl StackFrameTrait for BytecodeStackFrame {
       Sub => {
         let mut left = self.decoder.decode_val(&mut self.registers);
         let right = self.decoder.decode_val(&mut self.registers);
+        check_subscript(&right)?;
 
         if let Some(register_index) = self.decoder.decode_register_index() {
-          self.registers[register_index] = operations::op_sub(&mut left, &right)?;
+          self.registers[register_index] = read_property(operations::op_sub(&mut left, &right)?);
         }
       }
 
@@ -386,12 +864,21 @@ impl StackFrameTrait for BytecodeStackFrame {
         // element, not the vec itself. vec.get_many_mut has been considered, but it's not yet
         // stable.
         let subscript = self.decoder.decode_val(&mut self.registers);
+        check_subscript(&subscript)?;
 
         let value = self.decoder.decode_val(&mut self.registers);
 
         let target_index = self.decoder.decode_register_index().unwrap();
-
-        operations::op_submov(&mut self.registers[target_index], &subscript, value)?;
+        let target = &mut self.registers[target_index];
+
+        // op_submov doesn't support dynamic values, so assign to a copy instead
+        if let Some(vec) = val_dynamic_downcast::<ObliviousVec>(target) {
+          let mut vec = vec.clone();
+          vec.set(&subscript, value)?;
+          *target = vec.to_dynamic_val();
+        } else {
+          operations::op_submov(target, &subscript, value)?;
+        }
       }
 
       ConstSubCall => {
@@ -399,7 +886,16 @@ impl StackFrameTrait for BytecodeStackFrame {
 
         let mut obj = self.decoder.decode_val(&mut self.registers);
         let subscript = self.decoder.decode_val(&mut self.registers);
-        let fn_ = obj.sub(&subscript)?;
+        check_subscript(&subscript)?;
+        let fn_ = match array_method(&obj, &subscript).or_else(|| object_method(&obj, &subscript)) {
+          Some(fn_) => fn_,
+          None => read_property(obj.sub(&subscript)?),
+        };
+        let fn_ = self.dispatch(fn_, instruction_pos)?;
+
+        if self.intercept_intrinsic(&fn_, instruction_pos) {
+          return Ok(FrameStepOk::Continue);
+        }
 
         match fn_.load_function() {
           LoadFunctionResult::NotAFunction => {
@@ -433,7 +929,16 @@ impl StackFrameTrait for BytecodeStackFrame {
 
         let obj_i = self.decoder.decode_register_index().unwrap();
         let subscript = self.decoder.decode_val(&mut self.registers);
-        let fn_ = self.registers[obj_i].sub(&subscript)?;
+        check_subscript(&subscript)?;
+        let fn_ = match array_method(&self.registers[obj_i], &subscript) {
+          Some(fn_) => fn_,
+          None => read_property(self.registers[obj_i].sub(&subscript)?),
+        };
+        let fn_ = self.dispatch(fn_, instruction_pos)?;
+
+        if self.intercept_intrinsic(&fn_, instruction_pos) {
+          return Ok(FrameStepOk::Continue);
+        }
 
         match fn_.load_function() {
           LoadFunctionResult::NotAFunction => {
@@ -466,30 +971,29 @@ impl StackFrameTrait for BytecodeStackFrame {
 
       Jmp => {
         let dst = self.decoder.decode_pos();
+
+        if dst <= instruction_pos {
+          let mut back_edges = self.decoder.bytecode.back_edges.borrow_mut();
+          back_edges.insert(instruction_pos, dst);
+        }
+
         self.decoder.pos = dst;
       }
 
       JmpIf => 'b: {
-        let cond = self.decoder.decode_val(&mut self.registers);
+        let cond = normalize_condition(self.decoder.decode_val(&mut self.registers))?;
         let dst = self.decoder.decode_pos();
 
         if let Some(cond_signal) = val_dynamic_downcast::<CircuitSignal>(&cond) {
-          let mut alt_frame = self.clone();
-          alt_frame.decoder.pos = dst;
-
-          let flag = op_not(&cond).unwrap();
+          let flag = op_not(&cond)?;
 
           let alt_flag = match cond_signal.type_ {
             VsType::Bool => cond,
-            VsType::Number => op_not(&flag).unwrap(),
-            _ => panic!("Unexpected signal type {}", cond_signal.type_),
+            VsType::Number => op_not(&flag)?,
+            type_ => return Err(unexpected_signal_type(type_)),
           };
 
-          self.fork_info = Some(Box::new(ForkInfo {
-            flag,
-            alt_flag,
-            alt_frame,
-          }));
+          self.branch(instruction_pos, flag, alt_flag, dst)?;
 
           break 'b;
         }
@@ -500,26 +1004,19 @@ impl StackFrameTrait for BytecodeStackFrame {
       }
 
       JmpIfNot => 'b: {
-        let cond = self.decoder.decode_val(&mut self.registers);
+        let cond = normalize_condition(self.decoder.decode_val(&mut self.registers))?;
         let dst = self.decoder.decode_pos();
 
         if let Some(cond_signal) = val_dynamic_downcast::<CircuitSignal>(&cond) {
-          let mut alt_frame = self.clone();
-          alt_frame.decoder.pos = dst;
-
-          let alt_flag = op_not(&cond).unwrap();
+          let alt_flag = op_not(&cond)?;
 
           let flag = match cond_signal.type_ {
             VsType::Bool => cond,
-            VsType::Number => op_not(&alt_flag).unwrap(),
-            _ => panic!("Unexpected signal type {}", cond_signal.type_),
+            VsType::Number => op_not(&alt_flag)?,
+            type_ => return Err(unexpected_signal_type(type_)),
           };
 
-          self.fork_info = Some(Box::new(ForkInfo {
-            flag,
-            alt_flag,
-            alt_frame,
-          }));
+          self.branch(instruction_pos, flag, alt_flag, dst)?;
 
           break 'b;
         }
@@ -588,30 +1085,36 @@ impl StackFrameTrait for BytecodeStackFrame {
       }
 
       Throw => {
-        return match self.decoder.peek_type() {
+        // Avoid the void->undefined conversion for registers, since void means there is no
+        // exception (eg at the end of a `finally` block)
+        let (error, register) = match self.decoder.peek_type() {
           BytecodeType::TakeRegister => {
             self.decoder.decode_type();
+            let register = self.decoder.decode_register_index().unwrap();
 
-            // Avoid the void->undefined conversion here
-            let error = take(&mut self.registers[self.decoder.decode_register_index().unwrap()]);
-
-            match error {
-              Val::Void => Ok(FrameStepOk::Continue),
-              _ => Err(error),
-            }
+            (take(&mut self.registers[register]), Some(register))
           }
           BytecodeType::Register => {
             self.decoder.decode_type();
+            let register = self.decoder.decode_register_index().unwrap();
 
-            // Avoid the void->undefined conversion here
-            let error = self.registers[self.decoder.decode_register_index().unwrap()].clone();
+            (self.registers[register].clone(), Some(register))
+          }
+          _ => (self.decoder.decode_val(&mut self.registers), None),
+        };
 
-            match error {
-              Val::Void => Ok(FrameStepOk::Continue),
-              _ => Err(error),
-            }
+        if let (Some(optional), Some(register)) =
+          (val_dynamic_downcast::<OptionalSignal>(&error), register)
+        {
+          if let Val::Void = optional.nullish {
+            self.throw_optional(instruction_pos, register, optional)?;
+            return Ok(FrameStepOk::Continue);
           }
-          _ => Err(self.decoder.decode_val(&mut self.registers)),
+        }
+
+        return match error {
+          Val::Void => Ok(FrameStepOk::Continue),
+          _ => Err(error),
         };
       }
 
@@ -646,6 +1149,9 @@ impl StackFrameTrait for BytecodeStackFrame {
 
         let next_fn = self.registers[iter_i].sub(&"next".to_val())?;
 
+        // Generators suspended at different points are advanced separately (see `GeneratorSet`)
+        let next_fn = self.dispatch(next_fn, instruction_pos)?;
+
         match next_fn.load_function() {
           LoadFunctionResult::NotAFunction => {
             return Err(".next() is not a function".to_type_error())
@@ -709,14 +1215,20 @@ impl StackFrameTrait for BytecodeStackFrame {
 
       Yield => {
         let val = self.decoder.decode_val(&mut self.registers);
-        self.decoder.decode_register_index(); // TODO: Use this
+
+        // Receives the value passed to `next` when the generator resumes
+        self.this_target = None;
+        self.return_target = self.decoder.decode_register_index();
 
         return Ok(FrameStepOk::Yield(val));
       }
 
       YieldStar => {
         let val = self.decoder.decode_val(&mut self.registers);
-        self.decoder.decode_register_index(); // TODO: Use this
+
+        // Receives the return value of the delegated iterator
+        self.this_target = None;
+        self.return_target = self.decoder.decode_register_index();
 
         return Ok(FrameStepOk::YieldStar(val));
       }
@@ -855,3 +1367,52 @@ impl StackFrameTrait for BytecodeStackFrame {
     self
   }
 }
+
+/// Converts optional conditions into signals, and rejects conditions that couldn't be merged
+/// (which would otherwise be treated as truthy).
+fn normalize_condition(cond: Val) -> Result<Val, Val> {
+  if let Some(could_not_merge) = val_dynamic_downcast::<CouldNotMerge>(&cond) {
+    return Err(could_not_merge.to_error().to_val());
+  }
+
+  if let Some(optional) = val_dynamic_downcast::<OptionalSignal>(&cond) {
+    return optional.truthiness();
+  }
+
+  Ok(cond)
+}
+
+/// If `cond` is `x === value` where `x` is a signal and `value` is a constant, returns the id of `x`
+/// and `value`.
+fn case_test(cond: &Val) -> Option<(usize, f64)> {
+  let signal = val_dynamic_downcast::<CircuitSignal>(cond)?;
+
+  let (left, right) = match &signal.data {
+    CircuitSignalData::BinaryOp(BinaryOp::Eq, left, right) => (left, right),
+    _ => return None,
+  };
+
+  let (discriminant, value) = match (left, right) {
+    (Val::Number(value), discriminant) | (discriminant, Val::Number(value)) => {
+      (discriminant, *value)
+    }
+    _ => return None,
+  };
+
+  Some((
+    val_dynamic_downcast::<CircuitSignal>(discriminant)?.id,
+    value,
+  ))
+}
+
+fn unexpected_signal_type(type_: VsType) -> Val {
+  CircuitError::Internal(format!("Unexpected signal type {}", type_)).to_val()
+}
+
+/// Subscripts are resolved at compile time, so they can't depend on signals.
+fn check_subscript(subscript: &Val) -> Result<(), Val> {
+  match val_dynamic_downcast::<CircuitSignal>(subscript) {
+    Some(_) => Err(CircuitError::SignalAsIndex.to_val()),
+    None => Ok(()),
+  }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use valuescript_compiler::{Diagnostic, ResolvedPath};
+
+use crate::{
+  compile_error::{SourceLocation, SourceSpan},
+  intrinsics::is_summon_module_path,
+  source_annotations::line_col,
+  source_tokens::{is_open, is_punct_at, tokenize, Groups, Token, TokenKind},
+};
+
+/// Methods of the summon module whose calls are rewritten to pass their call site.
+const LOCATED_METHODS: [&str; 2] = ["assert", "log"];
+
+/**
+ * ValueScript doesn't produce a source map, so the bytecode doesn't say where a call came from. To
+ * report where `summon.assert` and `summon.log` are called, each module is rewritten as it is
+ * loaded, so that these calls pass the id of their call site as an extra first argument:
+ *
+ * ```ts
+ * summon.assert(x < 20, "x must be less than 20");
+ *
+ * // becomes
+ * summon.assertAt(0, x < 20, "x must be less than 20");
+ * ```
+ *
+ * Calls are found using the name that the summon module is imported as, so calls through other
+ * names (eg `const { assert } = summon`) still work, but don't have a location. Calls where a
+ * local declaration shadows the import (eg a parameter named `summon`) are left alone.
+ */
+#[derive(Default)]
+pub struct CallSites {
+  sites: Vec<SourceLocation>,
+
+  /// The text inserted into each rewritten file (path -> (position, length)), in characters of
+  /// the original source. This is used to map diagnostics back to the original source.
+  insertions: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl CallSites {
+  /// The location of the callee (eg `summon.assert`) of a call site.
+  pub fn get(&self, id: usize) -> Option<&SourceLocation> {
+    self.sites.get(id)
+  }
+
+  /// Rewrites the calls in `source` to pass the ids of their call sites.
+  pub fn rewrite(&mut self, path: &str, source: &str) -> String {
+    let tokens = tokenize(source);
+    let names = summon_import_names(&tokens);
+
+    if names.is_empty() {
+      return source.to_string();
+    }
+
+    let mut res = String::new();
+    let mut insertions = Vec::<(usize, usize)>::new();
+    let mut copied = 0;
+
+    for call in find_calls(source, &tokens, &names) {
+      let start = source[..call.start].chars().count();
+      let method_end = start + source[call.start..call.method_end].chars().count();
+      let args_start = method_end + source[call.method_end..call.args_start].chars().count();
+      let (line, column) = line_col(source, call.start);
+
+      let arg = format!("{}, ", self.sites.len());
+
+      self.sites.push(SourceLocation {
+        span: SourceSpan {
+          path: path.to_string(),
+          start,
+          end: method_end,
+        },
+        line,
+        column,
+      });
+
+      res += &source[copied..call.method_end];
+      res += "At";
+      res += &source[call.method_end..call.args_start];
+      res += &arg;
+
+      insertions.push((method_end, "At".len()));
+      insertions.push((args_start, arg.len()));
+
+      copied = call.args_start;
+    }
+
+    res += &source[copied..];
+
+    if !insertions.is_empty() {
+      self.insertions.insert(path.to_string(), insertions);
+    }
+
+    res
+  }
+
+  /// Maps the spans of diagnostics in rewritten files back to the original source.
+  pub fn fix_diagnostics(&self, diagnostics: &mut HashMap<ResolvedPath, Vec<Diagnostic>>) {
+    for (path, file_diagnostics) in diagnostics.iter_mut() {
+      let insertions = match self.insertions.get(&path.path) {
+        Some(insertions) => insertions,
+        None => continue,
+      };
+
+      for diagnostic in file_diagnostics {
+        diagnostic.span.lo.0 = original_pos(insertions, diagnostic.span.lo.0 as usize) as u32;
+        diagnostic.span.hi.0 = original_pos(insertions, diagnostic.span.hi.0 as usize) as u32;
+      }
+    }
+  }
+}
+
+/// Converts a position in rewritten source into a position in the original source. Positions
+/// inside inserted text map to where it was inserted.
+fn original_pos(insertions: &[(usize, usize)], pos: usize) -> usize {
+  let mut shift = 0;
+
+  for (insertion_pos, len) in insertions {
+    let rewritten_pos = insertion_pos + shift;
+
+    if pos < rewritten_pos {
+      break;
+    }
+
+    if pos < rewritten_pos + len {
+      return *insertion_pos;
+    }
+
+    shift += len;
+  }
+
+  pos - shift
+}
+
+/// The names that the summon module is imported as, eg `summon` for `import summon from "summon"`.
+fn summon_import_names(tokens: &[Token]) -> Vec<String> {
+  let mut names = Vec::<String>::new();
+
+  for (i, token) in tokens.iter().enumerate() {
+    if !token.is_ident("import") || is_member(tokens, i) {
+      continue;
+    }
+
+    let clause = &tokens[i + 1..];
+
+    let from = match clause
+      .iter()
+      .position(|token| token.is_ident("from") || token.is_punct(";"))
+    {
+      Some(from) if clause[from].is_ident("from") => from,
+      _ => continue,
+    };
+
+    let specifier = match clause.get(from + 1) {
+      Some(token) if token.kind == TokenKind::Literal => token.text,
+      _ => continue,
+    };
+
+    let specifier = specifier
+      .get(1..specifier.len().saturating_sub(1))
+      .unwrap_or_default();
+
+    if !is_summon_module_path(specifier) {
+      continue;
+    }
+
+    // `import type { SummonVec } from "summon"` doesn't import the module
+    match clause[..from] {
+      [first, second, ..] if first.is_ident("type") && !second.is_punct(",") => continue,
+      [first, ..] if first.kind == TokenKind::Ident => names.push(first.text.to_string()),
+      _ => {}
+    }
+  }
+
+  names
+}
+
+struct Call {
+  /// Position of the object, eg `summon`.
+  start: usize,
+
+  /// Position after the method name, eg `assert`.
+  method_end: usize,
+
+  /// Position after the `(`.
+  args_start: usize,
+}
+
+/// Finds calls like `<name>.assert(` in the source, skipping names that are shadowed by a local
+/// declaration (eg a parameter named `summon`).
+fn find_calls(source: &str, tokens: &[Token], names: &[String]) -> Vec<Call> {
+  let groups = Groups::new(source, tokens);
+  let mut calls = Vec::<Call>::new();
+
+  for name in names {
+    let shadowed = shadowed_ranges(tokens, &groups, name);
+
+    for (i, token) in tokens.iter().enumerate() {
+      if !token.is_ident(name) || is_member(tokens, i) {
+        continue;
+      }
+
+      if shadowed
+        .iter()
+        .any(|(start, end)| (*start..*end).contains(&token.pos))
+      {
+        continue;
+      }
+
+      if let [dot, method, open_paren, ..] = &tokens[i + 1..] {
+        if dot.is_punct(".")
+          && method.kind == TokenKind::Ident
+          && LOCATED_METHODS.contains(&method.text)
+          && open_paren.is_punct("(")
+        {
+          calls.push(Call {
+            start: token.pos,
+            method_end: method.end(),
+            args_start: open_paren.end(),
+          });
+        }
+      }
+    }
+  }
+
+  calls.sort_by_key(|call| call.start);
+  calls
+}
+
+/// Whether the token is accessed as a property, eg `x.summon`.
+fn is_member(tokens: &[Token], i: usize) -> bool {
+  i > 0 && (tokens[i - 1].is_punct(".") || tokens[i - 1].is_punct("?."))
+}
+
+/**
+ * The parts of the source (as byte ranges) where `name` refers to a local declaration instead of
+ * the import. These are found from the tokens rather than by parsing, so they can be larger than
+ * the real scopes, which only means that calls in them don't get a location. Declarations that are
+ * recognized:
+ *
+ * - `let`, `const`, `var`, `function` and `class` declarations, including destructuring
+ * - parameters of functions, methods, arrow functions and `catch` clauses
+ */
+fn shadowed_ranges(tokens: &[Token], groups: &Groups, name: &str) -> Vec<(usize, usize)> {
+  let mut ranges = Vec::<(usize, usize)>::new();
+
+  for (i, token) in tokens.iter().enumerate() {
+    if !token.is_ident(name) || is_member(tokens, i) {
+      continue;
+    }
+
+    let prev = i.checked_sub(1).map(|prev| &tokens[prev]);
+    let next = tokens.get(i + 1);
+    let next_is =
+      |puncts: &[&str]| next.is_some_and(|next| puncts.iter().any(|p| next.is_punct(p)));
+    let prev_is =
+      |puncts: &[&str]| prev.is_some_and(|prev| puncts.iter().any(|p| prev.is_punct(p)));
+
+    // `let name`, `function name`, `class name`, ...
+    match prev
+      .filter(|prev| prev.kind == TokenKind::Ident)
+      .map(|prev| prev.text)
+    {
+      Some("let" | "const" | "function" | "class") => {
+        ranges.push(groups.enclosing(i));
+        continue;
+      }
+      Some("var") => {
+        // `var` is scoped to the function, so this covers the outermost group instead
+        let mut outermost = i;
+
+        while let Some(parent) = groups.parent[outermost] {
+          outermost = parent;
+        }
+
+        ranges.push(match outermost == i {
+          true => (0, groups.source_len),
+          false => groups.range(outermost),
+        });
+
+        continue;
+      }
+      _ => {}
+    }
+
+    // Uses of the name, like `name.assert(...)`, aren't declarations
+    if next_is(&[".", "?.", "(", "["]) {
+      continue;
+    }
+
+    // `let a = 1, name = 2`
+    if prev_is(&[","])
+      && (next.is_none() || next_is(&[",", "=", ";", ":"]))
+      && continues_declaration(tokens, groups, i)
+    {
+      ranges.push(groups.enclosing(i));
+      continue;
+    }
+
+    // `name => ...`
+    if next_is(&["=>"]) {
+      let body = i + 2;
+
+      ranges.push(match is_punct_at(tokens, body, "{") {
+        true => (token.pos, groups.range(body).1),
+        false => (token.pos, groups.expression_end(body)),
+      });
+
+      continue;
+    }
+
+    // `{ key: value }` patterns bind the value, not the key
+    if next_is(&[":"]) && groups.parent[i].is_some_and(|parent| tokens[parent].is_punct("{")) {
+      continue;
+    }
+
+    // Destructuring and parameters, walking out through `{ ... }` and `[ ... ]` patterns
+    let mut group = groups.parent[i];
+    let mut in_pattern = false;
+
+    while let Some(open) = group {
+      if tokens[open].is_punct("(") {
+        let is_param =
+          in_pattern || (prev_is(&["(", ",", "..."]) && next_is(&[",", ")", ":", "=", "?"]));
+
+        if let Some(range) = groups.function_range(open).filter(|_| is_param) {
+          ranges.push(range);
+        }
+
+        break;
+      }
+
+      if !tokens[open].is_punct("{") && !tokens[open].is_punct("[") {
+        break;
+      }
+
+      // `const { name } = ...` or `const [name] = ...`
+      let declared = open.checked_sub(1).is_some_and(|before| {
+        ["let", "const", "var"]
+          .iter()
+          .any(|keyword| tokens[before].is_ident(keyword))
+      });
+
+      if declared {
+        ranges.push(groups.enclosing(open));
+        break;
+      }
+
+      in_pattern = true;
+      group = groups.parent[open];
+    }
+  }
+
+  ranges
+}
+
+/// Whether the token at `i` (after a `,`) is in the same `let`, `const` or `var` statement as an
+/// earlier declaration.
+fn continues_declaration(tokens: &[Token], groups: &Groups, i: usize) -> bool {
+  let mut k = i;
+
+  while k > 0 {
+    k -= 1;
+    let token = &tokens[k];
+
+    if let Some(open) = groups.open.get(&k) {
+      k = *open;
+      continue;
+    }
+
+    if token.is_punct(";") || is_open(token) {
+      return false;
+    }
+
+    if ["let", "const", "var"]
+      .iter()
+      .any(|keyword| token.is_ident(keyword))
+    {
+      return true;
+    }
+  }
+
+  false
+}
@@ -1,9 +1,13 @@
 use std::{cmp::max, collections::HashMap};
 
 use bristol_circuit::{BristolCircuit, CircuitInfo, ConstantInfo, Gate as BristolGate};
+use serde_json::{json, Map, Value};
 use valuescript_vm::{binary_op::BinaryOp, unary_op::UnaryOp};
 
-use crate::bristol_op_strings::{to_bristol_binary_op, to_bristol_unary_op};
+use crate::{
+  bristol_op_strings::{to_bristol_binary_op, to_bristol_unary_op},
+  range_analysis::ValueRange,
+};
 
 #[derive(Default)]
 pub struct Circuit {
@@ -12,6 +16,35 @@ pub struct Circuit {
   pub constants: HashMap<usize, usize>, // wire_id -> value
   pub outputs: HashMap<String, usize>,
   pub gates: Vec<Gate>,
+  pub output_ranges: HashMap<String, Option<ValueRange>>, // None means unbounded
+
+  /// The number of bits needed to represent every wire, if known.
+  pub bit_width: Option<usize>,
+
+  /// Declared input domains (from `@range`), which are checked by the `inputs_valid` output.
+  pub input_domains: HashMap<String, ValueRange>,
+
+  /// Assertions from `summon.assert`, which each have a boolean output.
+  pub assertions: Vec<AssertionInfo>,
+
+  /// The meaning of each value of the `error_code` output (if enabled).
+  pub error_codes: Vec<ErrorCodeInfo>,
+}
+
+pub struct ErrorCodeInfo {
+  pub code: usize,
+
+  /// The exception thrown the first time this site was reached.
+  pub message: String,
+}
+
+pub struct AssertionInfo {
+  pub output: String,
+  pub message: String,
+
+  /// (file, line, column) of the `summon.assert` call, if known. The file is relative to the
+  /// directory of the entry file.
+  pub location: Option<(String, usize, usize)>,
 }
 
 pub enum Gate {
@@ -88,6 +121,70 @@ impl Circuit {
     max_depth
   }
 
+  /// Information that doesn't fit into bristol's `CircuitInfo`. This is merged into
+  /// `circuit_info.json`.
+  pub fn extra_info(&self) -> Map<String, Value> {
+    let mut info = Map::new();
+
+    let output_ranges = self
+      .output_ranges
+      .iter()
+      .map(|(name, range)| {
+        let range = match range {
+          Some(range) => json!({
+            "min": i128_to_json(range.min),
+            "max": i128_to_json(range.max),
+            "bit_width": range.bit_width(),
+          }),
+          None => Value::Null,
+        };
+
+        (name.clone(), range)
+      })
+      .collect::<Map<_, _>>();
+
+    let input_domains = self
+      .input_domains
+      .iter()
+      .map(|(name, domain)| {
+        let domain = json!({
+          "min": i128_to_json(domain.min),
+          "max": i128_to_json(domain.max),
+        });
+
+        (name.clone(), domain)
+      })
+      .collect::<Map<_, _>>();
+
+    let assertions = self
+      .assertions
+      .iter()
+      .map(|assertion| {
+        json!({
+          "output": assertion.output,
+          "message": assertion.message,
+          "file": assertion.location.as_ref().map(|(file, _, _)| file),
+          "line": assertion.location.as_ref().map(|(_, line, _)| line),
+          "column": assertion.location.as_ref().map(|(_, _, column)| column),
+        })
+      })
+      .collect::<Vec<_>>();
+
+    let error_codes = self
+      .error_codes
+      .iter()
+      .map(|info| (info.code.to_string(), Value::String(info.message.clone())))
+      .collect::<Map<_, _>>();
+
+    info.insert("input_domains".into(), Value::Object(input_domains));
+    info.insert("assertions".into(), Value::Array(assertions));
+    info.insert("error_codes".into(), Value::Object(error_codes));
+    info.insert("output_ranges".into(), Value::Object(output_ranges));
+    info.insert("bit_width".into(), json!(self.bit_width));
+
+    info
+  }
+
   pub fn to_bristol(&self) -> BristolCircuit {
     let mut bristol_gates = Vec::<BristolGate>::new();
 
@@ -150,6 +247,13 @@ impl Circuit {
   }
 }
 
+fn i128_to_json(value: i128) -> Value {
+  match i64::try_from(value) {
+    Ok(value) => value.into(),
+    Err(_) => value.to_string().into(),
+  }
+}
+
 pub trait CircuitNumber: Clone {
   fn zero() -> Self;
   fn from_usize(x: usize) -> Self;
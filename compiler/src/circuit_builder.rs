@@ -4,8 +4,10 @@ use num_traits::ToPrimitive;
 use valuescript_vm::{vs_value::Val, ValTrait};
 
 use crate::{
+  arithmetic_merge::CouldNotMerge,
   circuit::Gate,
   circuit_signal::{CircuitSignal, CircuitSignalData},
+  compile_error::CircuitError,
 };
 
 #[derive(Default)]
@@ -25,40 +27,40 @@ impl CircuitBuilder {
     self.wire_count = input_len;
   }
 
-  pub fn include_outputs(&mut self, outputs: &Vec<Val>) -> Vec<usize> {
+  pub fn include_outputs(&mut self, outputs: &Vec<Val>) -> Result<Vec<usize>, CircuitError> {
     for output in outputs {
       for dep in get_dependencies(output) {
-        self.include_val(&dep);
+        self.include_val(&dep)?;
       }
     }
 
     let mut output_ids = vec![];
 
     for output in outputs {
-      output_ids.push(self.include_val(output));
+      output_ids.push(self.include_val(output)?);
     }
 
-    output_ids
+    Ok(output_ids)
   }
 
-  pub fn include_val(&mut self, val: &Val) -> usize {
+  pub fn include_val(&mut self, val: &Val) -> Result<usize, CircuitError> {
     match val {
       Val::Bool(bool) => {
         let value = if *bool { 1usize } else { 0usize };
 
         if let Some(wire_id) = self.constants.get(&value) {
-          return *wire_id;
+          return Ok(*wire_id);
         }
 
         let wire_id = self.wire_count;
         self.wire_count += 1;
         self.constants.insert(value, wire_id);
 
-        wire_id
+        Ok(wire_id)
       }
       Val::Number(number) => {
         if *number != number.trunc() {
-          panic!("Cannot use non-integer constant");
+          return Err(CircuitError::NonIntegerConstant(*number));
         }
 
         let value = if *number < 0.0 {
@@ -68,25 +70,25 @@ impl CircuitBuilder {
         };
 
         if let Some(wire_id) = self.constants.get(&value) {
-          return *wire_id;
+          return Ok(*wire_id);
         }
 
         let wire_id = self.wire_count;
         self.wire_count += 1;
         self.constants.insert(value, wire_id);
 
-        wire_id
+        Ok(wire_id)
       }
       Val::Dynamic(dyn_val) => {
         if let Some(circuit_number) = dyn_val.as_any().downcast_ref::<CircuitSignal>() {
           if let Some(wire_id) = self.wires_included.get(&circuit_number.id) {
-            return *wire_id;
+            return Ok(*wire_id);
           }
 
           let dependent_ids = get_dependencies(val)
             .iter()
             .map(|dep| self.include_val(dep))
-            .collect::<Vec<usize>>();
+            .collect::<Result<Vec<usize>, _>>()?;
 
           let wire_id = self.wire_count;
           self.wire_count += 1;
@@ -110,12 +112,16 @@ impl CircuitBuilder {
 
           self.wires_included.insert(circuit_number.id, wire_id);
 
-          return wire_id;
+          return Ok(wire_id);
         }
 
-        panic!("Can't include unrecognized type ({}) 1", val.codify());
+        if let Some(could_not_merge) = dyn_val.as_any().downcast_ref::<CouldNotMerge>() {
+          return Err(could_not_merge.to_error());
+        }
+
+        Err(CircuitError::UnsupportedValue(val.codify()))
       }
-      _ => panic!("Can't include unrecognized type ({}) 2", val.codify()),
+      _ => Err(CircuitError::UnsupportedValue(val.codify())),
     }
   }
 }
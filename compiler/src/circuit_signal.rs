@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use num_bigint::BigInt;
 use valuescript_vm::{
@@ -10,7 +10,12 @@ use valuescript_vm::{
   LoadFunctionResult, ValTrait,
 };
 
-use crate::{id_generator::IdGenerator, val_dynamic_downcast::val_dynamic_downcast};
+use crate::{
+  compile_error::{defer_error, CircuitError},
+  id_generator::IdGenerator,
+  optional_signal,
+  val_dynamic_downcast::val_dynamic_downcast,
+};
 use valuescript_vm::vs_value::VsType;
 
 #[derive(Clone)]
@@ -31,16 +36,55 @@ pub struct CircuitSignal {
 impl CircuitSignal {
   pub fn new(
     id_generator: &Rc<RefCell<IdGenerator>>,
-    type_: Option<VsType>,
+    type_: VsType,
     data: CircuitSignalData,
   ) -> Self {
     CircuitSignal {
-      type_: type_.unwrap_or_else(|| typeof_(&data)),
+      type_,
       data,
       id: id_generator.borrow_mut().gen(),
       id_generator: id_generator.clone(),
     }
   }
+
+  /// A signal computed by an operation, with the type that the operation gives. Fails when the
+  /// operands of a logical operation have different types.
+  pub fn from_op(
+    id_generator: &Rc<RefCell<IdGenerator>>,
+    data: CircuitSignalData,
+  ) -> Result<Val, Val> {
+    let type_ = typeof_(&data)?;
+    Ok(CircuitSignal::new(id_generator, type_, data).to_dynamic_val())
+  }
+
+  /**
+   * Describes the signal as the expression that computes it, eg `((x + 1) * #12)`.
+   *
+   * Inputs are shown by name when they are in `input_names` (signal id -> name), otherwise like
+   * `input#0`. Subexpressions more than `depth` levels deep are shown by their signal id (eg
+   * `#12`), since expressions built by loops quickly become too large to read.
+   */
+  pub fn describe(&self, input_names: &HashMap<usize, String>, depth: usize) -> String {
+    let operand = |val: &Val| match val_dynamic_downcast::<CircuitSignal>(val) {
+      Some(signal) => signal.describe(input_names, depth.saturating_sub(1)),
+      None => val.codify(),
+    };
+
+    match &self.data {
+      CircuitSignalData::Input => match input_names.get(&self.id) {
+        Some(name) => name.clone(),
+        None => format!("input#{}", self.id),
+      },
+      _ if depth == 0 => format!("#{}", self.id),
+      CircuitSignalData::UnaryOp(op, input) => format!("{}{}", unary_op_str(*op), operand(input)),
+      CircuitSignalData::BinaryOp(op, left, right) => format!(
+        "({} {} {})",
+        operand(left),
+        binary_op_str(*op),
+        operand(right)
+      ),
+    }
+  }
 }
 
 impl ValTrait for CircuitSignal {
@@ -53,7 +97,8 @@ impl ValTrait for CircuitSignal {
   }
 
   fn to_index(&self) -> Option<usize> {
-    panic!("Not implemented: using CircuitSignal as index")
+    defer_error(CircuitError::SignalAsIndex);
+    None
   }
 
   fn is_primitive(&self) -> bool {
@@ -61,7 +106,9 @@ impl ValTrait for CircuitSignal {
   }
 
   fn is_truthy(&self) -> bool {
-    panic!("Not implemented: truthiness of CircuitSignal")
+    // Conditions in the program are handled by `truthiness`, so this is only reached by builtins
+    defer_error(CircuitError::SignalAsCondition);
+    false
   }
 
   fn is_nullish(&self) -> bool {
@@ -101,6 +148,10 @@ impl ValTrait for CircuitSignal {
   }
 
   fn override_binary_op(&self, op: BinaryOp, left: &Val, right: &Val) -> Option<Result<Val, Val>> {
+    if let Some(res) = optional_signal::override_binary_op(op, left, right) {
+      return Some(res);
+    }
+
     if left.typeof_() != VsType::Number && left.typeof_() != VsType::Bool {
       return None;
     }
@@ -152,18 +203,15 @@ impl ValTrait for CircuitSignal {
         if let Some((y, a, b)) = common_and(left, right) {
           // (y && a) || (y && b) => y && (a || b)
 
-          return Some(op_or(a, b).map(|a_or_b| {
-            CircuitSignal::new(
+          return Some(op_or(a, b).and_then(|a_or_b| {
+            CircuitSignal::from_op(
               &y.id_generator,
-              None,
               CircuitSignalData::BinaryOp(BinaryOp::And, y.clone().to_dynamic_val(), a_or_b),
             )
-            .to_dynamic_val()
           }));
         }
 
-        // TODO: (y && a) || (y && b) => y && (a || b)
-        //       (y && a) || (y && !a) => y && (a || !a) => y
+        // TODO: (y && a) || (y && !a) => y && (a || !a) => y
 
         if left.typeof_() == VsType::Bool && right.typeof_() == VsType::Bool {
           match left {
@@ -197,36 +245,38 @@ impl ValTrait for CircuitSignal {
       _ => {}
     }
 
-    Some(Ok(
-      CircuitSignal::new(
-        &self.id_generator,
-        None,
-        CircuitSignalData::BinaryOp(op, left.clone(), right.clone()),
-      )
-      .to_dynamic_val(),
+    if matches!(op, BinaryOp::And | BinaryOp::Or) && left.typeof_() != right.typeof_() {
+      return Some(Err(
+        CircuitError::IncompatibleTypes(left.typeof_(), right.typeof_()).to_val(),
+      ));
+    }
+
+    Some(CircuitSignal::from_op(
+      &self.id_generator,
+      CircuitSignalData::BinaryOp(op, left.clone(), right.clone()),
     ))
   }
 
   fn override_unary_op(&self, op: UnaryOp, input: &Val) -> Option<Result<Val, Val>> {
     if op == UnaryOp::Plus && val_dynamic_downcast::<CircuitSignal>(input).is_some() {
       return Some(Ok(
-        CircuitSignal::new(&self.id_generator, Some(VsType::Number), self.data.clone())
-          .to_dynamic_val(),
+        CircuitSignal::new(&self.id_generator, VsType::Number, self.data.clone()).to_dynamic_val(),
       ));
     }
 
-    Some(Ok(
-      CircuitSignal::new(
-        &self.id_generator,
-        None,
-        CircuitSignalData::UnaryOp(op, input.clone()),
-      )
-      .to_dynamic_val(),
+    Some(CircuitSignal::from_op(
+      &self.id_generator,
+      CircuitSignalData::UnaryOp(op, input.clone()),
     ))
   }
 
   fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "[CircuitSignal]")
+    write!(
+      f,
+      "\x1b[33m[CircuitSignal #{}: {}]\x1b[39m",
+      self.id,
+      self.describe(&HashMap::new(), 3)
+    )
   }
 
   fn codify(&self) -> String {
@@ -234,6 +284,42 @@ impl ValTrait for CircuitSignal {
   }
 }
 
+fn unary_op_str(op: UnaryOp) -> &'static str {
+  match op {
+    UnaryOp::Plus => "+",
+    UnaryOp::Minus => "-",
+    UnaryOp::Not => "!",
+    UnaryOp::BitNot => "~",
+  }
+}
+
+fn binary_op_str(op: BinaryOp) -> &'static str {
+  match op {
+    BinaryOp::Plus => "+",
+    BinaryOp::Minus => "-",
+    BinaryOp::Mul => "*",
+    BinaryOp::Div => "/",
+    BinaryOp::Mod => "%",
+    BinaryOp::Exp => "**",
+    BinaryOp::LooseEq => "==",
+    BinaryOp::LooseNe => "!=",
+    BinaryOp::Eq => "===",
+    BinaryOp::Ne => "!==",
+    BinaryOp::And => "&&",
+    BinaryOp::Or => "||",
+    BinaryOp::Less => "<",
+    BinaryOp::LessEq => "<=",
+    BinaryOp::Greater => ">",
+    BinaryOp::GreaterEq => ">=",
+    BinaryOp::BitAnd => "&",
+    BinaryOp::BitOr => "|",
+    BinaryOp::BitXor => "^",
+    BinaryOp::LeftShift => "<<",
+    BinaryOp::RightShift => ">>",
+    BinaryOp::RightShiftUnsigned => ">>>",
+  }
+}
+
 fn related_by_negation(left: &Val, right: &Val) -> bool {
   if let Some((left, right)) = both_circuit_signals(left, right) {
     if let CircuitSignalData::UnaryOp(UnaryOp::Not, not_left) = &left.data {
@@ -294,8 +380,10 @@ impl std::fmt::Display for CircuitSignal {
   }
 }
 
-fn typeof_(data: &CircuitSignalData) -> VsType {
-  match data {
+/// The type of the signal computed by `data`. Logical operations give the type of their operands,
+/// which must match.
+fn typeof_(data: &CircuitSignalData) -> Result<VsType, Val> {
+  Ok(match data {
     CircuitSignalData::Input => VsType::Number,
     CircuitSignalData::UnaryOp(op, _) => match op {
       UnaryOp::Plus => VsType::Number,
@@ -317,7 +405,7 @@ fn typeof_(data: &CircuitSignalData) -> VsType {
       BinaryOp::And | BinaryOp::Or => match (left.typeof_(), right.typeof_()) {
         (VsType::Number, VsType::Number) => VsType::Number,
         (VsType::Bool, VsType::Bool) => VsType::Bool,
-        (left, right) => panic!("Incompatible types {} {}", left, right),
+        (left, right) => return Err(CircuitError::IncompatibleTypes(left, right).to_val()),
       },
       BinaryOp::Less => VsType::Bool,
       BinaryOp::LessEq => VsType::Bool,
@@ -330,5 +418,5 @@ fn typeof_(data: &CircuitSignalData) -> VsType {
       BinaryOp::RightShift => VsType::Number,
       BinaryOp::RightShiftUnsigned => VsType::Number,
     },
-  }
+  })
 }
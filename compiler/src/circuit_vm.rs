@@ -1,31 +1,144 @@
-use std::{cmp::Ordering, collections::BinaryHeap, mem::take, rc::Rc};
+use std::{
+  cell::RefCell,
+  cmp::Ordering,
+  collections::{BTreeMap, HashMap},
+  mem::take,
+  rc::Rc,
+  time::Instant,
+};
 
 use valuescript_vm::{
   internal_error_builtin::ToInternalError,
-  operations::op_or,
-  vs_value::{ToVal, Val},
+  operations::{op_not, op_or},
+  vs_value::{ToDynamicVal, ToVal, Val},
   CallResult, FirstStackFrame, LoadFunctionResult, StackFrameTrait, ValTrait,
 };
 
 use crate::{
-  arithmetic_merge::arithmetic_merge, bytecode_stack_frame::BytecodeStackFrame,
-  circuit_vm_branch::CircuitVMBranch,
+  arithmetic_merge::{arithmetic_merge, select, MergeSite, MergeTarget},
+  branch_scheduler::SchedulingStrategy,
+  bytecode_stack_frame::BytecodeStackFrame,
+  call_sites::CallSites,
+  circuit_vm_branch::{merge_fork_sites, CircuitVMBranch, RecursionLimits},
+  compile::CompileLimits,
+  compile_error::{
+    take_deferred_error, CircuitError, Limit, LimitExceeded, Location, NonConvergingLoop,
+    SourceLocation,
+  },
+  id_generator::IdGenerator,
+  intrinsics::{Intrinsic, IntrinsicCall},
+  loop_detector::{input_dependencies, LoopDetector, DEFAULT_MAX_LOOP_FORKS},
+  oblivious_vec::ObliviousVec,
+  optional_signal::truthiness,
+  summon_log::{describe, format_log, LogRecord},
+  waiting_branches::WaitingBranches,
 };
 
 #[derive(Default)]
 pub struct CircuitVM {
   pub branch: CircuitVMBranch,
-  pub alt_branches: BinaryHeap<CircuitVMBranch>,
+  pub alt_branches: WaitingBranches,
+
+  /// Assertions from `summon.assert` that depend on signals.
+  pub assertions: Vec<Assertion>,
+
+  /// Set when a `summon.assert` is known to fail at compile time, along with an error result.
+  pub failed_assertion: Option<Assertion>,
+
+  /// Branches that ended in an uncaught exception.
+  pub errors: Vec<ErrorOutcome>,
+
+  /// The `summon.log` calls that have been reached, in the order they were run.
+  pub logs: Vec<LogRecord>,
+
+  /// Set when a thrown exception is unconditional, along with an error result. This has a single
+  /// outcome, or the outcome of every branch when they all throw.
+  pub uncaught: Vec<ErrorOutcome>,
+
+  pub recursion_limits: Rc<RecursionLimits>,
+
+  pub scheduling_strategy: SchedulingStrategy,
+
+  pub limits: CompileLimits,
+
+  /// The generator of the program's signals, for `CompileLimits::max_signals`.
+  pub id_generator: Option<Rc<RefCell<IdGenerator>>>,
+
+  /// Set when one of the `limits` is exceeded, along with an error result.
+  pub limit_exceeded: Option<LimitExceeded>,
+
+  /// Names of the input signals by id, for describing loops that don't converge and logged
+  /// signals.
+  pub input_names: HashMap<usize, String>,
+
+  /// Where the calls to the summon module are, by the ids that the calls pass.
+  pub call_sites: Rc<CallSites>,
+
+  pub loop_detector: LoopDetector,
+
+  /// Set when a loop that doesn't converge is detected, along with an error result.
+  pub non_converging_loop: Option<NonConvergingLoop>,
+
+  pub profile: Profile,
+}
+
+/// Where the VM is spending its steps, for explaining `LimitExceeded`. This is only collected when
+/// there are limits.
+#[derive(Default)]
+pub struct Profile {
+  /// The number of steps run at each bytecode position.
+  steps: HashMap<usize, usize>,
+
+  /// The names of the functions that have been run, by the positions where they start.
+  fn_names: BTreeMap<usize, String>,
+}
+
+impl Profile {
+  fn location(&self, pos: usize) -> Location {
+    // Functions are contiguous in the bytecode, so this is the last one that starts before `pos`
+    let fn_name = match self.fn_names.range(..=pos).next_back() {
+      Some((_, fn_name)) => fn_name.clone(),
+      None => "<unknown>".to_string(),
+    };
+
+    Location::new(fn_name, pos)
+  }
+
+  /// The locations with the highest counts, most first.
+  fn top_locations(&self, counts: HashMap<usize, usize>) -> Vec<(Location, usize)> {
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|(pos, count)| (std::cmp::Reverse(*count), *pos));
+
+    counts
+      .into_iter()
+      .take(5)
+      .map(|(pos, count)| (self.location(pos), count))
+      .collect()
+  }
+}
+
+pub struct ErrorOutcome {
+  /// True when this error occurs.
+  pub flag: Val,
+
+  /// The instruction that threw (or the function, for recursion limits).
+  pub site: Location,
+
+  pub exception: Val,
+}
+
+pub struct Assertion {
+  /// True when the assertion holds or its call was not reached.
+  pub ok: Val,
+
+  pub message: String,
+
+  /// Where `summon.assert` was called, if known.
+  pub call_site: Option<SourceLocation>,
 }
 
 impl CircuitVM {
-  pub fn run(
-    &mut self,
-    step_limit: Option<usize>,
-    this: &mut Val,
-    fn_: Val,
-    args: Vec<Val>,
-  ) -> Result<Val, Val> {
+  pub fn run(&mut self, this: &mut Val, fn_: Val, args: Vec<Val>) -> Result<Val, Val> {
     let mut frame = match fn_.load_function() {
       LoadFunctionResult::StackFrame(f) => f,
       _ => return Err("fn_ is not a function".to_internal_error()),
@@ -41,38 +154,31 @@ impl CircuitVM {
       flag: true.to_val(),
       frame: Rc::new(frame),
       stack: vec![Rc::new(Box::new(FirstStackFrame::new()))],
-      alt_branch: None,
+      alt_branches: vec![],
+      fork_sites: vec![],
+      new_fork: None,
+      recursion_limits: self.recursion_limits.clone(),
+      scheduler: self.scheduling_strategy.scheduler(),
     };
 
-    let res = match step_limit {
-      Some(step_limit) => 'b: {
-        let mut step_count = 0;
+    // Discard any error left over from an earlier run that failed
+    take_deferred_error();
 
-        while step_count < step_limit {
-          self.step()?;
-          step_count += 1;
+    let start_time = Instant::now();
+    let mut step_count = 0;
 
-          if self.branch.stack.is_empty() {
-            assert!(self.alt_branches.is_empty());
-            break 'b self.branch.frame_mut().get_call_result();
-          }
-        }
+    while !self.branch.stack.is_empty() {
+      self.record_step();
+      self.step()?;
+      step_count += 1;
 
-        return Err("step limit reached".to_internal_error());
-      }
-      None => {
-        while !self.branch.stack.is_empty() {
-          self.step()?;
-        }
-
-        self.branch.frame_mut().get_call_result()
-      }
-    };
+      self.check_limits(step_count, start_time)?;
+    }
 
     let CallResult {
       return_,
       this: updated_this,
-    } = res;
+    } = self.branch.frame_mut().get_call_result();
 
     *this = updated_this;
 
@@ -80,129 +186,494 @@ impl CircuitVM {
   }
 
   pub fn step(&mut self) -> Result<(), Val> {
-    self.assert_current_branch_best();
-    assert!(self.branch.alt_branch.is_none());
+    self.check_branches()?;
 
-    self.branch.step()?;
+    let site = self.branch.throw_site();
+    let mut res = self.branch.step();
 
-    if let Some(alt_branch) = take(&mut self.branch.alt_branch) {
-      self.alt_branches.push(*alt_branch);
+    if let Some(err) = take_deferred_error() {
+      res = Err(err.to_val());
     }
 
-    loop {
-      if let Some(alt_branch) = self.alt_branches.peek() {
-        match self.branch.cmp(alt_branch) {
-          Ordering::Less => {
-            // Since the current branch is a lower priority than the best alt branch, adopt the best
-            // alt branch.
-            let alt_branch = self.alt_branches.pop().unwrap();
-            self.set_branch(alt_branch);
+    if let Err(exception) = res {
+      self.end_branch_with_error(exception, site)?;
+    }
 
-            continue;
-          }
-          Ordering::Equal => {
-            if let Some(current_frame) =
-              self.branch.frame.as_any().downcast_ref::<FirstStackFrame>()
-            {
-              let alt_frame = alt_branch
-                .frame
-                .as_any()
-                .downcast_ref::<FirstStackFrame>()
-                .expect("Should be first stack frame since the branches are equal");
-
-              let mut new_frame = FirstStackFrame::new();
-
-              new_frame.apply_call_result(CallResult {
-                return_: arithmetic_merge(
-                  &self.branch.flag,
-                  &current_frame.call_result.return_,
-                  &alt_branch.flag,
-                  &alt_frame.call_result.return_,
-                ),
-                this: arithmetic_merge(
-                  &self.branch.flag,
-                  &current_frame.call_result.this,
-                  &alt_branch.flag,
-                  &alt_frame.call_result.this,
-                ),
-              });
-
-              let mut new_frame = Rc::new(Box::new(new_frame) as Box<dyn StackFrameTrait>);
-
-              std::mem::swap(&mut self.branch.frame, &mut new_frame);
-              self.branch.flag = true.to_val();
-
-              self.alt_branches.pop();
-
-              continue;
-            }
-
-            if let (Some(current_frame), Some(alt_frame)) = (
-              Rc::make_mut(&mut self.branch.frame)
-                .as_any_mut()
-                .downcast_mut::<BytecodeStackFrame>(),
-              alt_branch
-                .frame
-                .as_any()
-                .downcast_ref::<BytecodeStackFrame>(),
-            ) {
-              assert!(current_frame.can_merge(alt_frame));
-
-              assert!(self.branch.stack.len() == alt_branch.stack.len());
-
-              // Because of the way we prefer deeper stacks, step once at a time, and a step cannot
-              // simultaneously pop and push frame(s), it should not be possible to have unequal stack
-              // traces here.
-              for i in 0..self.branch.stack.len() {
-                assert!(std::ptr::eq(
-                  self.branch.stack[i].as_ref(),
-                  alt_branch.stack[i].as_ref()
-                ));
-              }
-
-              let mut new_registers = Vec::<Val>::new();
-
-              assert!(current_frame.registers.len() == alt_frame.registers.len());
-
-              for i in 0..current_frame.registers.len() {
-                new_registers.push(arithmetic_merge(
-                  &self.branch.flag,
-                  &current_frame.registers[i],
-                  &alt_branch.flag,
-                  &alt_frame.registers[i],
-                ));
-              }
-
-              current_frame.registers = new_registers;
-              self.branch.flag = op_or(&self.branch.flag, &alt_branch.flag).unwrap();
-
-              self.alt_branches.pop();
-
-              continue;
-            }
+    if let Some(intrinsic_call) = self.branch.take_intrinsic_call() {
+      self.handle_intrinsic_call(*intrinsic_call)?;
+    }
 
+    if let Some((location, cond)) = take(&mut self.branch.new_fork) {
+      self.detect_non_converging_loop(location, &cond)?;
+    }
+
+    for alt_branch in take(&mut self.branch.alt_branches) {
+      self
+        .alt_branches
+        .push_merging(alt_branch, &mut self.loop_detector)?;
+    }
+
+    while let Some(alt_branch) = self.alt_branches.peek() {
+      match self.branch.cmp(alt_branch) {
+        Ordering::Less => {
+          // Since the current branch is a lower priority than the best alt branch, adopt the best
+          // alt branch.
+          let alt_branch = self.pop_alt_branch()?;
+          self.set_branch(alt_branch)?;
+        }
+        Ordering::Equal => {
+          let alt_branch = self.pop_alt_branch()?;
+
+          if !merge_branches(&mut self.branch, &alt_branch, &mut self.loop_detector)? {
+            self.alt_branches.push(alt_branch);
             break;
           }
-          Ordering::Greater => {
-            break;
-          }
+        }
+        Ordering::Greater => {
+          break;
         }
       }
+    }
 
-      break;
+    Ok(())
+  }
+
+  /// Records an uncaught exception as an error outcome and moves on to the next branch. If the
+  /// exception is unconditional, or wasn't thrown by a `throw` statement (eg a type error or a
+  /// `CircuitError`), it is returned instead. Exceeding a recursion limit is treated like a `throw`
+  /// in the recursive function.
+  ///
+  /// When there are no other branches, every branch has thrown, so the exceptions are unconditional
+  /// together, and they are all recorded in `uncaught`.
+  fn end_branch_with_error(&mut self, exception: Val, site: Option<Location>) -> Result<(), Val> {
+    let is_circuit_error = CircuitError::from_val(&exception).is_some();
+
+    let site = match CircuitError::from_val(&exception) {
+      Some(CircuitError::RecursionLimit(failure)) => {
+        Some(Location::new(failure.fn_name.clone(), failure.fn_pos))
+      }
+      _ => site,
+    };
+
+    let site = match site {
+      Some(site) => site,
+      None => return Err(exception),
+    };
+
+    let outcome = ErrorOutcome {
+      flag: self.branch.flag.clone(),
+      site,
+      exception: exception.clone(),
+    };
+
+    if matches!(self.branch.flag, Val::Bool(true)) {
+      if !is_circuit_error {
+        self.uncaught = vec![outcome];
+      }
+
+      return Err(exception);
+    }
+
+    let next_branch = match self.alt_branches.pop(&mut self.loop_detector)? {
+      Some(next_branch) => next_branch,
+      None => {
+        self.errors.push(outcome);
+        self.uncaught = take(&mut self.errors);
+
+        return Err(exception);
+      }
+    };
+
+    self.branch = next_branch;
+    self.errors.push(outcome);
+
+    Ok(())
+  }
+
+  fn handle_intrinsic_call(&mut self, call: IntrinsicCall) -> Result<(), Val> {
+    let res = match call.intrinsic {
+      Intrinsic::Assert => self.assert(&call.args, call.call_site)?,
+      Intrinsic::Vec => ObliviousVec::new(&call.args)?.to_dynamic_val(),
+      Intrinsic::Select => select_intrinsic(&call.args)?,
+      Intrinsic::Log => self.log(&call.args, call.pos, call.call_site),
+    };
+
+    if let Some(return_target) = call.return_target {
+      self.branch.write_register(return_target, res);
     }
 
     Ok(())
   }
 
-  fn set_branch(&mut self, mut new_branch: CircuitVMBranch) {
+  fn assert(&mut self, args: &[Val], call_site: Option<usize>) -> Result<Val, Val> {
+    let cond = args.first().cloned().unwrap_or(Val::Undefined);
+
+    let message = match args.get(1) {
+      None | Some(Val::Undefined) => "Assertion failed".to_string(),
+      Some(message) => message.to_string(),
+    };
+
+    let call_site = call_site.and_then(|id| self.call_sites.get(id).cloned());
+
+    let cond = match truthiness(&cond)? {
+      Val::Bool(true) => return Ok(Val::Undefined),
+      Val::Bool(false) => {
+        if matches!(self.branch.flag, Val::Bool(true)) {
+          let error = format!("Assertion failed: {}", message).to_val();
+
+          self.failed_assertion = Some(Assertion {
+            ok: false.to_val(),
+            message,
+            call_site,
+          });
+
+          return Err(error);
+        }
+
+        // Only fails when this branch is taken
+        false.to_val()
+      }
+      cond => cond,
+    };
+
+    let not_reached = op_not(&self.branch.flag)?;
+
+    self.assertions.push(Assertion {
+      ok: op_or(&not_reached, &cond)?,
+      message,
+      call_site,
+    });
+
+    Ok(Val::Undefined)
+  }
+
+  /// `summon.log(...values)`. Records the values along with where they were logged, and the
+  /// condition for reaching the call when it depends on signals.
+  fn log(&mut self, args: &[Val], pos: usize, call_site: Option<usize>) -> Val {
+    let fn_name = match self.branch.bytecode_frame() {
+      Some(frame) => frame.fn_name(),
+      None => "<unknown>".to_string(),
+    };
+
+    let condition = match self.branch.flag {
+      Val::Bool(true) => None,
+      ref flag => Some(describe(flag, &self.input_names)),
+    };
+
+    self.logs.push(LogRecord {
+      call_site: call_site.and_then(|id| self.call_sites.get(id).cloned()),
+      location: Location::new(fn_name, pos),
+      message: format_log(args, &self.input_names),
+      condition,
+    });
+
+    Val::Undefined
+  }
+
+  fn record_step(&mut self) {
+    if self.limits.is_unlimited() {
+      return;
+    }
+
+    if let Some(frame) = self.branch.bytecode_frame() {
+      *self.profile.steps.entry(frame.decoder.pos).or_default() += 1;
+
+      self
+        .profile
+        .fn_names
+        .entry(frame.fn_start)
+        .or_insert_with(|| frame.fn_name());
+    }
+  }
+
+  fn check_limits(&mut self, step_count: usize, start_time: Instant) -> Result<(), Val> {
+    let limits = &self.limits;
+    let branch_count = self.alt_branches.len() + 1;
+
+    let signal_count = match &self.id_generator {
+      Some(id_generator) => id_generator.borrow().count(),
+      None => 0,
+    };
+
+    let limit = [
+      limits
+        .max_steps
+        .filter(|max| step_count > *max)
+        .map(Limit::Steps),
+      limits
+        .max_branches
+        .filter(|max| branch_count > *max)
+        .map(Limit::Branches),
+      limits
+        .max_signals
+        .filter(|max| signal_count > *max)
+        .map(Limit::Signals),
+      // Checking the time is relatively slow, so it's only checked occasionally
+      limits
+        .timeout
+        .filter(|timeout| step_count.is_multiple_of(1024) && start_time.elapsed() > *timeout)
+        .map(Limit::Time),
+    ]
+    .into_iter()
+    .flatten()
+    .next();
+
+    let limit = match limit {
+      Some(limit) => limit,
+      None => return Ok(()),
+    };
+
+    let mut fork_sites = HashMap::<usize, usize>::new();
+
+    for branch in std::iter::once(&self.branch).chain(self.alt_branches.iter()) {
+      if let Some(fork_site) = branch.fork_sites.last() {
+        *fork_sites.entry(*fork_site).or_default() += 1;
+      }
+    }
+
+    let steps = take(&mut self.profile.steps);
+
+    let limit_exceeded = LimitExceeded {
+      limit,
+      hot_spots: self.profile.top_locations(steps),
+      fork_sites: self.profile.top_locations(fork_sites),
+    };
+
+    let message = limit_exceeded.to_string();
+    self.limit_exceeded = Some(limit_exceeded);
+
+    Err(message.to_internal_error())
+  }
+
+  /// Fails when the fork at `location` has repeated too many times without converging (see
+  /// `LoopDetector`).
+  fn detect_non_converging_loop(&mut self, location: Location, cond: &Val) -> Result<(), Val> {
+    let branch_count = 1 + self.branch.alt_branches.len() + self.alt_branches.len();
+    let forks = self.loop_detector.fork(location.pos, branch_count);
+
+    let max_forks = self.limits.max_loop_forks.unwrap_or(DEFAULT_MAX_LOOP_FORKS);
+
+    if forks <= max_forks {
+      return Ok(());
+    }
+
+    let non_converging_loop = NonConvergingLoop {
+      location,
+      inputs: input_dependencies(cond)
+        .iter()
+        .filter_map(|id| self.input_names.get(id).cloned())
+        .collect(),
+      forks,
+    };
+
+    let message = non_converging_loop.to_string();
+    self.non_converging_loop = Some(non_converging_loop);
+
+    Err(message.to_internal_error())
+  }
+
+  /// Pops the best alt branch, which `peek` has shown exists.
+  fn pop_alt_branch(&mut self) -> Result<CircuitVMBranch, Val> {
+    match self.alt_branches.pop(&mut self.loop_detector)? {
+      Some(alt_branch) => Ok(alt_branch),
+      None => Err(CircuitError::Internal("Missing alt branch".to_string()).to_val()),
+    }
+  }
+
+  /// Runs `new_branch` next, and makes the current branch wait (merging it with a waiting branch
+  /// at the same location if the scheduler merges eagerly).
+  fn set_branch(&mut self, mut new_branch: CircuitVMBranch) -> Result<(), Val> {
     std::mem::swap(&mut self.branch, &mut new_branch);
-    self.alt_branches.push(new_branch);
+
+    self
+      .alt_branches
+      .push_merging(new_branch, &mut self.loop_detector)
   }
 
-  fn assert_current_branch_best(&self) {
-    if let Some(alt_branch) = self.alt_branches.peek() {
-      assert!(&self.branch > alt_branch);
+  /// Checks that the current branch is the one that should run next, and that its forks have been
+  /// moved to `alt_branches`.
+  fn check_branches(&self) -> Result<(), Val> {
+    // Branches at the same location that can't be merged compare `Equal`
+    let is_best = match self.alt_branches.peek() {
+      Some(alt_branch) => &self.branch >= alt_branch,
+      None => true,
+    };
+
+    if !is_best || !self.branch.alt_branches.is_empty() {
+      return Err(CircuitError::Internal("Ran a branch out of order".to_string()).to_val());
     }
+
+    Ok(())
   }
 }
+
+/// Merges `other` into `branch`, returning false if they can't be merged. The branches must be at
+/// the same location (they compare `Equal`).
+pub fn merge_branches(
+  branch: &mut CircuitVMBranch,
+  other: &CircuitVMBranch,
+  loop_detector: &mut LoopDetector,
+) -> Result<bool, Val> {
+  if let Some(current_frame) = branch.frame.as_any().downcast_ref::<FirstStackFrame>() {
+    // Branches that have returned are only equal to other branches that have returned
+    let alt_frame = match other.frame.as_any().downcast_ref::<FirstStackFrame>() {
+      Some(alt_frame) => alt_frame,
+      None => return Ok(false),
+    };
+
+    let mut new_frame = FirstStackFrame::new();
+    let fork_pos = merge_fork_sites(&mut branch.fork_sites, &other.fork_sites);
+
+    if let Some(fork_pos) = fork_pos {
+      loop_detector.merge(fork_pos);
+    }
+
+    let site = |target| MergeSite {
+      target,
+      fn_name: None,
+      fork_pos,
+      merge_pos: None,
+    };
+
+    new_frame.apply_call_result(CallResult {
+      return_: arithmetic_merge(
+        &site(MergeTarget::ReturnValue),
+        &branch.flag,
+        &current_frame.call_result.return_,
+        &other.flag,
+        &alt_frame.call_result.return_,
+      )?,
+      this: arithmetic_merge(
+        &site(MergeTarget::This),
+        &branch.flag,
+        &current_frame.call_result.this,
+        &other.flag,
+        &alt_frame.call_result.this,
+      )?,
+    });
+
+    let mut new_frame = Rc::new(Box::new(new_frame) as Box<dyn StackFrameTrait>);
+
+    std::mem::swap(&mut branch.frame, &mut new_frame);
+    branch.flag = true.to_val();
+
+    return Ok(true);
+  }
+
+  // Frames inside generators are merged the same way (see `bytecode_frame`)
+  if let Some(alt_frame) = other.bytecode_frame() {
+    let frame = match branch.bytecode_frame() {
+      Some(frame) => frame,
+      None => return Ok(false),
+    };
+
+    if branch.stack.len() != other.stack.len() {
+      return Ok(false);
+    }
+
+    // Branches at the same location usually share their callers, since deeper stacks are run
+    // first. They can also reach the same continuation through different calls though, in which
+    // case their callers are merged frame by frame.
+    let divergent_callers = (0..branch.stack.len())
+      .filter(|i| !Rc::ptr_eq(&branch.stack[*i], &other.stack[*i]))
+      .collect::<Vec<_>>();
+
+    let callers_can_merge = divergent_callers.iter().all(|i| {
+      match (
+        branch.stack[*i]
+          .as_any()
+          .downcast_ref::<BytecodeStackFrame>(),
+        other.stack[*i]
+          .as_any()
+          .downcast_ref::<BytecodeStackFrame>(),
+      ) {
+        (Some(caller), Some(alt_caller)) => caller.can_merge(alt_caller),
+        _ => false,
+      }
+    });
+
+    if !callers_can_merge || !frame.can_merge(alt_frame) {
+      return Ok(false);
+    }
+
+    let fork_pos = merge_fork_sites(&mut branch.fork_sites, &other.fork_sites);
+
+    if let Some(fork_pos) = fork_pos {
+      loop_detector.merge(fork_pos);
+    }
+
+    let branch_flag = branch.flag.clone();
+
+    // `callers_can_merge` checked that the divergent callers are bytecode frames
+    for i in divergent_callers {
+      let caller = Rc::make_mut(&mut branch.stack[i])
+        .as_any_mut()
+        .downcast_mut::<BytecodeStackFrame>();
+
+      let alt_caller = other.stack[i].as_any().downcast_ref::<BytecodeStackFrame>();
+
+      if let (Some(caller), Some(alt_caller)) = (caller, alt_caller) {
+        caller.registers =
+          merge_registers(fork_pos, &branch_flag, caller, &other.flag, alt_caller)?;
+      }
+    }
+
+    if let Some(current_frame) = branch.bytecode_frame_mut() {
+      current_frame.registers = merge_registers(
+        fork_pos,
+        &branch_flag,
+        current_frame,
+        &other.flag,
+        alt_frame,
+      )?;
+    }
+
+    branch.flag = op_or(&branch_flag, &other.flag)?;
+
+    return Ok(true);
+  }
+
+  Ok(false)
+}
+
+/// Merges the registers of two frames that can be merged (see `BytecodeStackFrame::can_merge`).
+fn merge_registers(
+  fork_pos: Option<usize>,
+  flag: &Val,
+  frame: &BytecodeStackFrame,
+  alt_flag: &Val,
+  alt_frame: &BytecodeStackFrame,
+) -> Result<Vec<Val>, Val> {
+  let merge_pos = Some(frame.decoder.pos);
+  let fn_name = Some(Rc::<str>::from(frame.fn_name()));
+
+  frame
+    .registers
+    .iter()
+    .zip(&alt_frame.registers)
+    .enumerate()
+    .map(|(i, (register, alt_register))| {
+      arithmetic_merge(
+        &MergeSite {
+          target: MergeTarget::Register(i),
+          fn_name: fn_name.clone(),
+          fork_pos,
+          merge_pos,
+        },
+        flag,
+        register,
+        alt_flag,
+        alt_register,
+      )
+    })
+    .collect()
+}
+
+/// `summon.select(cond, a, b)`. Unlike `cond ? a : b`, both `a` and `b` are always evaluated, and
+/// the VM never forks.
+fn select_intrinsic(args: &[Val]) -> Result<Val, Val> {
+  let arg = |i: usize| args.get(i).cloned().unwrap_or(Val::Undefined);
+  let (cond, a, b) = (arg(0), arg(1), arg(2));
+
+  select(&truthiness(&cond)?, &a, &b)
+}
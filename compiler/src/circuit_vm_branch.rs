@@ -1,21 +1,68 @@
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::mem::take;
 use std::rc::Rc;
 
+use valuescript_common::InstructionByte;
 use valuescript_vm::internal_error_builtin::ToInternalError;
-use valuescript_vm::operations::op_and;
-use valuescript_vm::vs_value::{ToVal, Val};
+use valuescript_vm::operations::{op_and, op_not};
+use valuescript_vm::vs_value::{ToDynamicVal, ToVal, Val};
 use valuescript_vm::{FirstStackFrame, FrameStepOk, StackFrame};
 
+use crate::array_methods::ArrayMethodFrame;
+use crate::branch_scheduler::{BranchScheduler, SchedulingStrategy};
 use crate::bytecode_decoder::BytecodeType;
-use crate::bytecode_stack_frame::BytecodeStackFrame;
+use crate::bytecode_stack_frame::{BytecodeStackFrame, ForkInfo};
+use crate::compile_error::{CircuitError, Location, RecursionLimitFailure};
+use crate::generator::{Generator, GeneratorFrame};
+use crate::intrinsics::IntrinsicCall;
 
 #[derive(Clone)]
 pub struct CircuitVMBranch {
   pub flag: Val,
   pub frame: Rc<StackFrame>,
   pub stack: Vec<Rc<StackFrame>>,
-  pub alt_branch: Option<Box<CircuitVMBranch>>,
+  pub alt_branches: Vec<CircuitVMBranch>,
+
+  /// Positions of the forks that created this branch and haven't been merged yet, innermost last.
+  pub fork_sites: Vec<usize>,
+
+  /// The fork made by the last step if it was at a loop condition, along with the condition of this
+  /// branch, for detecting loops that don't converge.
+  pub new_fork: Option<(Location, Val)>,
+
+  pub recursion_limits: Rc<RecursionLimits>,
+
+  /// Chooses between branches at the same depth (see `Ord`).
+  pub scheduler: Rc<dyn BranchScheduler>,
+}
+
+/**
+ * Bounds on how many frames of each function can be on the stack. Without a bound, a recursive
+ * function whose base case depends on a signal recurses forever, since every call forks into a
+ * branch that makes another call.
+ *
+ * Branches that exceed a bound end with a `CircuitError::RecursionLimit`, which becomes an error
+ * outcome when the branch depends on signals (so the circuit gets an `error` output), and a
+ * compile error otherwise.
+ */
+#[derive(Default, Debug, Clone)]
+pub struct RecursionLimits {
+  /// The bound for functions without their own bound (from `--max-recursion-depth`).
+  pub default: Option<usize>,
+
+  /// Bounds from `@maxDepth` tags (function start -> max depth).
+  pub by_fn_start: HashMap<usize, usize>,
+}
+
+impl RecursionLimits {
+  fn max_depth(&self, frame: &BytecodeStackFrame) -> Option<usize> {
+    self
+      .by_fn_start
+      .get(&frame.fn_start)
+      .copied()
+      .or(self.default)
+  }
 }
 
 impl Default for CircuitVMBranch {
@@ -24,7 +71,11 @@ impl Default for CircuitVMBranch {
       flag: true.to_val(),
       frame: Rc::new(Box::new(FirstStackFrame::new())),
       stack: Default::default(),
-      alt_branch: None,
+      alt_branches: vec![],
+      fork_sites: vec![],
+      new_fork: None,
+      recursion_limits: Default::default(),
+      scheduler: SchedulingStrategy::default().scheduler(),
     }
   }
 }
@@ -34,6 +85,39 @@ impl CircuitVMBranch {
     Rc::make_mut(&mut self.frame)
   }
 
+  /// The bytecode frame being run, looking through a generator being advanced by `next`.
+  pub fn bytecode_frame(&self) -> Option<&BytecodeStackFrame> {
+    let frame = self.frame.as_any();
+
+    match frame.downcast_ref::<GeneratorFrame>() {
+      Some(generator_frame) => generator_frame.bytecode_frame(),
+      None => frame.downcast_ref::<BytecodeStackFrame>(),
+    }
+  }
+
+  pub fn bytecode_frame_mut(&mut self) -> Option<&mut BytecodeStackFrame> {
+    if self.frame.as_any().is::<GeneratorFrame>() {
+      return self
+        .frame_mut()
+        .as_any_mut()
+        .downcast_mut::<GeneratorFrame>()
+        .and_then(|generator_frame| generator_frame.bytecode_frame_mut());
+    }
+
+    self
+      .frame_mut()
+      .as_any_mut()
+      .downcast_mut::<BytecodeStackFrame>()
+  }
+
+  /// The number of frames inside the generator being advanced, if any.
+  fn generator_depth(&self) -> usize {
+    match self.frame.as_any().downcast_ref::<GeneratorFrame>() {
+      Some(generator_frame) => generator_frame.depth(),
+      None => 0,
+    }
+  }
+
   pub fn step(&mut self) -> Result<(), Val> {
     let step_ok = match self.frame_mut().step() {
       Ok(step_ok) => step_ok,
@@ -41,29 +125,16 @@ impl CircuitVMBranch {
     };
 
     match step_ok {
-      FrameStepOk::Continue => {
-        if let Some(frame) = self
-          .frame_mut()
-          .as_any_mut()
-          .downcast_mut::<BytecodeStackFrame>()
-        {
-          if let Some(fork_info) = take(&mut frame.fork_info) {
-            let mut alt_branch = self.clone();
-
-            self.flag = op_and(&self.flag, &fork_info.flag).unwrap();
-            alt_branch.flag = op_and(&alt_branch.flag, &fork_info.alt_flag).unwrap();
-            alt_branch.frame = Rc::new(Box::new(fork_info.alt_frame));
-
-            self.alt_branch = Some(Box::new(alt_branch));
-          }
-        }
-      }
+      FrameStepOk::Continue => self.fork()?,
       FrameStepOk::Pop(call_result) => {
         self.pop();
         self.frame_mut().apply_call_result(call_result);
       }
       FrameStepOk::Push(new_frame) => {
-        self.push(Rc::new(new_frame));
+        // Calling a `FunctionSet` forks before pushing the first candidate's frame
+        self.fork()?;
+        self.fork_array_method()?;
+        self.push(Rc::new(new_frame))?;
       }
       // TODO: Internal errors
       FrameStepOk::Yield(_) => {
@@ -77,9 +148,174 @@ impl CircuitVMBranch {
     Ok(())
   }
 
-  pub fn push(&mut self, mut frame: Rc<StackFrame>) {
+  /// Splits off `alt_branches` if the frame requested a fork.
+  fn fork(&mut self) -> Result<(), Val> {
+    let (fork_info, fn_name) = match self.bytecode_frame_mut() {
+      Some(frame) => match take(&mut frame.fork_info) {
+        Some(fork_info) => (fork_info, frame.fn_name()),
+        None => return Ok(()),
+      },
+      None => return Ok(()),
+    };
+
+    let ForkInfo {
+      pos,
+      flag,
+      alts,
+      is_loop_condition,
+    } = *fork_info;
+
+    for alt in alts {
+      let mut alt_branch = self.clone();
+
+      let alt_frame: StackFrame = match self.frame.as_any().downcast_ref::<GeneratorFrame>() {
+        Some(generator_frame) => Box::new(generator_frame.with_frame(Box::new(alt.frame))),
+        None => Box::new(alt.frame),
+      };
+
+      alt_branch.flag = op_and(&alt_branch.flag, &alt.flag)?;
+      alt_branch.frame = Rc::new(alt_frame);
+      alt_branch.fork_sites.push(pos);
+
+      self.alt_branches.push(alt_branch);
+    }
+
+    self.flag = op_and(&self.flag, &flag)?;
+    self.fork_sites.push(pos);
+
+    if is_loop_condition {
+      self.new_fork = Some((Location::new(fn_name, pos), flag));
+    }
+
+    Ok(())
+  }
+
+  /// Splits off a branch that skips the predicate call being made by `find` or `findIndex`, if
+  /// the call depends on a signal (see `ArrayMethodFrame::fork_flag`).
+  fn fork_array_method(&mut self) -> Result<(), Val> {
+    let flag = match self
+      .frame
+      .as_any()
+      .downcast_ref::<ArrayMethodFrame>()
+      .and_then(|frame| frame.fork_flag.as_ref())
+    {
+      Some(_) => self
+        .frame_mut()
+        .as_any_mut()
+        .downcast_mut::<ArrayMethodFrame>()
+        .and_then(|frame| take(&mut frame.fork_flag)),
+      None => None,
+    };
+
+    let flag = match flag {
+      Some(flag) => flag,
+      None => return Ok(()),
+    };
+
+    // The call to `find` or `findIndex`
+    let pos = self
+      .stack
+      .last()
+      .and_then(|caller| caller.as_any().downcast_ref::<BytecodeStackFrame>())
+      .map_or(0, |caller| caller.decoder.pos);
+
+    let mut alt_branch = self.clone();
+
+    if let Some(frame) = alt_branch
+      .frame_mut()
+      .as_any_mut()
+      .downcast_mut::<ArrayMethodFrame>()
+    {
+      frame.skip_remaining();
+    }
+
+    alt_branch.flag = op_and(&alt_branch.flag, &op_not(&flag)?)?;
+    alt_branch.fork_sites.push(pos);
+    self.alt_branches.push(alt_branch);
+
+    self.flag = op_and(&self.flag, &flag)?;
+    self.fork_sites.push(pos);
+
+    Ok(())
+  }
+
+  /// The location of the next instruction, if it is a `throw` in a bytecode frame.
+  pub fn throw_site(&self) -> Option<Location> {
+    let frame = self.bytecode_frame()?;
+
+    if frame.decoder.peek_type() == BytecodeType::End {
+      return None;
+    }
+
+    match frame
+      .decoder
+      .clone_at(frame.decoder.pos)
+      .decode_instruction()
+    {
+      InstructionByte::Throw => Some(Location::new(frame.fn_name(), frame.decoder.pos)),
+      _ => None,
+    }
+  }
+
+  pub fn take_intrinsic_call(&mut self) -> Option<Box<IntrinsicCall>> {
+    self.bytecode_frame()?.intrinsic_call.as_ref()?;
+
+    self
+      .bytecode_frame_mut()
+      .and_then(|frame| take(&mut frame.intrinsic_call))
+  }
+
+  pub fn write_register(&mut self, register_index: usize, val: Val) {
+    if let Some(frame) = self.bytecode_frame_mut() {
+      frame.registers[register_index] = val;
+    }
+  }
+
+  /// Pushes `frame`, failing if this exceeds the recursion limit of its function.
+  pub fn push(&mut self, mut frame: Rc<StackFrame>) -> Result<(), Val> {
+    if let Some(new_frame) = frame.as_any().downcast_ref::<BytecodeStackFrame>() {
+      self.check_recursion_depth(new_frame)?;
+    }
+
     std::mem::swap(&mut self.frame, &mut frame);
     self.stack.push(frame);
+
+    Ok(())
+  }
+
+  fn check_recursion_depth(&self, new_frame: &BytecodeStackFrame) -> Result<(), Val> {
+    let max_depth = match self.recursion_limits.max_depth(new_frame) {
+      Some(max_depth) => max_depth,
+      None => return Ok(()),
+    };
+
+    let frames = self
+      .stack
+      .iter()
+      .chain(std::iter::once(&self.frame))
+      .filter_map(|frame| frame.as_any().downcast_ref::<BytecodeStackFrame>())
+      .chain(std::iter::once(new_frame))
+      .collect::<Vec<_>>();
+
+    let depth = frames
+      .iter()
+      .filter(|frame| frame.fn_start == new_frame.fn_start)
+      .count();
+
+    if depth <= max_depth {
+      return Ok(());
+    }
+
+    Err(
+      CircuitError::RecursionLimit(RecursionLimitFailure {
+        fn_name: new_frame.fn_name(),
+        max_depth,
+        call_chain: frames.iter().map(|frame| frame.fn_name()).collect(),
+        fn_pos: new_frame.fn_start,
+        location: None,
+      })
+      .to_val(),
+    )
   }
 
   pub fn pop(&mut self) {
@@ -89,6 +325,11 @@ impl CircuitVMBranch {
   }
 
   pub fn handle_exception(&mut self, mut exception: Val) -> Result<(), Val> {
+    if CircuitError::from_val(&exception).is_some() {
+      // The program can't be compiled, so the program shouldn't be able to catch this
+      return Err(exception);
+    }
+
     while !self.stack.is_empty() {
       if self.frame.can_catch_exception(&exception) {
         self.frame_mut().catch_exception(&mut exception);
@@ -99,38 +340,106 @@ impl CircuitVMBranch {
         return Err(exception);
       }
 
+      let generator_threw = self.frame.as_any().is::<GeneratorFrame>();
       self.pop();
+
+      if generator_threw {
+        // The generator can't be resumed after throwing, so later calls to `next` give
+        // `done: true` (the caller's copy of the generator was taken when `next` was called)
+        if let Some(frame) = self.bytecode_frame_mut() {
+          if let Some(this_target) = take(&mut frame.this_target) {
+            frame.registers[this_target] = Generator::finished().to_dynamic_val();
+          }
+        }
+      }
     }
 
     Err(exception)
   }
 }
 
+impl CircuitVMBranch {
+  /// Identifies where the branch is, so that branches compare `Equal` when they have the same key,
+  /// as long as the scheduler only compares positions. This is `None` for branches that aren't
+  /// running bytecode.
+  pub fn location_key(&self) -> Option<Vec<usize>> {
+    let frame = self.bytecode_frame()?;
+
+    // Branches at the end of a function are compared by the function instead of the position
+    let (is_end, pos) = match frame.decoder.peek_type() {
+      BytecodeType::End => (1, frame.fn_start),
+      _ => (0, frame.decoder.pos),
+    };
+
+    let mut key = vec![self.stack.len(), self.generator_depth(), is_end, pos];
+
+    key.extend(
+      self
+        .stack
+        .iter()
+        .map(|caller| resume_pos(caller).unwrap_or(usize::MAX)),
+    );
+
+    Some(key)
+  }
+
+  /// Compares the positions that two branches at the same location will return to. Callers at
+  /// the same positions compare `Equal` even if they aren't shared, since they can be merged frame
+  /// by frame.
+  fn cmp_callers(&self, other: &Self) -> Ordering {
+    for (caller, other_caller) in self.stack.iter().zip(&other.stack) {
+      if Rc::ptr_eq(caller, other_caller) {
+        continue;
+      }
+
+      // Earlier positions first, reversed for the max-heap
+      let ordering = resume_pos(other_caller).cmp(&resume_pos(caller));
+
+      if ordering != Ordering::Equal {
+        return ordering;
+      }
+    }
+
+    Ordering::Equal
+  }
+}
+
+/// Where a caller continues when the function it called returns.
+fn resume_pos(frame: &Rc<StackFrame>) -> Option<usize> {
+  frame
+    .as_any()
+    .downcast_ref::<BytecodeStackFrame>()
+    .map(|frame| frame.decoder.pos)
+}
+
 impl Ord for CircuitVMBranch {
   fn cmp(&self, other: &Self) -> Ordering {
-    let depth_cmp = self.stack.len().cmp(&other.stack.len());
+    let depth_cmp =
+      (self.stack.len(), self.generator_depth()).cmp(&(other.stack.len(), other.generator_depth()));
 
     if depth_cmp != Ordering::Equal {
       // Prefer deeper stacks
       return depth_cmp;
     }
 
-    match (
-      self.frame.as_any().downcast_ref::<BytecodeStackFrame>(),
-      other.frame.as_any().downcast_ref::<BytecodeStackFrame>(),
-    ) {
+    match (self.bytecode_frame(), other.bytecode_frame()) {
       (Some(self_frame), Some(other_frame)) => {
         match (
           self_frame.decoder.peek_type(),
           other_frame.decoder.peek_type(),
         ) {
-          (BytecodeType::End, BytecodeType::End) => Ordering::Equal,
+          // Different functions return separately, so that the branches can meet in the caller
+          (BytecodeType::End, BytecodeType::End) => other_frame
+            .fn_start
+            .cmp(&self_frame.fn_start)
+            .then_with(|| self.cmp_callers(other)),
           (BytecodeType::End, _) => Ordering::Less,
           (_, BytecodeType::End) => Ordering::Greater,
 
-          // We prefer working on an earlier bytecode position, but we have a max-heap so we need to
-          // reverse the order so that the earlier position will be higher priority.
-          _ => other_frame.decoder.pos.cmp(&self_frame.decoder.pos),
+          // Branches at the same position are merged
+          _ if self_frame.decoder.pos == other_frame.decoder.pos => self.cmp_callers(other),
+
+          _ => self.scheduler.cmp(self, other),
         }
       }
       // No preference for non-bytecode frames
@@ -152,3 +461,16 @@ impl PartialEq for CircuitVMBranch {
     self.cmp(other) == Ordering::Equal
   }
 }
+
+/// Removes the fork that separated two merging branches (the last fork they have in common) from
+/// `fork_sites` and returns its position.
+pub fn merge_fork_sites(fork_sites: &mut Vec<usize>, other: &[usize]) -> Option<usize> {
+  let common_len = fork_sites
+    .iter()
+    .zip(other)
+    .take_while(|(a, b)| a == b)
+    .count();
+
+  fork_sites.truncate(common_len);
+  fork_sites.pop()
+}
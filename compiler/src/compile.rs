@@ -1,108 +1,928 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, path::Path, rc::Rc, time::Duration};
 
-use valuescript_compiler::{asm, assemble, Diagnostic, ResolvedPath};
-use valuescript_vm::vs_value::{ToDynamicVal, Val, VsType};
+use valuescript_compiler::{asm, assemble, Diagnostic, DiagnosticLevel, ResolvedPath};
+use valuescript_vm::{
+  operations::{op_and, op_greater_eq, op_less_eq, op_mul, op_not, op_or, op_plus},
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  ValTrait,
+};
 
 use crate::{
+  branch_scheduler::SchedulingStrategy,
   bytecode::{Bytecode, DecoderMaker},
-  circuit::Circuit,
+  call_sites::CallSites,
+  circuit::{AssertionInfo, Circuit, ErrorCodeInfo},
   circuit_builder::CircuitBuilder,
   circuit_signal::{CircuitSignal, CircuitSignalData},
-  circuit_vm::CircuitVM,
+  circuit_vm::{Assertion, CircuitVM, ErrorOutcome},
+  circuit_vm_branch::RecursionLimits,
+  compile_error::{
+    CircuitError, CompileErr, LimitExceeded, Location, NonConvergingLoop, UncaughtException,
+  },
   cs_function::CsFunction,
+  debug_info::DebugInfo,
   id_generator::IdGenerator,
+  intrinsics::{is_summon_module_path, SUMMON_MODULE_SOURCE},
+  optional_signal::OptionalSignal,
+  range_analysis::{RangeAnalysis, ValueRange},
+  source_annotations::{parse_entry_annotations, EntryAnnotations, Validation},
+  summon_log::LogRecord,
   val_dynamic_downcast::val_dynamic_downcast,
 };
 
 pub struct CompileOk {
   pub circuit: Circuit,
   pub diagnostics: HashMap<ResolvedPath, Vec<Diagnostic>>,
-}
 
-#[derive(Debug)]
-pub struct CompileErr {
-  pub diagnostics: HashMap<ResolvedPath, Vec<Diagnostic>>,
+  /// The `summon.log` calls that were reached, in the order they were run.
+  pub logs: Vec<LogRecord>,
 }
 
 pub type CompileResult = Result<CompileOk, CompileErr>;
 
+#[derive(Default, Clone)]
+pub struct CompileOptions {
+  /// Add an `error_code` output identifying which throw site caused the `error` output.
+  pub error_codes: bool,
+
+  /// Entry parameters to bind to values during compilation (param name -> value), instead of
+  /// making them circuit inputs.
+  pub consts: HashMap<String, f64>,
+
+  /// The export to compile, or the default export if `None`.
+  pub entry: Option<String>,
+
+  /// The default bound on how deeply each function can recurse, for functions without a
+  /// `@maxDepth` tag.
+  pub max_recursion_depth: Option<usize>,
+
+  pub limits: CompileLimits,
+
+  /// How the VM chooses which branch to run next, which can affect the size of the circuit.
+  pub scheduling_strategy: SchedulingStrategy,
+}
+
+/// Budgets for running the program, so that programs which branch indefinitely fail with
+/// `CompileErr::LimitExceeded` instead of running forever. There are no limits by default.
+#[derive(Default, Clone, Debug)]
+pub struct CompileLimits {
+  /// VM steps, counted over all branches.
+  pub max_steps: Option<usize>,
+
+  /// Branches that are alive at the same time.
+  pub max_branches: Option<usize>,
+
+  /// Signals created, which is an upper bound on the number of wires.
+  pub max_signals: Option<usize>,
+
+  /// Time spent running the program.
+  pub timeout: Option<Duration>,
+
+  /// How many times a fork can repeat without merging before its loop is reported as
+  /// non-converging. Unlike the other limits, this applies by default (see
+  /// `DEFAULT_MAX_LOOP_FORKS`).
+  pub max_loop_forks: Option<usize>,
+}
+
+impl CompileLimits {
+  pub fn is_unlimited(&self) -> bool {
+    let CompileLimits {
+      max_steps,
+      max_branches,
+      max_signals,
+      timeout,
+      max_loop_forks: _,
+    } = self;
+
+    max_steps.is_none() && max_branches.is_none() && max_signals.is_none() && timeout.is_none()
+  }
+}
+
 pub fn compile<ReadFile>(path: ResolvedPath, read_file: ReadFile) -> CompileResult
 where
   ReadFile: Fn(&str) -> Result<String, String>,
 {
+  compile_with_options(path, &CompileOptions::default(), read_file)
+}
+
+/// Compiles the entry point into a circuit. Problems with the program are reported as a
+/// `CompileErr`, never as a panic.
+pub fn compile_with_options<ReadFile>(
+  path: ResolvedPath,
+  options: &CompileOptions,
+  read_file: ReadFile,
+) -> CompileResult
+where
+  ReadFile: Fn(&str) -> Result<String, String>,
+{
+  let entry_path = path.clone();
+  let entry_source = read_file(&path.path).unwrap_or_default();
+
+  let selection = match &options.entry {
+    Some(export_name) => ExportSelection::Named(export_name.clone()),
+    None => ExportSelection::Default,
+  };
+
+  let CompileArtifacts {
+    entry_points,
+    functions,
+    call_sites,
+    debug_info,
+    mut diagnostics,
+  } = get_compile_artifacts(path, &read_file, selection)?;
+
+  let entry_point = match entry_points.into_iter().next() {
+    Some(entry_point) => entry_point,
+    None => return Err(CompileErr::Internal("Missing entry point".into())),
+  };
+
+  let mut logs = Vec::<LogRecord>::new();
+
+  match compile_entry_point(
+    entry_point,
+    &functions,
+    options,
+    &entry_path,
+    &entry_source,
+    &call_sites,
+    &mut logs,
+  )
+  .map_err(|err| debug_info.annotate(err))
+  {
+    Ok(circuit) => Ok(CompileOk {
+      circuit,
+      diagnostics,
+      logs,
+    }),
+    Err(CompileErr::Diagnostics(entry_diagnostics)) => {
+      // Include the diagnostics from compiling the module too
+      for (path, file_diagnostics) in entry_diagnostics {
+        diagnostics
+          .entry(path)
+          .or_default()
+          .extend(file_diagnostics);
+      }
+
+      Err(CompileErr::Diagnostics(diagnostics))
+    }
+    Err(err) => Err(err.with_logs(logs)),
+  }
+}
+
+pub struct CompileAllOk {
+  /// The result for each exported function (export name -> result). The default export is named
+  /// `default`.
+  pub entries: Vec<(String, CompileResult)>,
+
+  /// Diagnostics from compiling the module, which are shared by all entries.
+  pub diagnostics: HashMap<ResolvedPath, Vec<Diagnostic>>,
+}
+
+/// Compiles every exported function into its own circuit. The module is only parsed and assembled
+/// once. `options.entry` is ignored.
+pub fn compile_all_exports<ReadFile>(
+  path: ResolvedPath,
+  options: &CompileOptions,
+  read_file: ReadFile,
+) -> Result<CompileAllOk, CompileErr>
+where
+  ReadFile: Fn(&str) -> Result<String, String>,
+{
+  let entry_path = path.clone();
+  let entry_source = read_file(&path.path).unwrap_or_default();
+
   let CompileArtifacts {
-    name,
-    main_asm,
-    main,
+    entry_points,
+    functions,
+    call_sites,
+    debug_info,
     diagnostics,
-  } = get_compile_artifacts(path, read_file)?;
+  } = get_compile_artifacts(path, &read_file, ExportSelection::All)?;
 
-  let (input_len, outputs) = run(main);
+  let entries = entry_points
+    .into_iter()
+    .map(|entry_point| {
+      let export_name = entry_point.export_name.clone();
+      let mut logs = Vec::<LogRecord>::new();
 
-  let (output_ids, builder) = build(input_len, outputs);
-  let circuit = generate_circuit(name, main_asm, output_ids, builder);
+      let result = match compile_entry_point(
+        entry_point,
+        &functions,
+        options,
+        &entry_path,
+        &entry_source,
+        &call_sites,
+        &mut logs,
+      ) {
+        Ok(circuit) => Ok(CompileOk {
+          circuit,
+          diagnostics: Default::default(),
+          logs,
+        }),
+        Err(err) => Err(debug_info.annotate(err).with_logs(logs)),
+      };
 
-  Ok(CompileOk {
-    circuit,
+      (export_name, result)
+    })
+    .collect();
+
+  Ok(CompileAllOk {
+    entries,
     diagnostics,
   })
 }
 
+fn compile_entry_point(
+  entry_point: EntryPoint,
+  functions: &[CompiledFunction],
+  options: &CompileOptions,
+  entry_path: &ResolvedPath,
+  entry_source: &str,
+  call_sites: &Rc<CallSites>,
+  logs: &mut Vec<LogRecord>,
+) -> Result<Circuit, CompileErr> {
+  let EntryPoint {
+    export_name: _,
+    fn_name: name,
+    fn_asm: main_asm,
+    fn_: main,
+  } = entry_point;
+
+  if val_dynamic_downcast::<CsFunction>(&main).is_none() {
+    return Err(CompileErr::EntryNotFound(format!(
+      "Entry point {} is not a regular function",
+      name
+    )));
+  }
+
+  let annotations = parse_entry_annotations(entry_source, &name);
+
+  let param_names = main_asm
+    .parameters
+    .iter()
+    .map(|reg| reg.name.clone())
+    .collect::<Vec<_>>();
+
+  let mut setup_diagnostics = check_annotations(entry_source, &annotations, &param_names);
+  let max_depths = resolve_max_depths(
+    entry_source,
+    &annotations,
+    functions,
+    &mut setup_diagnostics,
+  );
+
+  for (const_name, value) in &options.consts {
+    if !param_names.contains(const_name) {
+      setup_diagnostics.push(make_diagnostic(
+        entry_source,
+        0,
+        format!(
+          "Can't bind {} because {} has no such parameter",
+          const_name, name
+        ),
+      ));
+
+      continue;
+    }
+
+    let declared_ranges = [
+      ("@range", annotations.input_domains.get(const_name)),
+      ("range annotation", annotations.input_ranges.get(const_name)),
+    ];
+
+    for (kind, range) in declared_ranges {
+      if let Some(range) = range.filter(|range| !range.contains(*value)) {
+        setup_diagnostics.push(make_diagnostic(
+          entry_source,
+          0,
+          format!(
+            "Can't bind {} to {} because it is outside its {} {}..{}",
+            const_name, value, kind, range.min, range.max
+          ),
+        ));
+      }
+    }
+  }
+
+  if !setup_diagnostics.is_empty() {
+    return Err(CompileErr::Diagnostics(HashMap::from([(
+      entry_path.clone(),
+      setup_diagnostics,
+    )])));
+  }
+
+  let id_gen = Rc::new(RefCell::new(IdGenerator::new()));
+
+  // Parameters that aren't bound by `options.consts` become inputs, numbered in order
+  let mut input_names = Vec::<String>::new();
+  let mut input_names_by_id = HashMap::<usize, String>::new();
+
+  let args = param_names
+    .iter()
+    .map(|param_name| match options.consts.get(param_name) {
+      Some(value) => value.to_val(),
+      None => {
+        input_names.push(param_name.clone());
+
+        let input = CircuitSignal::new(&id_gen, VsType::Number, CircuitSignalData::Input);
+        input_names_by_id.insert(input.id, param_name.clone());
+
+        input.to_dynamic_val()
+      }
+    })
+    .collect::<Vec<_>>();
+
+  // Keyed by the ids of the input signals, which is how `RangeAnalysis` finds them
+  let mut input_ranges = HashMap::<usize, ValueRange>::new();
+  for (id, param_name) in &input_names_by_id {
+    let assumed = annotations.input_ranges.get(param_name);
+    let declared = annotations.input_domains.get(param_name);
+
+    let range = match (assumed, declared) {
+      (Some(assumed), Some(declared)) => assumed.intersect(declared),
+      (assumed, declared) => assumed.or(declared).copied(),
+    };
+
+    if let Some(range) = range {
+      input_ranges.insert(*id, range);
+    }
+  }
+
+  let RunOutput {
+    outputs,
+    assertions,
+    errors,
+  } = match run(
+    CircuitVM {
+      recursion_limits: Rc::new(RecursionLimits {
+        default: options.max_recursion_depth,
+        by_fn_start: max_depths,
+      }),
+      scheduling_strategy: options.scheduling_strategy,
+      limits: options.limits.clone(),
+      id_generator: Some(id_gen.clone()),
+      input_names: input_names_by_id,
+      call_sites: call_sites.clone(),
+      ..Default::default()
+    },
+    main,
+    args.clone(),
+    logs,
+  ) {
+    Ok(run_output) => run_output,
+    Err(RunError::AssertionFailed(assertion)) => {
+      return Err(CompileErr::AssertionFailed {
+        message: assertion.message,
+        span: assertion.call_site.map(|call_site| call_site.span),
+      });
+    }
+    Err(RunError::LimitExceeded(limit_exceeded)) => {
+      return Err(CompileErr::LimitExceeded(limit_exceeded));
+    }
+    Err(RunError::NonConvergingLoop(non_converging_loop)) => {
+      return Err(CompileErr::NonConvergingLoop(non_converging_loop));
+    }
+    Err(RunError::Exception(exception, uncaught)) => {
+      if uncaught.is_empty() {
+        return Err(exception_to_compile_err(&exception));
+      }
+
+      return Err(CompileErr::UncaughtException(UncaughtException {
+        throws: uncaught
+          .into_iter()
+          .map(|outcome| (exception_message(&outcome.exception), Some(outcome.site)))
+          .collect(),
+      }));
+    }
+  };
+
+  let output_names = main_output_names(&name, outputs.len());
+  let (mut outputs, mut output_names) = flatten_optional_outputs(outputs, output_names)?;
+  let mut error_codes = Vec::<ErrorCodeInfo>::new();
+
+  if !errors.is_empty() {
+    let mut error = false.to_val();
+
+    for outcome in &errors {
+      error = op_or(&error, &outcome.flag).map_err(|e| exception_to_compile_err(&e))?;
+    }
+
+    let no_error = op_not(&error).map_err(|e| exception_to_compile_err(&e))?;
+
+    for output in &mut outputs {
+      *output = op_mul(output, &no_error).map_err(|e| exception_to_compile_err(&e))?;
+    }
+
+    outputs.push(error);
+    output_names.push("error".to_string());
+
+    if options.error_codes {
+      let (error_code, infos) = error_code(&errors)?;
+      error_codes = infos;
+
+      outputs.push(error_code);
+      output_names.push("error_code".to_string());
+    }
+  }
+
+  if !annotations.input_domains.is_empty() {
+    let inputs_valid =
+      inputs_valid(&args, &param_names, &annotations).map_err(|e| exception_to_compile_err(&e))?;
+
+    if annotations.validation == Validation::Gate {
+      for output in &mut outputs {
+        *output = op_mul(output, &inputs_valid).map_err(|e| exception_to_compile_err(&e))?;
+      }
+    }
+
+    outputs.push(inputs_valid);
+    output_names.push("inputs_valid".to_string());
+  }
+
+  let mut assertion_infos = Vec::<AssertionInfo>::new();
+
+  for (i, assertion) in assertions.into_iter().enumerate() {
+    let output = format!("__assert_{}", i);
+
+    assertion_infos.push(AssertionInfo {
+      output: output.clone(),
+      location: assertion.call_site.map(|call_site| {
+        (
+          relative_to_entry(&call_site.span.path, entry_path),
+          call_site.line,
+          call_site.column,
+        )
+      }),
+      message: assertion.message,
+    });
+
+    outputs.push(assertion.ok);
+    output_names.push(output);
+  }
+
+  let mut range_analysis = RangeAnalysis::new(input_ranges);
+
+  let output_ranges = outputs
+    .iter()
+    .map(|output| range_analysis.range_of(output))
+    .collect::<Vec<_>>();
+
+  let (output_ids, builder) = build(input_names.len(), outputs)?;
+  let mut circuit = generate_circuit(
+    input_names,
+    output_names,
+    output_ids,
+    output_ranges,
+    builder,
+  );
+  circuit.bit_width = range_analysis.max_bit_width();
+  circuit.input_domains = annotations.input_domains;
+  circuit.assertions = assertion_infos;
+  circuit.error_codes = error_codes;
+
+  Ok(circuit)
+}
+
+/// The path of a file relative to the directory of the entry file (or the full path if it's
+/// outside of that directory).
+fn relative_to_entry(path: &str, entry_path: &ResolvedPath) -> String {
+  let relative = Path::new(&entry_path.path)
+    .parent()
+    .and_then(|dir| Path::new(path).strip_prefix(dir).ok());
+
+  match relative {
+    Some(relative) => relative.to_string_lossy().to_string(),
+    None => path.to_string(),
+  }
+}
+
+fn check_annotations(
+  source: &str,
+  annotations: &EntryAnnotations,
+  param_names: &[String],
+) -> Vec<Diagnostic> {
+  let mut errors = annotations.errors.clone();
+
+  for param_name in annotations.input_domains.keys() {
+    if !param_names.contains(param_name) {
+      errors.push((
+        0,
+        format!("@range refers to unknown parameter {}", param_name),
+      ));
+    }
+  }
+
+  errors
+    .into_iter()
+    .map(|(pos, message)| make_diagnostic(source, pos, message))
+    .collect()
+}
+
+/**
+ * Finds the functions that `@maxDepth` tags are on (function start -> max depth). Functions are
+ * matched by name, and then by their parameter names if several functions have the same name (eg
+ * `helper` functions in different modules).
+ */
+fn resolve_max_depths(
+  source: &str,
+  annotations: &EntryAnnotations,
+  functions: &[CompiledFunction],
+  diagnostics: &mut Vec<Diagnostic>,
+) -> HashMap<usize, usize> {
+  let mut max_depths = HashMap::<usize, usize>::new();
+
+  for tag in &annotations.max_depths {
+    let mut candidates = functions
+      .iter()
+      .filter(|function| function.name == tag.fn_name)
+      .collect::<Vec<_>>();
+
+    if candidates.len() > 1 {
+      candidates.retain(|function| function.param_names == tag.param_names);
+    }
+
+    match candidates.as_slice() {
+      // The function isn't in the bytecode, so it is never called
+      [] => {}
+      [function] => {
+        max_depths.insert(function.start, tag.max_depth);
+      }
+      _ => diagnostics.push(make_diagnostic(
+        source,
+        tag.pos,
+        format!(
+          "@maxDepth can't tell which function {} it is for, since several functions have that \
+          name and parameters (rename one of them)",
+          tag.fn_name
+        ),
+      )),
+    }
+  }
+
+  max_depths
+}
+
+fn make_diagnostic(source: &str, pos: usize, message: String) -> Diagnostic {
+  let mut diagnostic = Diagnostic {
+    level: DiagnosticLevel::Error,
+    message,
+    span: Default::default(),
+  };
+
+  // The CLI interprets spans as character positions
+  let char_pos = source[..pos].chars().count() as u32;
+  diagnostic.span.lo.0 = char_pos;
+  diagnostic.span.hi.0 = char_pos;
+
+  diagnostic
+}
+
+/// Generates a signal for the code of the error that occurred (or zero). Each throw site gets its
+/// own code, starting from 1.
+fn error_code(errors: &[ErrorOutcome]) -> Result<(Val, Vec<ErrorCodeInfo>), CompileErr> {
+  let mut sites = Vec::<&Location>::new();
+  let mut infos = Vec::<ErrorCodeInfo>::new();
+  let mut error_code = 0.0.to_val();
+
+  for outcome in errors {
+    let code = match sites.iter().position(|site| **site == outcome.site) {
+      Some(i) => i + 1,
+      None => {
+        sites.push(&outcome.site);
+
+        infos.push(ErrorCodeInfo {
+          code: sites.len(),
+          message: exception_message(&outcome.exception),
+        });
+
+        sites.len()
+      }
+    };
+
+    let term =
+      op_mul(&outcome.flag, &(code as f64).to_val()).map_err(|e| exception_to_compile_err(&e))?;
+
+    error_code = op_plus(&error_code, &term).map_err(|e| exception_to_compile_err(&e))?;
+  }
+
+  Ok((error_code, infos))
+}
+
+fn exception_message(exception: &Val) -> String {
+  match exception.sub(&"message".to_val()) {
+    Ok(Val::String(message)) => message.to_string(),
+    _ => exception.to_string(),
+  }
+}
+
+/// Converts an exception from running the program (or from an operation on its outputs) into a
+/// `CompileErr`.
+fn exception_to_compile_err(exception: &Val) -> CompileErr {
+  match CircuitError::from_val(exception) {
+    Some(err) => err.clone().into(),
+    None => CompileErr::UncaughtException(UncaughtException {
+      throws: vec![(exception_message(exception), None)],
+    }),
+  }
+}
+
+/// Generates a signal that is true when every input is within its `@range` domain.
+fn inputs_valid(
+  args: &[Val],
+  param_names: &[String],
+  annotations: &EntryAnnotations,
+) -> Result<Val, Val> {
+  let mut valid = Val::Bool(true);
+
+  for (input, param_name) in args.iter().zip(param_names) {
+    if let Some(domain) = annotations.input_domains.get(param_name) {
+      let above_min = op_greater_eq(input, &(domain.min as f64).to_val())?;
+      let below_max = op_less_eq(input, &(domain.max as f64).to_val())?;
+
+      valid = op_and(&valid, &above_min)?;
+      valid = op_and(&valid, &below_max)?;
+    }
+  }
+
+  Ok(valid)
+}
+
 struct CompileArtifacts {
-  name: String,
-  main_asm: asm::Function,
-  main: Val,
+  entry_points: Vec<EntryPoint>,
+  functions: Vec<CompiledFunction>,
+  call_sites: Rc<CallSites>,
+  debug_info: DebugInfo,
   diagnostics: HashMap<ResolvedPath, Vec<Diagnostic>>,
 }
 
+struct EntryPoint {
+  export_name: String,
+  fn_name: String,
+  fn_asm: asm::Function,
+  fn_: Val,
+}
+
+/// A named function in the bytecode.
+struct CompiledFunction {
+  name: String,
+  param_names: Vec<String>,
+
+  /// Where the function's bytecode starts, which identifies it while running.
+  start: usize,
+}
+
+enum ExportSelection {
+  Default,
+  Named(String),
+  All,
+}
+
 fn get_compile_artifacts<ReadFile>(
   path: ResolvedPath,
   read_file: ReadFile,
+  selection: ExportSelection,
 ) -> Result<CompileArtifacts, CompileErr>
 where
   ReadFile: Fn(&str) -> Result<String, String>,
 {
+  let call_sites = RefCell::new(CallSites::default());
+  let sources = RefCell::new(HashMap::<String, String>::new());
+
+  let read_file = |path: &str| {
+    if is_summon_module_path(path) {
+      return Ok(SUMMON_MODULE_SOURCE.to_string());
+    }
+
+    let source = read_file(path)?;
+    let rewritten = call_sites.borrow_mut().rewrite(path, &source);
+    sources.borrow_mut().insert(path.to_string(), source);
+
+    Ok(rewritten)
+  };
+
   let valuescript_compiler::CompileResult {
     module,
-    diagnostics,
+    mut diagnostics,
   } = valuescript_compiler::compile(path, read_file);
 
-  let module = match module {
+  let call_sites = call_sites.into_inner();
+  call_sites.fix_diagnostics(&mut diagnostics);
+
+  // The summon module isn't a real file, so avoid reporting it (there shouldn't be any diagnostics
+  // for it anyway)
+  diagnostics.retain(|path, file_diagnostics| {
+    !is_summon_module_path(&path.path) || !file_diagnostics.is_empty()
+  });
+
+  let mut module = match module {
     Some(module) => module,
-    None => return Err(CompileErr { diagnostics }),
+    None => return Err(CompileErr::Diagnostics(diagnostics)),
   };
 
-  let (name, asm_fn) = get_asm_main(&module);
+  let exports = select_exports(&module, &selection).map_err(CompileErr::EntryNotFound)?;
+
+  let named_functions = named_functions(&module);
+
+  // Assemble the module with a default export containing the selected functions, so that they can
+  // all be decoded from the same bytecode. The other named functions are included after them to
+  // find where their bytecode starts.
+  module.export_default = asm::Value::Array(Box::new(asm::Array {
+    values: exports
+      .iter()
+      .map(|export| export.pointer.clone())
+      .chain(named_functions.iter().map(|(pointer, _)| pointer.clone()))
+      .map(asm::Value::Pointer)
+      .collect(),
+  }));
 
   let bytecode = Rc::new(Bytecode::new(assemble(&module)));
 
-  let val = bytecode.decoder(0).decode_val(&mut vec![]);
+  let mut fns = match bytecode.decoder(0).decode_val(&mut vec![]) {
+    Val::Array(array) => array.elements.clone(),
+    _ => return Err(CompileErr::Internal("Expected array of exports".into())),
+  };
+
+  let functions = named_functions
+    .into_iter()
+    .zip(fns.split_off(exports.len()))
+    .filter_map(|((_, function), fn_)| {
+      Some(CompiledFunction {
+        start: val_dynamic_downcast::<CsFunction>(&fn_)?.start,
+        ..function
+      })
+    })
+    .collect::<Vec<_>>();
+
+  let fn_starts = functions
+    .iter()
+    .map(|function| (function.name.clone(), function.start))
+    .collect();
+
+  let debug_info = DebugInfo::new(&module, &sources.into_inner(), &bytecode, &fn_starts);
+
+  let entry_points = exports
+    .into_iter()
+    .zip(fns)
+    .map(|(export, fn_)| EntryPoint {
+      export_name: export.name,
+      fn_name: export.fn_name,
+      fn_asm: export.fn_asm,
+      fn_,
+    })
+    .collect();
 
   Ok(CompileArtifacts {
-    name: name.clone(),
-    main_asm: asm_fn.clone(),
-    main: val,
+    entry_points,
+    functions,
+    call_sites: Rc::new(call_sites),
+    debug_info,
     diagnostics,
   })
 }
 
-fn get_asm_main(module: &asm::Module) -> (&String, &asm::Function) {
-  let main_ptr = match &module.export_default {
-    asm::Value::Pointer(ptr) => ptr,
-    _ => panic!("Expected pointer"),
+/// The functions in the module that have names, with their names and parameter names (the start is
+/// filled in after assembling).
+fn named_functions(module: &asm::Module) -> Vec<(asm::Pointer, CompiledFunction)> {
+  module
+    .definitions
+    .iter()
+    .filter_map(|definition| {
+      let function = match &definition.content {
+        asm::DefinitionContent::Function(function) => function,
+        _ => return None,
+      };
+
+      let meta = module
+        .definitions
+        .iter()
+        .find(|meta| Some(&meta.pointer) == function.meta.as_ref())?;
+
+      let name = match &meta.content {
+        asm::DefinitionContent::Meta(meta) if !meta.name.is_empty() => meta.name.clone(),
+        _ => return None,
+      };
+
+      Some((
+        definition.pointer.clone(),
+        CompiledFunction {
+          name,
+          param_names: function
+            .parameters
+            .iter()
+            .map(|param| param.name.clone())
+            .collect(),
+          start: 0,
+        },
+      ))
+    })
+    .collect()
+}
+
+struct ExportedFunction {
+  name: String,
+  pointer: asm::Pointer,
+  fn_name: String,
+  fn_asm: asm::Function,
+}
+
+fn select_exports(
+  module: &asm::Module,
+  selection: &ExportSelection,
+) -> Result<Vec<ExportedFunction>, String> {
+  let named_exports = module
+    .export_star
+    .local
+    .properties
+    .iter()
+    .filter_map(|(key, value)| match key {
+      asm::Value::String(name) => Some((name.clone(), value)),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+
+  match selection {
+    ExportSelection::Default => Ok(vec![resolve_exported_fn(
+      module,
+      "default",
+      &module.export_default,
+    )
+    .ok_or("Default export is not a function")?]),
+    ExportSelection::Named(name) => {
+      let value = match named_exports
+        .iter()
+        .find(|(export_name, _)| export_name == name)
+      {
+        Some((_, value)) => value,
+        None => {
+          let available = named_exports
+            .iter()
+            .map(|(export_name, _)| export_name.as_str())
+            .collect::<Vec<_>>();
+
+          return Err(format!(
+            "No export named {} (available: {})",
+            name,
+            available.join(", ")
+          ));
+        }
+      };
+
+      Ok(vec![resolve_exported_fn(module, name, value).ok_or_else(
+        || format!("Export {} is not a function", name),
+      )?])
+    }
+    ExportSelection::All => {
+      let mut exports = Vec::<ExportedFunction>::new();
+
+      exports.extend(resolve_exported_fn(
+        module,
+        "default",
+        &module.export_default,
+      ));
+
+      for (name, value) in &named_exports {
+        exports.extend(resolve_exported_fn(module, name, value));
+      }
+
+      if exports.is_empty() {
+        return Err("Module does not export any functions".to_string());
+      }
+
+      Ok(exports)
+    }
+  }
+}
+
+fn resolve_exported_fn(
+  module: &asm::Module,
+  name: &str,
+  value: &asm::Value,
+) -> Option<ExportedFunction> {
+  let pointer = match value {
+    asm::Value::Pointer(pointer) => pointer,
+    _ => return None,
   };
 
-  let fn_ = match resolve_ptr(module, main_ptr).unwrap() {
-    asm::DefinitionContent::Function(fn_) => fn_,
-    _ => panic!("Expected function"),
+  let fn_asm = match resolve_ptr(module, pointer)? {
+    asm::DefinitionContent::Function(fn_asm) => fn_asm,
+    _ => return None,
   };
 
-  let meta = match resolve_ptr(module, fn_.meta.as_ref().unwrap()).unwrap() {
-    asm::DefinitionContent::Meta(meta) => meta,
-    _ => panic!("Expected meta"),
+  let fn_name = match fn_asm
+    .meta
+    .as_ref()
+    .and_then(|meta| resolve_ptr(module, meta))
+  {
+    Some(asm::DefinitionContent::Meta(meta)) => meta.name.clone(),
+    _ => name.to_string(),
   };
 
-  (&meta.name, fn_)
+  Some(ExportedFunction {
+    name: name.to_string(),
+    pointer: pointer.clone(),
+    fn_name,
+    fn_asm: fn_asm.clone(),
+  })
 }
 
 fn resolve_ptr<'a>(
@@ -118,52 +938,117 @@ fn resolve_ptr<'a>(
   None
 }
 
-fn run(main: Val) -> (usize, Vec<Val>) {
-  let param_count = match val_dynamic_downcast::<CsFunction>(&main) {
-    Some(cs_fn) => cs_fn.parameter_count,
-    None => panic!("Default export is not a regular function"),
+struct RunOutput {
+  outputs: Vec<Val>,
+  assertions: Vec<Assertion>,
+  errors: Vec<ErrorOutcome>,
+}
+
+/// Runs the entry function. Fails with an assertion that is known to fail at compile time, with the
+/// limit that was exceeded, or with a loop that doesn't converge.
+enum RunError {
+  AssertionFailed(Assertion),
+  LimitExceeded(LimitExceeded),
+  NonConvergingLoop(NonConvergingLoop),
+
+  /// An exception, along with the outcomes of the branches that threw it when it was thrown by
+  /// `throw` statements (see `CircuitVM::uncaught`).
+  Exception(Val, Vec<ErrorOutcome>),
+}
+
+/// Runs the entry function, adding the `summon.log` calls it reaches to `logs` whether or not it
+/// fails.
+fn run(
+  mut vm: CircuitVM,
+  main: Val,
+  args: Vec<Val>,
+  logs: &mut Vec<LogRecord>,
+) -> Result<RunOutput, RunError> {
+  let res = vm.run(&mut Val::Undefined, main, args);
+  logs.append(&mut vm.logs);
+
+  let outputs = match res {
+    Ok(Val::Array(vs_array)) => vs_array.elements.clone(),
+    Ok(val) => vec![val],
+    Err(_) if vm.failed_assertion.is_some() => {
+      return Err(RunError::AssertionFailed(vm.failed_assertion.unwrap()));
+    }
+    Err(_) if vm.limit_exceeded.is_some() => {
+      return Err(RunError::LimitExceeded(vm.limit_exceeded.unwrap()));
+    }
+    Err(_) if vm.non_converging_loop.is_some() => {
+      return Err(RunError::NonConvergingLoop(vm.non_converging_loop.unwrap()));
+    }
+    Err(err) => return Err(RunError::Exception(err, vm.uncaught)),
   };
 
-  let id_gen = Rc::new(RefCell::new(IdGenerator::new()));
-  let mut input_args = Vec::<Val>::new();
+  Ok(RunOutput {
+    outputs,
+    assertions: vm.assertions,
+    errors: vm.errors,
+  })
+}
 
-  for _ in 0..param_count {
-    input_args.push(
-      CircuitSignal::new(&id_gen, Some(VsType::Number), CircuitSignalData::Input).to_dynamic_val(),
-    );
-  }
+fn build(
+  input_len: usize,
+  outputs: Vec<Val>,
+) -> Result<(Vec<usize>, CircuitBuilder), CircuitError> {
+  let mut builder = CircuitBuilder::default();
+  builder.include_inputs(input_len);
+  let output_ids = builder.include_outputs(&outputs)?;
+
+  Ok((output_ids, builder))
+}
 
-  let mut vm = CircuitVM::default();
+/// Optional outputs become their value (zero when undefined) and a `<name>.isDefined` output.
+fn flatten_optional_outputs(
+  outputs: Vec<Val>,
+  names: Vec<String>,
+) -> Result<(Vec<Val>, Vec<String>), CompileErr> {
+  let mut flat_outputs = Vec::<Val>::new();
+  let mut flat_names = Vec::<String>::new();
 
-  let res = vm.run(None, &mut Val::Undefined, main, input_args);
+  for (output, name) in outputs.into_iter().zip(names) {
+    match val_dynamic_downcast::<OptionalSignal>(&output) {
+      Some(optional) => {
+        flat_outputs.push(
+          optional
+            .masked_value()
+            .map_err(|e| exception_to_compile_err(&e))?,
+        );
+        flat_names.push(name.clone());
 
-  match res {
-    Ok(Val::Array(vs_array)) => (param_count, vs_array.elements.clone()),
-    Ok(val) => (param_count, vec![val]),
-    Err(err) => {
-      eprintln!("Uncaught exception: {}", err.pretty());
-      std::process::exit(1);
+        flat_outputs.push(optional.is_defined.clone());
+        flat_names.push(format!("{}.isDefined", name));
+      }
+      None => {
+        flat_outputs.push(output);
+        flat_names.push(name);
+      }
     }
   }
+
+  Ok((flat_outputs, flat_names))
 }
 
-fn build(input_len: usize, outputs: Vec<Val>) -> (Vec<usize>, CircuitBuilder) {
-  let mut builder = CircuitBuilder::default();
-  builder.include_inputs(input_len);
-  let output_ids = builder.include_outputs(&outputs);
+fn main_output_names(name: &str, len: usize) -> Vec<String> {
+  if len == 1 {
+    return vec![name.to_string()];
+  }
 
-  (output_ids, builder)
+  (0..len).map(|i| format!("{}[{}]", name, i)).collect()
 }
 
 fn generate_circuit(
-  name: String,
-  fn_: asm::Function,
+  input_names: Vec<String>,
+  output_names: Vec<String>,
   output_ids: Vec<usize>,
+  output_ranges: Vec<Option<ValueRange>>,
   builder: CircuitBuilder,
 ) -> Circuit {
   let mut inputs = HashMap::<String, usize>::new();
-  for (i, reg) in fn_.parameters.iter().enumerate() {
-    inputs.insert(reg.name.clone(), i);
+  for (i, input_name) in input_names.into_iter().enumerate() {
+    inputs.insert(input_name, i);
   }
 
   let mut constants = HashMap::<usize, usize>::new();
@@ -172,12 +1057,10 @@ fn generate_circuit(
   }
 
   let mut outputs = HashMap::<String, usize>::new();
-  if output_ids.len() == 1 {
-    outputs.insert(name, output_ids[0]);
-  } else {
-    for (i, output_id) in output_ids.iter().enumerate() {
-      outputs.insert(format!("{}[{}]", name, i), *output_id);
-    }
+  let mut named_output_ranges = HashMap::<String, Option<ValueRange>>::new();
+  for ((name, output_id), range) in output_names.into_iter().zip(output_ids).zip(output_ranges) {
+    outputs.insert(name.clone(), output_id);
+    named_output_ranges.insert(name, range);
   }
 
   Circuit {
@@ -186,5 +1069,7 @@ fn generate_circuit(
     constants,
     outputs,
     gates: builder.gates,
+    output_ranges: named_output_ranges,
+    ..Default::default()
   }
 }
@@ -0,0 +1,627 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc, time::Duration};
+
+use valuescript_compiler::{Diagnostic, DiagnosticLevel, ResolvedPath};
+use valuescript_vm::{
+  type_error_builtin::ToTypeError,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, Val, VsType},
+  LoadFunctionResult, ValTrait,
+};
+
+use crate::{summon_log::LogRecord, val_dynamic_downcast::val_dynamic_downcast};
+
+/// Why compilation failed.
+#[derive(Debug)]
+pub enum CompileErr {
+  /// Problems reported as diagnostics, such as syntax errors and invalid annotations.
+  Diagnostics(HashMap<ResolvedPath, Vec<Diagnostic>>),
+
+  /// The requested export doesn't exist or isn't a function.
+  EntryNotFound(String),
+
+  /// A `summon.assert` that always fails.
+  AssertionFailed {
+    message: String,
+    span: Option<SourceSpan>,
+  },
+
+  /// An exception that is thrown unconditionally (or isn't thrown by a `throw` statement).
+  UncaughtException(UncaughtException),
+
+  /// The program can't be represented as a circuit.
+  Circuit(CircuitError),
+
+  /// Running the program exceeded one of the `CompileLimits`.
+  LimitExceeded(LimitExceeded),
+
+  /// A loop whose condition depends on signals, so it keeps forking forever.
+  NonConvergingLoop(NonConvergingLoop),
+
+  /// A bug in the compiler.
+  Internal(String),
+
+  /// An error from running a program that reached `summon.log` calls, along with what they logged.
+  Logged(Box<CompileErr>, Vec<LogRecord>),
+}
+
+/// A span of a source file, in characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+  pub path: String,
+  pub start: usize,
+  pub end: usize,
+}
+
+/// A span of a source file, along with the 1-based line and column where it starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+  pub span: SourceSpan,
+  pub line: usize,
+  pub column: usize,
+}
+
+/// Problems found while running the program on signals.
+#[derive(Debug, Clone)]
+pub enum CircuitError {
+  /// Circuits can only contain integers.
+  NonIntegerConstant(f64),
+
+  /// A value that can't be included in the circuit (eg a string output).
+  UnsupportedValue(String),
+
+  /// A logical operation between a boolean signal and a number signal.
+  IncompatibleTypes(VsType, VsType),
+
+  /// Using a signal as an array index or property key.
+  SignalAsIndex,
+
+  /// Values from different branches that don't have the same structure.
+  CouldNotMerge(Box<MergeFailure>),
+
+  /// A function recursed more deeply than its `@maxDepth` (or `--max-recursion-depth`) allows.
+  RecursionLimit(RecursionLimitFailure),
+
+  /// A signal used where the VM needs a constant boolean, eg as the callback result of a builtin
+  /// that doesn't support signals.
+  SignalAsCondition,
+
+  /// A bug in the compiler, found while running the program.
+  Internal(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeFailure {
+  /// What couldn't be merged, eg `register %4.items[3]`.
+  pub target: String,
+
+  /// The variable that couldn't be merged, eg `list.items[3]`, if the target is a register whose
+  /// name is known.
+  pub variable: Option<String>,
+
+  /// How the values differ, eg `array of length 3 vs array of length 4`.
+  pub reason: String,
+
+  /// The function where the branches met again (`None` for merges at the end of the entry
+  /// function).
+  pub fn_name: Option<String>,
+
+  /// The register that couldn't be merged, if the target is a register.
+  pub register: Option<usize>,
+
+  /// Where the mismatch is within the target, eg `.items[3]`.
+  pub path: String,
+
+  /// Bytecode position of the conditional jump that created the branches.
+  pub fork_pos: Option<usize>,
+
+  /// Bytecode position where the branches met again.
+  pub merge_pos: Option<usize>,
+
+  /// The condition that created the branches, eg the `if`, if it could be found.
+  pub fork_location: Option<SourceLocation>,
+
+  /// Where the branches met again, eg the end of the `if` statement, if it could be found.
+  pub merge_location: Option<SourceLocation>,
+
+  /// Where `fn_name` is declared, if it could be found.
+  pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecursionLimitFailure {
+  pub fn_name: String,
+  pub max_depth: usize,
+
+  /// The functions on the stack, outermost first, ending with the call that exceeded the limit.
+  pub call_chain: Vec<String>,
+
+  /// Bytecode position of the function, which identifies the error when it becomes an error
+  /// outcome.
+  pub fn_pos: usize,
+
+  /// Where the function is declared, if it could be found.
+  pub location: Option<SourceLocation>,
+}
+
+/// Why a program stopped being run early, along with where it was spending its time.
+#[derive(Debug, Clone)]
+pub struct LimitExceeded {
+  pub limit: Limit,
+
+  /// The bytecode positions where the most steps were run, with their step counts (most first).
+  /// These are only located in the source by the function they are in.
+  pub hot_spots: Vec<(Location, usize)>,
+
+  /// The forks that live branches haven't merged yet, with how many branches are waiting on each
+  /// (most first).
+  pub fork_sites: Vec<(Location, usize)>,
+}
+
+/// One of the `CompileLimits`, along with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+  Steps(usize),
+  Branches(usize),
+  Signals(usize),
+  Time(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct NonConvergingLoop {
+  /// The conditional jump that keeps forking.
+  pub location: Location,
+
+  /// The entry parameters that the condition depends on.
+  pub inputs: Vec<String>,
+
+  /// The number of times the fork repeated before the loop was reported.
+  pub forks: usize,
+}
+
+/// Exceptions that are thrown unconditionally. This is usually a single exception, but when every
+/// branch throws, it is the exception of each branch.
+#[derive(Debug, Clone)]
+pub struct UncaughtException {
+  /// The message of each exception, along with where it was thrown (if it was thrown by a `throw`
+  /// statement).
+  pub throws: Vec<(String, Option<Location>)>,
+}
+
+/// A bytecode position, along with the function it is in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+  pub fn_name: String,
+  pub pos: usize,
+
+  /// Where the function is declared, if it could be found. The bytecode doesn't have a source map,
+  /// so this only locates the function, not the position within it.
+  pub source: Option<SourceLocation>,
+}
+
+impl Location {
+  pub fn new(fn_name: String, pos: usize) -> Self {
+    Location {
+      fn_name,
+      pos,
+      source: None,
+    }
+  }
+}
+
+impl CompileErr {
+  /// Attaches the records of the `summon.log` calls that ran before the error, if there are any.
+  pub fn with_logs(self, logs: Vec<LogRecord>) -> Self {
+    match (self, logs.is_empty()) {
+      (err, true) => err,
+      (CompileErr::Logged(err, mut earlier_logs), false) => {
+        earlier_logs.extend(logs);
+        CompileErr::Logged(err, earlier_logs)
+      }
+      (err, false) => CompileErr::Logged(Box::new(err), logs),
+    }
+  }
+
+  /// The `summon.log` calls that ran before the error.
+  pub fn logs(&self) -> &[LogRecord] {
+    match self {
+      CompileErr::Logged(_, logs) => logs,
+      _ => &[],
+    }
+  }
+
+  /// Where the error happened, if it is known. Errors from running the program are located by the
+  /// declaration of the function they happened in (see `Location::source`).
+  pub fn span(&self) -> Option<&SourceSpan> {
+    let location = match self {
+      CompileErr::AssertionFailed { span, .. } => return span.as_ref(),
+      CompileErr::Logged(err, _) => return err.span(),
+      CompileErr::UncaughtException(uncaught) => uncaught
+        .throws
+        .iter()
+        .find_map(|(_, site)| site.as_ref()?.source.as_ref()),
+      CompileErr::NonConvergingLoop(non_converging_loop) => {
+        non_converging_loop.location.source.as_ref()
+      }
+      CompileErr::LimitExceeded(exceeded) => exceeded
+        .hot_spots
+        .first()
+        .and_then(|(location, _)| location.source.as_ref()),
+      CompileErr::Circuit(CircuitError::CouldNotMerge(failure)) => failure.location.as_ref(),
+      CompileErr::Circuit(CircuitError::RecursionLimit(failure)) => failure.location.as_ref(),
+      _ => None,
+    };
+
+    location.map(|location| &location.span)
+  }
+
+  /// Describes the error as diagnostics, with errors that aren't already diagnostics being
+  /// attributed to the file of their span, or the entry file if they don't have one.
+  pub fn into_diagnostics(
+    self,
+    entry_path: &ResolvedPath,
+  ) -> HashMap<ResolvedPath, Vec<Diagnostic>> {
+    match self {
+      CompileErr::Diagnostics(diagnostics) => return diagnostics,
+      CompileErr::Logged(err, _) => return err.into_diagnostics(entry_path),
+      _ => {}
+    }
+
+    let mut diagnostic = Diagnostic {
+      level: match self {
+        CompileErr::Internal(_) => DiagnosticLevel::InternalError,
+        _ => DiagnosticLevel::Error,
+      },
+      message: self.to_string(),
+      span: Default::default(),
+    };
+
+    let path = match self.span() {
+      Some(span) => {
+        diagnostic.span.lo.0 = span.start as u32;
+        diagnostic.span.hi.0 = span.end as u32;
+
+        ResolvedPath {
+          path: span.path.clone(),
+        }
+      }
+      None => entry_path.clone(),
+    };
+
+    HashMap::from([(path, vec![diagnostic])])
+  }
+}
+
+impl fmt::Display for CompileErr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CompileErr::Diagnostics(diagnostics) => {
+        let messages = diagnostics
+          .iter()
+          .flat_map(|(path, file_diagnostics)| {
+            file_diagnostics
+              .iter()
+              .map(move |d| format!("{}: {}: {}", path.path, d.level, d.message))
+          })
+          .collect::<Vec<_>>();
+
+        write!(f, "{}", messages.join("\n"))
+      }
+      CompileErr::EntryNotFound(message) => write!(f, "{}", message),
+      CompileErr::AssertionFailed { message, span: _ } => {
+        write!(f, "Assertion failed: {}", message)
+      }
+      CompileErr::UncaughtException(uncaught) => write!(f, "{}", uncaught),
+      CompileErr::Circuit(err) => write!(f, "{}", err),
+      CompileErr::LimitExceeded(exceeded) => write!(f, "{}", exceeded),
+      CompileErr::NonConvergingLoop(non_converging_loop) => write!(f, "{}", non_converging_loop),
+      CompileErr::Internal(message) => write!(f, "Internal error: {}", message),
+      CompileErr::Logged(err, _) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl std::error::Error for CompileErr {}
+
+impl From<CircuitError> for CompileErr {
+  fn from(err: CircuitError) -> Self {
+    match err {
+      CircuitError::Internal(message) => CompileErr::Internal(message),
+      err => CompileErr::Circuit(err),
+    }
+  }
+}
+
+impl fmt::Display for CircuitError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CircuitError::NonIntegerConstant(value) => {
+        write!(f, "Cannot use non-integer constant {} in a circuit", value)
+      }
+      CircuitError::UnsupportedValue(value) => {
+        write!(f, "Cannot include {} in a circuit", value)
+      }
+      CircuitError::IncompatibleTypes(left, right) => {
+        write!(
+          f,
+          "Incompatible signal types for logical operation: {} and {}",
+          left, right
+        )
+      }
+      CircuitError::SignalAsIndex => write!(f, "Cannot use a signal as an index"),
+      CircuitError::CouldNotMerge(failure) => write!(f, "{}", failure),
+      CircuitError::RecursionLimit(failure) => write!(f, "{}", failure),
+      CircuitError::SignalAsCondition => {
+        write!(
+          f,
+          "Cannot use a signal where a constant condition is required"
+        )
+      }
+      CircuitError::Internal(message) => write!(f, "Internal error: {}", message),
+    }
+  }
+}
+
+impl fmt::Display for MergeFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match (&self.variable, self.register) {
+      (Some(variable), Some(register)) => write!(
+        f,
+        "Could not merge `{}` (register %{}) from different branches: {}",
+        variable, register, self.reason
+      )?,
+      _ => write!(
+        f,
+        "Could not merge {} from different branches: {}",
+        self.target, self.reason
+      )?,
+    }
+
+    if let Some(fork_pos) = self.fork_pos {
+      match &self.fork_location {
+        Some(fork_location) => write!(f, " (branched at {}", fork_location)?,
+        None => write!(f, " (branched at bytecode position {}", fork_pos)?,
+      }
+
+      match (&self.merge_location, self.merge_pos) {
+        (Some(merge_location), _) => write!(f, ", merged at {})", merge_location)?,
+        (None, Some(merge_pos)) => write!(f, ", merged at bytecode position {})", merge_pos)?,
+        (None, None) => write!(f, ", merged on return)")?,
+      }
+    }
+
+    if let Some(fn_name) = &self.fn_name {
+      write!(f, " in {}", fn_name)?;
+
+      if let Some(location) = &self.location {
+        write!(f, " ({})", location)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl fmt::Display for RecursionLimitFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Maximum recursion depth of {} exceeded in {}",
+      self.max_depth, self.fn_name
+    )?;
+
+    if let Some(location) = &self.location {
+      write!(f, " at {}", location)?;
+    }
+
+    write!(f, " (call chain: {})", self.call_chain.join(" -> "))
+  }
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Exceeded the limit of {}", self.limit)?;
+
+    if !self.hot_spots.is_empty() {
+      write!(f, "\nBytecode positions with the most steps:")?;
+
+      for (location, steps) in &self.hot_spots {
+        write!(f, "\n  {}: {} steps", location, steps)?;
+      }
+    }
+
+    if !self.fork_sites.is_empty() {
+      write!(f, "\nUnmerged forks:")?;
+
+      for (location, branches) in &self.fork_sites {
+        write!(f, "\n  {}: {} branches", location, branches)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl fmt::Display for NonConvergingLoop {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let inputs = match self.inputs.as_slice() {
+      [] => "signals".to_string(),
+      inputs => inputs
+        .iter()
+        .map(|input| format!("`{}`", input))
+        .collect::<Vec<_>>()
+        .join(", "),
+    };
+
+    write!(
+      f,
+      "Loop does not converge: the condition at {} depends on {}, and forked {} times without \
+      merging. Add a bound that doesn't depend on signals (eg `i < 100 && i < input`), or raise \
+      the limit with --max-loop-forks.",
+      self.location, inputs, self.forks,
+    )
+  }
+}
+
+impl fmt::Display for UncaughtException {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let write_throw = |f: &mut fmt::Formatter<'_>, (message, site): &(String, Option<Location>)| {
+      write!(f, "{}", message)?;
+
+      match site {
+        Some(site) => write!(f, " (thrown at {})", site),
+        None => Ok(()),
+      }
+    };
+
+    match self.throws.as_slice() {
+      [throw] => {
+        write!(f, "Uncaught exception: ")?;
+        write_throw(f, throw)
+      }
+      throws => {
+        write!(f, "Every branch throws an uncaught exception:")?;
+
+        for throw in throws {
+          write!(f, "\n  ")?;
+          write_throw(f, throw)?;
+        }
+
+        Ok(())
+      }
+    }
+  }
+}
+
+impl fmt::Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Limit::Steps(steps) => write!(f, "{} VM steps", steps),
+      Limit::Branches(branches) => write!(f, "{} simultaneous branches", branches),
+      Limit::Signals(signals) => write!(f, "{} signals", signals),
+      Limit::Time(duration) => write!(f, "{:?} of running time", duration),
+    }
+  }
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} (", self.fn_name)?;
+
+    if let Some(source) = &self.source {
+      write!(f, "declared at {}, ", source)?;
+    }
+
+    write!(f, "bytecode position {})", self.pos)
+  }
+}
+
+impl fmt::Display for SourceLocation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}:{}", self.span.path, self.line, self.column)
+  }
+}
+
+impl CircuitError {
+  /// Wraps the error as an exception that the program can't catch.
+  pub fn to_val(self) -> Val {
+    FatalError(self).to_dynamic_val()
+  }
+
+  pub fn from_val(val: &Val) -> Option<&CircuitError> {
+    val_dynamic_downcast::<FatalError>(val).map(|fatal| &fatal.0)
+  }
+}
+
+thread_local! {
+  /// An error from a `ValTrait` method that can't return one, such as `is_truthy` on a signal.
+  static DEFERRED_ERROR: RefCell<Option<CircuitError>> = const { RefCell::new(None) };
+}
+
+/// Records an error for the VM to throw after the current step (see `take_deferred_error`). Only
+/// the first error is kept.
+pub fn defer_error(err: CircuitError) {
+  DEFERRED_ERROR.with(|deferred| {
+    deferred.borrow_mut().get_or_insert(err);
+  });
+}
+
+pub fn take_deferred_error() -> Option<CircuitError> {
+  DEFERRED_ERROR.with(|deferred| deferred.borrow_mut().take())
+}
+
+/// A `CircuitError` thrown inside the VM. Unlike other exceptions, these can't be caught.
+#[derive(Clone, Debug)]
+pub struct FatalError(pub CircuitError);
+
+impl ValTrait for FatalError {
+  fn typeof_(&self) -> VsType {
+    VsType::Object
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, _key: &Val) -> Result<Val, Val> {
+    Ok(Val::Undefined)
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    Some(false)
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign subscript of FatalError".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "\x1b[31mFatalError({})\x1b[39m", self.0)
+  }
+
+  fn codify(&self) -> String {
+    format!("FatalError({})", self.0)
+  }
+}
+
+impl fmt::Display for FatalError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
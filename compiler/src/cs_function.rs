@@ -84,6 +84,10 @@ impl CsFunction {
       return_target: None,
       catch_setting: None,
       fork_info: None,
+      intrinsic_call: None,
+      dispatch_fn: None,
+      fn_start: self.start,
+      fn_meta_pos: self.meta_pos,
     }
   }
 
@@ -0,0 +1,318 @@
+use std::{collections::HashMap, ops::Range, rc::Rc};
+
+use valuescript_compiler::asm;
+
+use crate::{
+  bytecode::Bytecode,
+  compile_error::{CircuitError, CompileErr, Location, SourceLocation, SourceSpan},
+  source_annotations::{find_function, is_ident_char, line_col, FoundFunction},
+  source_branches::{conditional_jumps, function_branches},
+};
+
+/**
+ * The bytecode doesn't have a source map, so errors from running it only know function names and
+ * bytecode positions. This recovers what it can from the assembly, the bytecode and the source
+ * files: the names of each function's registers, where each function is declared, and where its
+ * conditional jumps are (see `SourceBranch`).
+ *
+ * These are looked up by function name, so functions that share a name (including anonymous
+ * functions) aren't included.
+ */
+#[derive(Default)]
+pub struct DebugInfo {
+  /// The names of each function's registers, indexed by register.
+  register_names: HashMap<String, Vec<String>>,
+
+  /// Where each function is declared.
+  fn_locations: HashMap<String, SourceLocation>,
+
+  /// The conditions of the conditional jumps, by the bytecode position of the jump.
+  branch_sites: HashMap<usize, BranchSite>,
+}
+
+/// Where a conditional jump is in the source.
+struct BranchSite {
+  /// The condition's keyword or operator, eg `if`.
+  location: SourceLocation,
+
+  /// Where the branches meet again, eg the `}` that ends the `if` statement.
+  merge_location: SourceLocation,
+
+  /// The bytecode positions where the branches meet at `merge_location`: after the jumps inside
+  /// the condition's statement or expression, up to the next jump.
+  merge_positions: Range<usize>,
+}
+
+impl DebugInfo {
+  /// `sources` are the files that were compiled (path -> source), and `fn_starts` are where the
+  /// bytecode of each named function starts.
+  pub fn new(
+    module: &asm::Module,
+    sources: &HashMap<String, String>,
+    bytecode: &Rc<Bytecode>,
+    fn_starts: &HashMap<String, usize>,
+  ) -> Self {
+    let mut functions = HashMap::<String, Option<&asm::Function>>::new();
+
+    for definition in &module.definitions {
+      let function = match &definition.content {
+        asm::DefinitionContent::Function(function) => function,
+        _ => continue,
+      };
+
+      let meta = function.meta.as_ref().and_then(|meta| {
+        module
+          .definitions
+          .iter()
+          .find(|definition| &definition.pointer == meta)
+      });
+
+      let name = match meta.map(|meta| &meta.content) {
+        Some(asm::DefinitionContent::Meta(meta)) if !meta.name.is_empty() => meta.name.clone(),
+        _ => continue,
+      };
+
+      functions
+        .entry(name)
+        .and_modify(|function| *function = None)
+        .or_insert(Some(function));
+    }
+
+    let mut debug_info = DebugInfo::default();
+
+    for (name, function) in functions {
+      let function = match function {
+        Some(function) => function,
+        None => continue,
+      };
+
+      debug_info
+        .register_names
+        .insert(name.clone(), register_names(function));
+
+      let (path, source, found) = match find_declaration(sources, &name) {
+        Some(declaration) => declaration,
+        None => continue,
+      };
+
+      if let Some(start) = fn_starts.get(&name) {
+        debug_info.add_branch_sites(bytecode, *start, path, source, &found);
+      }
+
+      let location = source_location(path, source, found.start..found.start + "function".len());
+      debug_info.fn_locations.insert(name, location);
+    }
+
+    debug_info
+  }
+
+  /// Matches up the conditional jumps of a function with the conditions in its source, if there are
+  /// the same number of each.
+  fn add_branch_sites(
+    &mut self,
+    bytecode: &Rc<Bytecode>,
+    start: usize,
+    path: &str,
+    source: &str,
+    found: &FoundFunction,
+  ) {
+    let function_jumps = match conditional_jumps(bytecode, start) {
+      Some(function_jumps) => function_jumps,
+      None => return,
+    };
+
+    let jumps = function_jumps.jumps;
+    let branches = function_branches(source, found.open_paren);
+
+    if branches.len() != jumps.len() {
+      return;
+    }
+
+    for (k, branch) in branches.into_iter().enumerate() {
+      let last_nested = jumps[k + branch.nested];
+      let next = jumps.get(k + branch.nested + 1).copied();
+
+      self.branch_sites.insert(
+        jumps[k],
+        BranchSite {
+          location: source_location(path, source, branch.at),
+          merge_location: source_location(path, source, branch.end),
+          merge_positions: last_nested + 1..next.unwrap_or(function_jumps.end) + 1,
+        },
+      );
+    }
+  }
+
+  pub fn register_name(&self, fn_name: &str, register: usize) -> Option<&str> {
+    self
+      .register_names
+      .get(fn_name)?
+      .get(register)
+      .map(String::as_str)
+  }
+
+  pub fn fn_location(&self, fn_name: &str) -> Option<&SourceLocation> {
+    self.fn_locations.get(fn_name)
+  }
+
+  /// Adds the source locations and variable names that the error is missing.
+  pub fn annotate(&self, mut err: CompileErr) -> CompileErr {
+    match &mut err {
+      CompileErr::UncaughtException(uncaught) => {
+        for (_, site) in &mut uncaught.throws {
+          if let Some(site) = site {
+            self.locate(site);
+          }
+        }
+      }
+      CompileErr::Circuit(CircuitError::CouldNotMerge(failure)) => {
+        if let (Some(fn_name), Some(register)) = (&failure.fn_name, failure.register) {
+          failure.variable = self
+            .register_name(fn_name, register)
+            .map(|name| format!("{}{}", name, failure.path));
+        }
+
+        failure.location = failure
+          .fn_name
+          .as_ref()
+          .and_then(|fn_name| self.fn_location(fn_name).cloned());
+
+        let site = failure
+          .fork_pos
+          .and_then(|fork_pos| self.branch_sites.get(&fork_pos));
+
+        failure.fork_location = site.map(|site| site.location.clone());
+
+        failure.merge_location = site
+          .filter(|site| {
+            failure
+              .merge_pos
+              .is_some_and(|merge_pos| site.merge_positions.contains(&merge_pos))
+          })
+          .map(|site| site.merge_location.clone());
+      }
+      CompileErr::Circuit(CircuitError::RecursionLimit(failure)) => {
+        failure.location = self.fn_location(&failure.fn_name).cloned();
+      }
+      CompileErr::LimitExceeded(limit_exceeded) => {
+        for (location, _) in limit_exceeded
+          .hot_spots
+          .iter_mut()
+          .chain(limit_exceeded.fork_sites.iter_mut())
+        {
+          self.locate(location);
+        }
+      }
+      CompileErr::NonConvergingLoop(non_converging_loop) => {
+        self.locate(&mut non_converging_loop.location);
+      }
+      _ => {}
+    }
+
+    err
+  }
+
+  fn locate(&self, location: &mut Location) {
+    location.source = self.fn_location(&location.fn_name).cloned();
+  }
+}
+
+/**
+ * The names of the function's registers, in the order that the assembler numbers them: `return`,
+ * `this`, the parameters, and then the other registers in the order they first appear.
+ *
+ * This is read from the function's assembly text (eg `op+ %x %y %sum`), which lists the parameters
+ * first and then the instructions in order.
+ */
+fn register_names(function: &asm::Function) -> Vec<String> {
+  let text = function.to_string();
+  let mut names = vec!["return".to_string(), "this".to_string()];
+
+  for line in text.lines() {
+    if line.trim_start().starts_with("//") {
+      continue;
+    }
+
+    for name in line_registers(line) {
+      // `%ignore` discards the value, so it isn't a real register
+      if name != "ignore" && !names.iter().any(|existing| existing == name) {
+        names.push(name.to_string());
+      }
+    }
+  }
+
+  names
+}
+
+/// The register names (`%name`, or `%!name` when the value is taken) in a line of assembly,
+/// skipping string literals.
+fn line_registers(line: &str) -> Vec<&str> {
+  let mut registers = Vec::<&str>::new();
+  let mut chars = line.char_indices();
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '"' => {
+        while let Some((_, c)) = chars.next() {
+          match c {
+            '\\' => {
+              chars.next();
+            }
+            '"' => break,
+            _ => {}
+          }
+        }
+      }
+      '%' => {
+        let rest = &line[i + 1..];
+        let rest = rest.strip_prefix('!').unwrap_or(rest);
+        let len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+
+        if len > 0 {
+          registers.push(&rest[..len]);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  registers
+}
+
+/// Finds `function name(` in exactly one of the sources, returning the path and source along with
+/// the declaration.
+fn find_declaration<'a>(
+  sources: &'a HashMap<String, String>,
+  fn_name: &str,
+) -> Option<(&'a str, &'a str, FoundFunction)> {
+  let mut found = sources.iter().filter_map(|(path, source)| {
+    Some((
+      path.as_str(),
+      source.as_str(),
+      find_function(source, fn_name)?,
+    ))
+  });
+
+  let declaration = found.next()?;
+
+  match found.next() {
+    Some(_) => None,
+    None => Some(declaration),
+  }
+}
+
+/// The location of a byte range of the source.
+fn source_location(path: &str, source: &str, range: Range<usize>) -> SourceLocation {
+  let (line, column) = line_col(source, range.start);
+  let start = source[..range.start].chars().count();
+
+  SourceLocation {
+    span: SourceSpan {
+      path: path.to_string(),
+      start,
+      end: start + source[range].chars().count(),
+    },
+    line,
+    column,
+  }
+}
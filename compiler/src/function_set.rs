@@ -0,0 +1,162 @@
+use std::rc::Rc;
+
+use valuescript_vm::{
+  type_error_builtin::ToTypeError,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  LoadFunctionResult, ValTrait,
+};
+
+use crate::val_dynamic_downcast::val_dynamic_downcast;
+
+/**
+ * A function that depends on signals, created when different functions are merged:
+ *
+ * ```ts
+ * let op = add;
+ *
+ * if (x > y) {
+ *   op = mul; // op is a FunctionSet after the merge
+ * }
+ *
+ * op(x, y);
+ * ```
+ *
+ * Calling a `FunctionSet` forks the branch so that each candidate is called under its condition,
+ * and the results are merged afterwards. This also applies to methods of merged class instances,
+ * since their prototypes are merged key by key.
+ */
+#[derive(Clone)]
+pub struct FunctionSet {
+  pub candidates: Vec<Candidate>,
+}
+
+#[derive(Clone)]
+pub struct Candidate {
+  /// True when this is the function that was assigned. Exactly one candidate's condition is true
+  /// when the branch is taken.
+  pub cond: Val,
+
+  pub fn_: Val,
+}
+
+impl FunctionSet {
+  /// The candidates of `fn_`, which is a single candidate unless `fn_` is already a `FunctionSet`.
+  pub fn candidates_of(fn_: &Val) -> Vec<Candidate> {
+    match val_dynamic_downcast::<FunctionSet>(fn_) {
+      Some(function_set) => function_set.candidates.clone(),
+      None => vec![Candidate {
+        cond: true.to_val(),
+        fn_: fn_.clone(),
+      }],
+    }
+  }
+
+  /// Creates a function from candidates, avoiding `FunctionSet` when the choice is known.
+  pub fn from_candidates(candidates: Vec<Candidate>) -> Val {
+    let mut candidates = candidates
+      .into_iter()
+      .filter(|candidate| !matches!(candidate.cond, Val::Bool(false)))
+      .collect::<Vec<_>>();
+
+    if let Some(candidate) = candidates
+      .iter()
+      .find(|candidate| matches!(candidate.cond, Val::Bool(true)))
+    {
+      return candidate.fn_.clone();
+    }
+
+    match candidates.len() {
+      0 => Val::Undefined,
+      1 => candidates.remove(0).fn_,
+      _ => FunctionSet { candidates }.to_dynamic_val(),
+    }
+  }
+
+  /// The first candidate, and a function for the remaining candidates.
+  pub fn split_first(&self) -> (Candidate, Val) {
+    let mut rest = self.candidates.clone();
+    let first = rest.remove(0);
+
+    let rest = match rest.len() {
+      1 => rest.remove(0).fn_,
+      _ => FunctionSet { candidates: rest }.to_dynamic_val(),
+    };
+
+    (first, rest)
+  }
+}
+
+impl ValTrait for FunctionSet {
+  fn typeof_(&self) -> VsType {
+    VsType::Function
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    // Calls are dispatched by `BytecodeStackFrame` before this would be used
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, _key: &Val) -> Result<Val, Val> {
+    Ok(Val::Undefined)
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    Some(false)
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign to a signal-dependent function".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[Function set of {}]", self.candidates.len())
+  }
+
+  fn codify(&self) -> String {
+    format!("[Function set of {}]", self.candidates.len())
+  }
+}
+
+impl std::fmt::Display for FunctionSet {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[Function set]")
+  }
+}
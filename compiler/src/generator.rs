@@ -17,10 +17,19 @@ use valuescript_vm::{
   StackFrameTrait, ValTrait, VsSymbol, RETURN_THIS,
 };
 
+use crate::{
+  bytecode_stack_frame::BytecodeStackFrame,
+  function_set::{Candidate, FunctionSet},
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
 #[derive(Clone, Default)]
 pub struct Generator {
   frame: StackFrame,
   stack: Vec<StackFrame>,
+
+  /// Whether the generator is suspended at a `yield`, which receives the value passed to `next`.
+  suspended: bool,
 }
 
 impl Generator {
@@ -28,8 +37,56 @@ impl Generator {
     Generator {
       frame,
       stack: vec![],
+      suspended: false,
     }
   }
+
+  /// A generator that has finished by throwing. Like a generator that has returned, calling `next`
+  /// gives `{ value: undefined, done: true }`.
+  pub fn finished() -> Generator {
+    Generator::new(Box::new(FinishedFrame))
+  }
+
+  fn is_finished(&self) -> bool {
+    self.frame.as_any().is::<FinishedFrame>()
+  }
+
+  /// Merges generators that are suspended at the same point, using `merge_register` for each
+  /// register of their frames. Returns `None` if they are at different points, or if either is
+  /// delegating with `yield*`.
+  pub fn merge(
+    &self,
+    other: &Generator,
+    merge_register: impl Fn(usize, &Val, &Val) -> Result<Val, Val>,
+  ) -> Result<Option<Generator>, Val> {
+    if self.is_finished() && other.is_finished() {
+      return Ok(Some(self.clone()));
+    }
+
+    if self.suspended != other.suspended || !self.stack.is_empty() || !other.stack.is_empty() {
+      return Ok(None);
+    }
+
+    let (frame, other_frame) = match (
+      self.frame.as_any().downcast_ref::<BytecodeStackFrame>(),
+      other.frame.as_any().downcast_ref::<BytecodeStackFrame>(),
+    ) {
+      (Some(frame), Some(other_frame)) if frame.can_merge(other_frame) => (frame, other_frame),
+      _ => return Ok(None),
+    };
+
+    let mut merged = frame.clone();
+
+    merged.registers = (0..frame.registers.len())
+      .map(|i| merge_register(i, &frame.registers[i], &other_frame.registers[i]))
+      .collect::<Result<_, _>>()?;
+
+    Ok(Some(Generator {
+      frame: Box::new(merged),
+      stack: vec![],
+      suspended: self.suspended,
+    }))
+  }
 }
 
 impl ValTrait for Generator {
@@ -131,15 +188,80 @@ static NEXT: NativeFrameFunction = NativeFrameFunction {
   make_frame: || Box::<GeneratorFrame>::default(),
 };
 
+/**
+ * Runs a generator until its next `yield`. The generator's frames are stepped here rather than on
+ * the VM's stack, so the VM looks through this frame (see `bytecode_frame`) to fork and merge
+ * branches inside the generator, just like branches in ordinary functions.
+ */
 #[derive(Clone, Default)]
-struct GeneratorFrame {
+pub struct GeneratorFrame {
   generator: Generator,
+
+  /// Which candidate to advance when `next` is called on a `GeneratorSet`.
+  candidate: Option<usize>,
+
+  /// The value passed to `next`, which becomes the result of the `yield` expression.
+  sent: Option<Val>,
+
+  resumed: bool,
 }
 
-impl GeneratorFrame {}
+impl GeneratorFrame {
+  /// The generator's current frame, if it is running bytecode.
+  pub fn bytecode_frame(&self) -> Option<&BytecodeStackFrame> {
+    self.generator.frame.as_any().downcast_ref()
+  }
+
+  pub fn bytecode_frame_mut(&mut self) -> Option<&mut BytecodeStackFrame> {
+    self.generator.frame.as_any_mut().downcast_mut()
+  }
+
+  /// The number of frames below the generator's current frame (from `yield*`).
+  pub fn depth(&self) -> usize {
+    self.generator.stack.len()
+  }
+
+  /// A copy of this frame with `frame` as the generator's current frame, for forks inside the
+  /// generator.
+  pub fn with_frame(&self, frame: StackFrame) -> GeneratorFrame {
+    let mut new_frame = self.clone();
+    new_frame.generator.frame = frame;
+
+    new_frame
+  }
+
+  /// Passes the value given to `next` to the `yield` the generator is suspended at.
+  fn resume(&mut self) {
+    let sent = take(&mut self.sent).unwrap_or(Val::Undefined);
+
+    if !take(&mut self.generator.suspended) {
+      return;
+    }
+
+    // Values sent through `yield*` to other kinds of iterators are ignored
+    if let Some(frame) = self.bytecode_frame_mut() {
+      frame.apply_call_result(CallResult {
+        return_: sent,
+        this: Val::Undefined,
+      });
+    }
+  }
+}
 
 impl StackFrameTrait for GeneratorFrame {
   fn write_this(&mut self, const_: bool, this: Val) -> Result<(), Val> {
+    if let Some(candidate) = self.candidate {
+      if let Some(generator_set) = val_dynamic_downcast::<GeneratorSet>(&this) {
+        if const_ {
+          return Err("Cannot call Generator.next on a const generator".to_type_error());
+        }
+
+        self.generator = generator_set.candidates[candidate].generator.clone();
+
+        return Ok(());
+      }
+    }
+
     let mut dynamic = match this {
       Val::Dynamic(dynamic) => dynamic,
       _ => return Err("TODO: indirection".to_internal_error()),
@@ -159,15 +281,30 @@ impl StackFrameTrait for GeneratorFrame {
     Ok(())
   }
 
-  fn write_param(&mut self, _param: Val) {
-    panic!("TODO: results of yield expressions")
+  fn write_param(&mut self, param: Val) {
+    if self.sent.is_none() {
+      self.sent = Some(param);
+    }
   }
 
   fn step(&mut self) -> FrameStepResult {
+    if !self.resumed {
+      self.resumed = true;
+      self.resume();
+    }
+
     let fsr = self.generator.frame.step();
 
     match fsr {
-      Err(_) => fsr, // TODO: Stack unwind internal stack first
+      Err(mut exception) => {
+        if !self.can_catch_exception(&exception) {
+          return Err(exception);
+        }
+
+        self.catch_exception(&mut exception);
+
+        Ok(FrameStepOk::Continue)
+      }
       Ok(FrameStepOk::Continue) | Ok(FrameStepOk::Push(_)) => fsr,
       Ok(FrameStepOk::Pop(call_result)) => match self.generator.stack.pop() {
         Some(mut frame) => {
@@ -185,14 +322,18 @@ impl StackFrameTrait for GeneratorFrame {
           this: take(&mut self.generator).to_dynamic_val(),
         })),
       },
-      Ok(FrameStepOk::Yield(val)) => Ok(FrameStepOk::Pop(CallResult {
-        return_: IterationResult {
-          value: val,
-          done: false,
-        }
-        .to_dynamic_val(),
-        this: take(&mut self.generator).to_dynamic_val(),
-      })),
+      Ok(FrameStepOk::Yield(val)) => {
+        self.generator.suspended = true;
+
+        Ok(FrameStepOk::Pop(CallResult {
+          return_: IterationResult {
+            value: val,
+            done: false,
+          }
+          .to_dynamic_val(),
+          this: take(&mut self.generator).to_dynamic_val(),
+        }))
+      }
       Ok(FrameStepOk::YieldStar(iterable)) => {
         let make_iter = iterable.sub(&VsSymbol::ITERATOR.to_val())?;
 
@@ -230,9 +371,22 @@ impl StackFrameTrait for GeneratorFrame {
 
   fn can_catch_exception(&self, exception: &Val) -> bool {
     self.generator.frame.can_catch_exception(exception)
+      || self
+        .generator
+        .stack
+        .iter()
+        .any(|frame| frame.can_catch_exception(exception))
   }
 
+  /// Unwinds the generator's internal stack to the frame that catches the exception.
   fn catch_exception(&mut self, exception: &mut Val) {
+    while !self.generator.frame.can_catch_exception(exception) {
+      match self.generator.stack.pop() {
+        Some(frame) => self.generator.frame = frame,
+        None => return,
+      }
+    }
+
     self.generator.frame.catch_exception(exception)
   }
 
@@ -371,3 +525,293 @@ impl StackFrameTrait for YieldStarFrame {
     self
   }
 }
+
+/// The frame of a finished generator, which returns `undefined` every time it is run.
+#[derive(Clone)]
+struct FinishedFrame;
+
+impl StackFrameTrait for FinishedFrame {
+  fn write_this(&mut self, _const: bool, _this: Val) -> Result<(), Val> {
+    panic!("Not appropriate for FinishedFrame")
+  }
+
+  fn write_param(&mut self, _param: Val) {
+    panic!("Not appropriate for FinishedFrame")
+  }
+
+  fn step(&mut self) -> FrameStepResult {
+    Ok(FrameStepOk::Pop(CallResult {
+      return_: Val::Undefined,
+      this: Val::Undefined,
+    }))
+  }
+
+  fn apply_call_result(&mut self, _call_result: CallResult) {}
+
+  fn get_call_result(&mut self) -> CallResult {
+    panic!("Not appropriate for FinishedFrame")
+  }
+
+  fn can_catch_exception(&self, _exception: &Val) -> bool {
+    false
+  }
+
+  fn catch_exception(&mut self, _exception: &mut Val) {}
+
+  fn clone_to_stack_frame(&self) -> StackFrame {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn std::any::Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+    self
+  }
+}
+
+/**
+ * Generators from different branches that are suspended at different points, eg after
+ *
+ * ```ts
+ * if (x > 10) {
+ *   yield 10;
+ * } else {
+ *   yield x;
+ * }
+ * ```
+ *
+ * Calling `next` forks the branch so that each candidate is advanced under its condition (like
+ * `FunctionSet`), and the results are merged afterwards.
+ */
+#[derive(Clone)]
+pub struct GeneratorSet {
+  pub candidates: Vec<GeneratorCandidate>,
+}
+
+#[derive(Clone)]
+pub struct GeneratorCandidate {
+  pub cond: Val,
+  pub generator: Generator,
+}
+
+impl GeneratorSet {
+  /// The candidates of `val`, or `None` if it isn't a generator.
+  pub fn candidates_of(val: &Val) -> Option<Vec<GeneratorCandidate>> {
+    if let Some(generator_set) = val_dynamic_downcast::<GeneratorSet>(val) {
+      return Some(generator_set.candidates.clone());
+    }
+
+    val_dynamic_downcast::<Generator>(val).map(|generator| {
+      vec![GeneratorCandidate {
+        cond: true.to_val(),
+        generator: generator.clone(),
+      }]
+    })
+  }
+
+  /// Creates a generator from candidates, avoiding `GeneratorSet` when the choice is known.
+  pub fn from_candidates(candidates: Vec<GeneratorCandidate>) -> Val {
+    let mut candidates = candidates
+      .into_iter()
+      .filter(|candidate| !matches!(candidate.cond, Val::Bool(false)))
+      .collect::<Vec<_>>();
+
+    if let Some(candidate) = candidates
+      .iter()
+      .find(|candidate| matches!(candidate.cond, Val::Bool(true)))
+    {
+      return candidate.generator.clone().to_dynamic_val();
+    }
+
+    match candidates.len() {
+      0 => Val::Undefined,
+      1 => candidates.remove(0).generator.to_dynamic_val(),
+      _ => GeneratorSet { candidates }.to_dynamic_val(),
+    }
+  }
+}
+
+impl ValTrait for GeneratorSet {
+  fn typeof_(&self) -> VsType {
+    VsType::Object
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, key: &Val) -> Result<Val, Val> {
+    if key.to_string() == "next" {
+      return Ok(FunctionSet::from_candidates(
+        self
+          .candidates
+          .iter()
+          .enumerate()
+          .map(|(i, candidate)| Candidate {
+            cond: candidate.cond.clone(),
+            fn_: CandidateNext { candidate: i }.to_dynamic_val(),
+          })
+          .collect(),
+      ));
+    }
+
+    if let Val::Symbol(VsSymbol::ITERATOR) = key {
+      return Ok(RETURN_THIS.to_val());
+    }
+
+    Ok(Val::Undefined)
+  }
+
+  fn has(&self, key: &Val) -> Option<bool> {
+    if key.to_string() == "next" {
+      return Some(true);
+    }
+
+    if let Val::Symbol(VsSymbol::ITERATOR) = key {
+      return Some(true);
+    }
+
+    Some(false)
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign to subscript of a generator".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "\x1b[36m[Generator set of {}]\x1b[39m",
+      self.candidates.len()
+    )
+  }
+
+  fn codify(&self) -> String {
+    format!("GeneratorSet {{ [{} candidates] }}", self.candidates.len())
+  }
+}
+
+impl fmt::Display for GeneratorSet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "[object Generator]")
+  }
+}
+
+/// `next` for one of the candidates of a `GeneratorSet`.
+#[derive(Clone)]
+struct CandidateNext {
+  candidate: usize,
+}
+
+impl ValTrait for CandidateNext {
+  fn typeof_(&self) -> VsType {
+    VsType::Function
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::StackFrame(Box::new(GeneratorFrame {
+      candidate: Some(self.candidate),
+      ..Default::default()
+    }))
+  }
+
+  fn sub(&self, _key: &Val) -> Result<Val, Val> {
+    Ok(Val::Undefined)
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    Some(false)
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign to subscript of a function".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "\x1b[36m[Function: next]\x1b[39m")
+  }
+
+  fn codify(&self) -> String {
+    "function next() { [native code] }".to_string()
+  }
+}
+
+impl fmt::Display for CandidateNext {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "function next() {{ [native code] }}")
+  }
+}
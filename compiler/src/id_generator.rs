@@ -13,4 +13,9 @@ impl IdGenerator {
 
     res
   }
+
+  /// The number of ids generated so far.
+  pub fn count(&self) -> usize {
+    self.next_id
+  }
 }
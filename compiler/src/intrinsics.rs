@@ -0,0 +1,187 @@
+use std::{path::Path, rc::Rc};
+
+use valuescript_vm::{
+  type_error_builtin::ToTypeError,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, Val, VsType},
+  LoadFunctionResult, ValTrait,
+};
+
+/// Source of the module provided for `import summon from "summon"`.
+pub const SUMMON_MODULE_SOURCE: &str = include_str!("summon_module.ts");
+
+/// Prefix of the function names in `SUMMON_MODULE_SOURCE` that are replaced with intrinsics.
+const INTRINSIC_PREFIX: &str = "summon$";
+
+/// Suffix of the intrinsics that take the id of their call site as an extra first argument (see
+/// `CallSites`).
+const CALL_SITE_SUFFIX: &str = "At";
+
+pub fn is_summon_module_path(path: &str) -> bool {
+  Path::new(path)
+    .file_name()
+    .is_some_and(|name| name == "summon")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Intrinsic {
+  /// `summon.assert(cond, message)`: adds an output which is true when `cond` holds (or the call
+  /// is not reached).
+  Assert,
+
+  /// `summon.vec(capacity, initialValues?)`: creates an `ObliviousVec`.
+  Vec,
+
+  /// `summon.select(cond, a, b)`: `a` when `cond` is true, otherwise `b`, without branching.
+  Select,
+
+  /// `summon.log(...values)`: prints the values during compilation, describing signals by the
+  /// expressions that compute them.
+  Log,
+}
+
+impl Intrinsic {
+  fn from_name(name: &str) -> Option<Intrinsic> {
+    match name {
+      "assert" => Some(Intrinsic::Assert),
+      "vec" => Some(Intrinsic::Vec),
+      "select" => Some(Intrinsic::Select),
+      "log" => Some(Intrinsic::Log),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Intrinsic::Assert => "assert",
+      Intrinsic::Vec => "vec",
+      Intrinsic::Select => "select",
+      Intrinsic::Log => "log",
+    }
+  }
+}
+
+/// An intrinsic as a value. Calls to it are intercepted by `BytecodeStackFrame` and handled by
+/// `CircuitVM`, since they need access to the state of the branch.
+#[derive(Clone, Debug)]
+pub struct IntrinsicFn {
+  pub intrinsic: Intrinsic,
+
+  /// Whether the first argument is the id of the call site, eg `summon.assertAt(id, cond)`.
+  pub takes_call_site: bool,
+}
+
+impl IntrinsicFn {
+  pub fn from_fn_name(fn_name: &str) -> Option<IntrinsicFn> {
+    let name = fn_name.strip_prefix(INTRINSIC_PREFIX)?;
+
+    if let Some(intrinsic) = Intrinsic::from_name(name) {
+      return Some(IntrinsicFn {
+        intrinsic,
+        takes_call_site: false,
+      });
+    }
+
+    Some(IntrinsicFn {
+      intrinsic: Intrinsic::from_name(name.strip_suffix(CALL_SITE_SUFFIX)?)?,
+      takes_call_site: true,
+    })
+  }
+}
+
+/// A call to an intrinsic that the frame has decoded and is waiting for the VM to handle.
+#[derive(Clone)]
+pub struct IntrinsicCall {
+  pub intrinsic: Intrinsic,
+  pub args: Vec<Val>,
+  pub return_target: Option<usize>,
+
+  /// Bytecode position of the call.
+  pub pos: usize,
+
+  /// The id of the call's site in the source, if known (see `CallSites`).
+  pub call_site: Option<usize>,
+}
+
+impl ValTrait for IntrinsicFn {
+  fn typeof_(&self) -> VsType {
+    VsType::Function
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    // Calls are intercepted before loading the function, so this only happens when an intrinsic
+    // is used indirectly (eg via `.call`).
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, _key: &Val) -> Result<Val, Val> {
+    Ok(Val::Undefined)
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    Some(false)
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign to subscript of intrinsic".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "\x1b[36m[Intrinsic summon.{}]\x1b[39m",
+      self.intrinsic.name()
+    )
+  }
+
+  fn codify(&self) -> String {
+    format!("summon.{}", self.intrinsic.name())
+  }
+}
+
+impl std::fmt::Display for IntrinsicFn {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[intrinsic summon.{}]", self.intrinsic.name())
+  }
+}
+
+pub fn intrinsic_val(intrinsic_fn: IntrinsicFn) -> Val {
+  intrinsic_fn.to_dynamic_val()
+}
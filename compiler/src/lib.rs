@@ -1,29 +1,65 @@
 mod arithmetic_merge;
+mod array_methods;
+mod branch_scheduler;
 mod bristol_depth;
 mod bristol_op_strings;
 mod bytecode;
 mod bytecode_decoder;
 mod bytecode_stack_frame;
+mod call_sites;
 mod circuit;
 mod circuit_builder;
 mod circuit_signal;
 mod circuit_vm;
 mod circuit_vm_branch;
 mod compile;
+mod compile_error;
 mod cs_function;
+mod debug_info;
+mod function_set;
 mod generator;
 mod id_generator;
+mod intrinsics;
+mod loop_detector;
 mod make_generator_frame;
+mod oblivious_vec;
+mod optional_key;
+mod optional_signal;
+mod range_analysis;
 mod resolve_entry_path;
+mod source_annotations;
+mod source_branches;
+mod source_tokens;
+mod summon_log;
 mod tests;
 mod val_dynamic_downcast;
+mod waiting_branches;
 
+pub use branch_scheduler::SchedulingStrategy;
 pub use bristol_depth::bristol_depth;
+pub use circuit::Circuit;
 pub use compile::compile;
-pub use compile::CompileErr;
+pub use compile::compile_all_exports;
+pub use compile::compile_with_options;
+pub use compile::CompileAllOk;
+pub use compile::CompileLimits;
 pub use compile::CompileOk;
+pub use compile::CompileOptions;
 pub use compile::CompileResult;
+pub use compile_error::CircuitError;
+pub use compile_error::CompileErr;
+pub use compile_error::Limit;
+pub use compile_error::LimitExceeded;
+pub use compile_error::Location;
+pub use compile_error::MergeFailure;
+pub use compile_error::NonConvergingLoop;
+pub use compile_error::RecursionLimitFailure;
+pub use compile_error::SourceLocation;
+pub use compile_error::SourceSpan;
+pub use compile_error::UncaughtException;
+pub use range_analysis::ValueRange;
 pub use resolve_entry_path::resolve_entry_path;
+pub use summon_log::LogRecord;
 pub use valuescript_compiler::Diagnostic;
 pub use valuescript_compiler::DiagnosticLevel;
 pub use valuescript_compiler::ResolvedPath;
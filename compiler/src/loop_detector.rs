@@ -0,0 +1,99 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use valuescript_vm::vs_value::Val;
+
+use crate::{
+  circuit_signal::{CircuitSignal, CircuitSignalData},
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/// How many times the same fork can repeat (without merging, while the number of branches keeps
+/// growing) before its loop is reported as non-converging, unless set by
+/// `CompileLimits::max_loop_forks`. This is well above the constant bounds loops are expected to
+/// have, like the `2000` in `i < 2000 && i < input`, so that adding one fixes the loop.
+pub const DEFAULT_MAX_LOOP_FORKS: usize = 10_000;
+
+/**
+ * Detects loops bounded by signals, such as:
+ *
+ * ```ts
+ * for (let i = 0; i < input; i++) {
+ *   sum += i;
+ * }
+ * ```
+ *
+ * Each iteration forks at the loop condition, and the branch that leaves the loop waits for the
+ * branch that continues, which never finishes. So the same fork repeats, every repetition adds a
+ * branch, and none of the branches merge. Loops that do converge either merge their forks or stop
+ * repeating them, which resets the count.
+ *
+ * Only forks at loop conditions are counted (see `ForkInfo::is_loop_condition`). Long loops with a
+ * fixed bound also fork repeatedly when they `break` or `return` under a signal, but they finish.
+ */
+#[derive(Default)]
+pub struct LoopDetector {
+  /// Repeated forks by the position of the conditional jump.
+  streaks: HashMap<usize, Streak>,
+}
+
+struct Streak {
+  forks: usize,
+
+  /// The number of live branches after the latest fork.
+  branch_count: usize,
+}
+
+impl LoopDetector {
+  /// Records a fork at `pos`, returning the number of times it has repeated.
+  pub fn fork(&mut self, pos: usize, branch_count: usize) -> usize {
+    let streak = self.streaks.entry(pos).or_insert(Streak {
+      forks: 0,
+      branch_count: 0,
+    });
+
+    streak.forks = match branch_count > streak.branch_count {
+      true => streak.forks + 1,
+      false => 1,
+    };
+
+    streak.branch_count = branch_count;
+
+    streak.forks
+  }
+
+  /// Records that branches created by the fork at `pos` have merged.
+  pub fn merge(&mut self, pos: usize) {
+    self.streaks.remove(&pos);
+  }
+}
+
+/// The ids of the input signals that `val` depends on.
+pub fn input_dependencies(val: &Val) -> BTreeSet<usize> {
+  let mut inputs = BTreeSet::new();
+  let mut visited = HashSet::new();
+  let mut stack = vec![val.clone()];
+
+  while let Some(val) = stack.pop() {
+    let signal = match val_dynamic_downcast::<CircuitSignal>(&val) {
+      Some(signal) => signal,
+      None => continue,
+    };
+
+    if !visited.insert(signal.id) {
+      continue;
+    }
+
+    match &signal.data {
+      CircuitSignalData::Input => {
+        inputs.insert(signal.id);
+      }
+      CircuitSignalData::UnaryOp(_, input) => stack.push(input.clone()),
+      CircuitSignalData::BinaryOp(_, left, right) => {
+        stack.push(left.clone());
+        stack.push(right.clone());
+      }
+    }
+  }
+
+  inputs
+}
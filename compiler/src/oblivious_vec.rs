@@ -0,0 +1,372 @@
+use std::rc::Rc;
+
+use valuescript_vm::{
+  native_function::{native_fn, NativeFunction, ThisWrapper},
+  operations::{op_and, op_less, op_minus, op_mul, op_plus, op_triple_eq, op_unary_plus},
+  type_error_builtin::ToTypeError,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  LoadFunctionResult, ValTrait,
+};
+
+use crate::{circuit_signal::CircuitSignal, val_dynamic_downcast::val_dynamic_downcast};
+
+/**
+ * A vector with a fixed capacity and a length which can be a signal, created with
+ * `summon.vec(capacity, initialValues?)`.
+ *
+ * Regular arrays can't be merged after a signal-dependent branch changes their length:
+ *
+ * ```ts
+ * if (x > 10) {
+ *   arr.push(x); // arr has a different length in each branch
+ * }
+ * ```
+ *
+ * An `ObliviousVec` always has `capacity` slots, so the branches can be merged slot by slot, and
+ * the length becomes a signal. Slots at or after the length always hold `0`.
+ */
+#[derive(Clone)]
+pub struct ObliviousVec {
+  pub slots: Vec<Val>,
+  pub len: Val,
+}
+
+impl ObliviousVec {
+  pub fn new(args: &[Val]) -> Result<ObliviousVec, Val> {
+    let capacity = match args.first() {
+      Some(Val::Number(capacity)) if *capacity >= 0.0 && *capacity == capacity.trunc() => {
+        *capacity as usize
+      }
+      _ => return Err("summon.vec requires a constant capacity".to_type_error()),
+    };
+
+    let initial_values = match args.get(1) {
+      None | Some(Val::Undefined) => vec![],
+      Some(Val::Array(array)) => array.elements.clone(),
+      Some(_) => return Err("summon.vec initial values must be an array".to_type_error()),
+    };
+
+    if initial_values.len() > capacity {
+      return Err(
+        format!(
+          "summon.vec has {} initial values, which exceeds its capacity ({})",
+          initial_values.len(),
+          capacity
+        )
+        .to_type_error(),
+      );
+    }
+
+    let len = initial_values.len();
+    let mut slots = initial_values;
+    slots.resize(capacity, 0.0.to_val());
+
+    Ok(ObliviousVec {
+      slots,
+      len: (len as f64).to_val(),
+    })
+  }
+
+  /**
+   * A vector of the `values` whose flag in `keep` is true, in their original order.
+   *
+   * This is an oblivious compaction: each value's destination is the number of kept values before
+   * it, which is at most its own index, so slot `k` only needs to consider values `k..`.
+   */
+  pub fn compact(values: &[Val], keep: &[Val]) -> Result<ObliviousVec, Val> {
+    let mut slots = vec![0.0.to_val(); values.len()];
+    let mut len = 0.0.to_val();
+
+    for (i, (value, keep)) in values.iter().zip(keep).enumerate() {
+      check_element(value)?;
+
+      for (k, slot) in slots.iter_mut().enumerate().take(i + 1) {
+        let is_dest = op_and(keep, &op_triple_eq(&len, &(k as f64).to_val())?)?;
+        *slot = select(&is_dest, value, slot)?;
+      }
+
+      len = op_plus(&len, &op_unary_plus(keep)?)?;
+    }
+
+    Ok(ObliviousVec { slots, len })
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.slots.len()
+  }
+
+  /// When the length is a signal, pushing to a full vector does nothing.
+  pub fn push(&mut self, value: Val) -> Result<(), Val> {
+    let len = match self.len {
+      Val::Number(len) => len as usize,
+      _ => {
+        check_element(&value)?;
+
+        for i in 0..self.capacity() {
+          let is_end = op_triple_eq(&self.len, &(i as f64).to_val())?;
+          self.slots[i] = select(&is_end, &value, &self.slots[i])?;
+        }
+
+        let not_full = op_less(&self.len, &(self.capacity() as f64).to_val())?;
+        self.len = op_plus(&self.len, &not_full)?;
+
+        return Ok(());
+      }
+    };
+
+    if len >= self.capacity() {
+      return Err(format!("summon.vec capacity ({}) exceeded", self.capacity()).to_type_error());
+    }
+
+    self.slots[len] = value;
+    self.len = ((len + 1) as f64).to_val();
+
+    Ok(())
+  }
+
+  /// Removes the last element and returns it. If the vector is empty, this returns `undefined`
+  /// (or `0` when the length is a signal).
+  pub fn pop(&mut self) -> Result<Val, Val> {
+    let len = match self.len {
+      Val::Number(len) => len as usize,
+      _ => {
+        let mut res = 0.0.to_val();
+
+        for i in 0..self.capacity() {
+          check_element(&self.slots[i])?;
+
+          let is_last = op_triple_eq(&self.len, &((i + 1) as f64).to_val())?;
+          res = select(&is_last, &self.slots[i], &res)?;
+          self.slots[i] = select(&is_last, &0.0.to_val(), &self.slots[i])?;
+        }
+
+        let not_empty = op_less(&0.0.to_val(), &self.len)?;
+        self.len = op_minus(&self.len, &not_empty)?;
+
+        return Ok(res);
+      }
+    };
+
+    if len == 0 {
+      return Ok(Val::Undefined);
+    }
+
+    let res = std::mem::replace(&mut self.slots[len - 1], 0.0.to_val());
+    self.len = ((len - 1) as f64).to_val();
+
+    Ok(res)
+  }
+
+  /// Assigns an existing element. When the length is a signal, assignments at or after the length
+  /// do nothing.
+  pub fn set(&mut self, key: &Val, value: Val) -> Result<(), Val> {
+    let i = self.slot_index(key)?;
+
+    match self.len {
+      Val::Number(len) => {
+        if i >= len as usize {
+          return Err(
+            format!(
+              "Cannot assign index {} of summon.vec with length {}",
+              i, len
+            )
+            .to_type_error(),
+          );
+        }
+
+        self.slots[i] = value;
+      }
+      _ => {
+        check_element(&value)?;
+
+        let in_range = op_less(&(i as f64).to_val(), &self.len)?;
+        self.slots[i] = select(&in_range, &value, &self.slots[i])?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Each slot as `[valid, value]`, where `valid` is whether the slot is before the length. This is
+  /// how you iterate over the vector:
+  ///
+  /// ```ts
+  /// for (const [valid, value] of vec.slots()) {
+  ///   if (valid) { ... }
+  /// }
+  /// ```
+  pub fn masked_slots(&self) -> Result<Val, Val> {
+    let mut res = Vec::<Val>::new();
+
+    for (i, slot) in self.slots.iter().enumerate() {
+      let valid = op_less(&(i as f64).to_val(), &self.len)?;
+      res.push(vec![valid, slot.clone()].to_val());
+    }
+
+    Ok(res.to_val())
+  }
+
+  fn slot_index(&self, key: &Val) -> Result<usize, Val> {
+    match key.to_index() {
+      Some(i) if i < self.capacity() => Ok(i),
+      _ => Err(
+        format!(
+          "Invalid index {} for summon.vec with capacity {}",
+          key.codify(),
+          self.capacity()
+        )
+        .to_type_error(),
+      ),
+    }
+  }
+}
+
+/// `if_true` when `cond` is true, otherwise `if_false`. `cond` is a boolean signal.
+fn select(cond: &Val, if_true: &Val, if_false: &Val) -> Result<Val, Val> {
+  // if_false + cond * (if_true - if_false)
+  op_plus(if_false, &op_mul(cond, &op_minus(if_true, if_false)?)?)
+}
+
+/// Elements are combined arithmetically when the length is a signal, so they need to be numbers.
+fn check_element(value: &Val) -> Result<(), Val> {
+  let is_number = match value {
+    Val::Number(_) | Val::Bool(_) => true,
+    Val::Dynamic(_) => val_dynamic_downcast::<CircuitSignal>(value).is_some(),
+    _ => false,
+  };
+
+  match is_number {
+    true => Ok(()),
+    false => Err(
+      format!(
+        "summon.vec elements must be numbers when the length is a signal (got {})",
+        value.codify()
+      )
+      .to_type_error(),
+    ),
+  }
+}
+
+fn this_vec(this: &ThisWrapper) -> Result<ObliviousVec, Val> {
+  match val_dynamic_downcast::<ObliviousVec>(this.get()) {
+    Some(vec) => Ok(vec.clone()),
+    None => Err("Expected summon.vec".to_type_error()),
+  }
+}
+
+static PUSH: NativeFunction = native_fn(|mut this, params| {
+  let mut vec = this_vec(&this)?;
+
+  for param in params {
+    vec.push(param)?;
+  }
+
+  let len = vec.len.clone();
+  *this.get_mut()? = vec.to_dynamic_val();
+
+  Ok(len)
+});
+
+static POP: NativeFunction = native_fn(|mut this, _params| {
+  let mut vec = this_vec(&this)?;
+  let res = vec.pop()?;
+  *this.get_mut()? = vec.to_dynamic_val();
+
+  Ok(res)
+});
+
+static SLOTS: NativeFunction = native_fn(|this, _params| this_vec(&this)?.masked_slots());
+
+impl ValTrait for ObliviousVec {
+  fn typeof_(&self) -> VsType {
+    VsType::Object
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    false
+  }
+
+  fn is_truthy(&self) -> bool {
+    true
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, key: &Val) -> Result<Val, Val> {
+    if let Val::String(key) = key {
+      return Ok(match &**key {
+        "length" => self.len.clone(),
+        "capacity" => (self.capacity() as f64).to_val(),
+        "push" => Val::Static(&PUSH),
+        "pop" => Val::Static(&POP),
+        "slots" => Val::Static(&SLOTS),
+        _ => Val::Undefined,
+      });
+    }
+
+    Ok(self.slots[self.slot_index(key)?].clone())
+  }
+
+  fn has(&self, key: &Val) -> Option<bool> {
+    Some(key.to_index().is_some_and(|i| i < self.capacity()))
+  }
+
+  fn submov(&mut self, key: &Val, value: Val) -> Result<(), Val> {
+    self.set(key, value)
+  }
+
+  fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "summon.vec({}, {})",
+      self.capacity(),
+      self.slots.clone().to_val().pretty()
+    )
+  }
+
+  fn codify(&self) -> String {
+    format!(
+      "summon.vec({}, {})",
+      self.capacity(),
+      self.slots.clone().to_val().codify()
+    )
+  }
+}
+
+impl std::fmt::Display for ObliviousVec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[summon.vec]")
+  }
+}
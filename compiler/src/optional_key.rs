@@ -0,0 +1,219 @@
+use std::rc::Rc;
+
+use valuescript_common::BuiltinName;
+use valuescript_vm::{
+  internal_error_builtin::ToInternalError,
+  native_function::{native_fn, NativeFunction, ThisWrapper},
+  type_error_builtin::ToTypeError,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  LoadFunctionResult, ValTrait, BUILTIN_VALS,
+};
+
+use crate::{
+  compile_error::{defer_error, CircuitError},
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/**
+ * An object property that is only present in some branches:
+ *
+ * ```ts
+ * const result: { max?: number } = {};
+ *
+ * if (x > y) {
+ *   result.max = x; // result.max is an OptionalKey after the merge
+ * }
+ * ```
+ *
+ * `is_present` is what `"max" in result` gives, and `value` is what reading `result.max` gives (an
+ * optional value which is undefined when the key isn't present). These are different when a
+ * branch sets the property to `undefined`.
+ *
+ * These are only stored in objects. Reading the property gives `value` (see `read_property`).
+ */
+#[derive(Clone)]
+pub struct OptionalKey {
+  pub is_present: Val,
+  pub value: Val,
+}
+
+impl OptionalKey {
+  /// Creates a property value, avoiding `OptionalKey` when the key is known to be present.
+  pub fn new_val(is_present: Val, value: Val) -> Val {
+    match is_present {
+      Val::Bool(true) => value,
+      _ => OptionalKey { is_present, value }.to_dynamic_val(),
+    }
+  }
+
+  /// Whether the property is present, and its value (`undefined` when it isn't).
+  pub fn parts(property: Option<&Val>) -> (Val, Val) {
+    let property = match property {
+      Some(property) => property,
+      None => return (false.to_val(), Val::Undefined),
+    };
+
+    match val_dynamic_downcast::<OptionalKey>(property) {
+      Some(optional_key) => (optional_key.is_present.clone(), optional_key.value.clone()),
+      None => (true.to_val(), property.clone()),
+    }
+  }
+}
+
+/// The value of a property read from an object.
+pub fn read_property(property: Val) -> Val {
+  match val_dynamic_downcast::<OptionalKey>(&property) {
+    Some(optional_key) => optional_key.value.clone(),
+    None => property,
+  }
+}
+
+/// `key in object`, which is a signal when the key is only present in some branches.
+pub fn op_in_optional_key(key: &Val, object: &Val) -> Result<Val, Val> {
+  if let (Val::String(key), Val::Object(object)) = (key, object) {
+    if let Some(optional_key) = object
+      .string_map
+      .get(&**key)
+      .and_then(val_dynamic_downcast::<OptionalKey>)
+    {
+      return Ok(optional_key.is_present.clone());
+    }
+  }
+
+  valuescript_vm::operations::op_in(key, object)
+}
+
+/// Replaces `Object.keys`, `Object.values` and `Object.entries`, which can't list keys that are
+/// only present in some branches, since the length of the result would depend on a signal.
+pub fn object_method(object_builtin: &Val, key: &Val) -> Option<Val> {
+  let is_object_builtin = match (object_builtin, BUILTIN_VALS[BuiltinName::Object as usize]()) {
+    (Val::Static(val), Val::Static(object)) => std::ptr::addr_eq(*val, object),
+    _ => false,
+  };
+
+  if !is_object_builtin {
+    return None;
+  }
+
+  let key = match key {
+    Val::String(key) => key,
+    _ => return None,
+  };
+
+  Some(match &**key {
+    "keys" => Val::Static(&KEYS),
+    "values" => Val::Static(&VALUES),
+    "entries" => Val::Static(&ENTRIES),
+    _ => return None,
+  })
+}
+
+static KEYS: NativeFunction = native_fn(|this, params| list_properties("keys", this, params));
+static VALUES: NativeFunction = native_fn(|this, params| list_properties("values", this, params));
+static ENTRIES: NativeFunction = native_fn(|this, params| list_properties("entries", this, params));
+
+fn list_properties(method: &str, this: ThisWrapper, params: Vec<Val>) -> Result<Val, Val> {
+  if let Some(Val::Object(object)) = params.first() {
+    let optional_key = object
+      .string_map
+      .iter()
+      .find(|(_, value)| val_dynamic_downcast::<OptionalKey>(value).is_some());
+
+    if let Some((key, _)) = optional_key {
+      return Err(
+        format!(
+          "Object.{} can't list the key \"{}\" because it is only present in some branches (use \
+          `in` to check for it)",
+          method, key,
+        )
+        .to_type_error(),
+      );
+    }
+  }
+
+  let builtin = BUILTIN_VALS[BuiltinName::Object as usize]().sub(&method.to_val())?;
+
+  match builtin.load_function() {
+    LoadFunctionResult::NativeFunction(native_fn) => native_fn(this, params),
+    _ => Err(format!("Object.{} is not a native function", method).to_internal_error()),
+  }
+}
+
+impl ValTrait for OptionalKey {
+  fn typeof_(&self) -> VsType {
+    self.value.typeof_()
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    self.value.is_primitive()
+  }
+
+  fn is_truthy(&self) -> bool {
+    // Properties are converted using `read_property`, so this shouldn't be reached
+    defer_error(CircuitError::Internal(
+      "Optional key was used without reading its value".to_string(),
+    ));
+
+    false
+  }
+
+  fn is_nullish(&self) -> bool {
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, key: &Val) -> Result<Val, Val> {
+    self.value.sub(key)
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    None
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign a property of a possibly-missing property".to_type_error())
+  }
+
+  fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "OptionalKey({})", self.value.pretty())
+  }
+
+  fn codify(&self) -> String {
+    format!("OptionalKey({})", self.value.codify())
+  }
+}
+
+impl std::fmt::Display for OptionalKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[OptionalKey]")
+  }
+}
@@ -0,0 +1,433 @@
+use std::rc::Rc;
+
+use valuescript_vm::{
+  binary_op::BinaryOp,
+  operations::{
+    op_and, op_bit_and, op_bit_not, op_bit_or, op_bit_xor, op_div, op_eq, op_exp, op_greater,
+    op_greater_eq, op_left_shift, op_less, op_less_eq, op_minus, op_mod, op_mul, op_ne, op_not,
+    op_or, op_plus, op_right_shift, op_right_shift_unsigned, op_sub, op_triple_eq, op_triple_ne,
+    op_unary_minus, op_unary_plus,
+  },
+  type_error_builtin::ToTypeError,
+  unary_op::UnaryOp,
+  vs_array::VsArray,
+  vs_class::VsClass,
+  vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  LoadFunctionResult, ValTrait,
+};
+
+use crate::{
+  arithmetic_merge::select,
+  circuit_signal::CircuitSignal,
+  compile_error::{defer_error, CircuitError},
+  optional_key::read_property,
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/**
+ * A value that is only defined when `is_defined` is true, otherwise it is `nullish` (`undefined`
+ * or `null`).
+ *
+ * These are created when a nullish value is merged with a defined value:
+ *
+ * ```ts
+ * let best;
+ *
+ * if (x > y) {
+ *   best = x; // best is an OptionalSignal after the merge
+ * }
+ * ```
+ *
+ * The payload is zero (or the zero-like equivalent for arrays and objects) when `is_defined` is
+ * false.
+ */
+#[derive(Clone)]
+pub struct OptionalSignal {
+  pub is_defined: Val,
+  pub value: Val,
+  pub nullish: Val,
+}
+
+impl OptionalSignal {
+  /// Creates an optional value, avoiding `OptionalSignal` when `is_defined` is known and avoiding
+  /// nesting when `value` is also optional.
+  pub fn new_val(is_defined: Val, value: Val, nullish: Val) -> Result<Val, Val> {
+    if let Some(inner) = val_dynamic_downcast::<OptionalSignal>(&value) {
+      let is_defined = op_and(&is_defined, &inner.is_defined)?;
+      return OptionalSignal::new_val(is_defined, inner.value.clone(), nullish);
+    }
+
+    Ok(match is_defined {
+      Val::Bool(true) => value,
+      Val::Bool(false) => nullish,
+      _ => OptionalSignal {
+        is_defined,
+        value,
+        nullish,
+      }
+      .to_dynamic_val(),
+    })
+  }
+
+  /// A boolean signal for whether the value is truthy, like `if (value)`.
+  pub fn truthiness(&self) -> Result<Val, Val> {
+    op_and(&self.is_defined, &truthiness(&self.value)?)
+  }
+
+  /// The payload, zeroed when the value isn't defined.
+  pub fn masked_value(&self) -> Result<Val, Val> {
+    select(&self.is_defined, &self.value, &zero_like(&self.value))
+  }
+}
+
+/// Whether `val` is truthy, as a boolean (a signal if `val` depends on signals).
+pub fn truthiness(val: &Val) -> Result<Val, Val> {
+  if let Some(optional) = val_dynamic_downcast::<OptionalSignal>(val) {
+    return optional.truthiness();
+  }
+
+  Ok(match val_dynamic_downcast::<CircuitSignal>(val) {
+    Some(signal) if signal.type_ == VsType::Bool => val.clone(),
+    Some(_) => op_not(&op_not(val)?)?,
+    None => val.is_truthy().to_val(),
+  })
+}
+
+/// A value with the same structure as `val`, with numbers and booleans replaced by zero / false.
+/// This is the payload of undefined optionals.
+pub fn zero_like(val: &Val) -> Val {
+  match val {
+    Val::Bool(_) => false.to_val(),
+    Val::Number(_) => 0.0.to_val(),
+    Val::Array(array) => array
+      .elements
+      .iter()
+      .map(zero_like)
+      .collect::<Vec<_>>()
+      .to_val(),
+    Val::Object(object) => {
+      let mut object = (**object).clone();
+
+      for value in object.string_map.values_mut() {
+        *value = zero_like(value);
+      }
+
+      for value in object.symbol_map.values_mut() {
+        *value = zero_like(value);
+      }
+
+      object.to_val()
+    }
+    Val::Dynamic(_) => match val_dynamic_downcast::<CircuitSignal>(val) {
+      Some(signal) if signal.type_ == VsType::Bool => false.to_val(),
+      Some(_) => 0.0.to_val(),
+      None => val.clone(),
+    },
+    _ => val.clone(),
+  }
+}
+
+/// `undefined` or `null`, treating missing values (`Val::Void`) as `undefined`.
+pub fn nullish_kind(val: &Val) -> Val {
+  match val {
+    Val::Null => Val::Null,
+    _ => Val::Undefined,
+  }
+}
+
+/// An operand of a binary operation involving an `OptionalSignal`.
+enum Operand<'a> {
+  Nullish(&'a Val),
+  Optional(&'a OptionalSignal),
+  Defined(&'a Val),
+}
+
+impl<'a> Operand<'a> {
+  fn new(val: &'a Val) -> Operand<'a> {
+    if let Some(optional) = val_dynamic_downcast::<OptionalSignal>(val) {
+      return Operand::Optional(optional);
+    }
+
+    match val {
+      Val::Void | Val::Undefined | Val::Null => Operand::Nullish(val),
+      _ => Operand::Defined(val),
+    }
+  }
+
+  fn is_defined(&self) -> Val {
+    match self {
+      Operand::Nullish(_) => false.to_val(),
+      Operand::Optional(optional) => optional.is_defined.clone(),
+      Operand::Defined(_) => true.to_val(),
+    }
+  }
+
+  fn value(&self) -> &Val {
+    match self {
+      Operand::Nullish(val) => val,
+      Operand::Optional(optional) => &optional.value,
+      Operand::Defined(val) => val,
+    }
+  }
+
+  fn nullish(&self) -> Option<Val> {
+    match self {
+      Operand::Nullish(val) => Some(nullish_kind(val)),
+      Operand::Optional(optional) => Some(nullish_kind(&optional.nullish)),
+      Operand::Defined(_) => None,
+    }
+  }
+}
+
+/**
+ * Applies a binary operation where at least one operand is an `OptionalSignal`. Returns `None`
+ * when neither operand is optional.
+ *
+ * - Comparisons with `undefined` / `null` check `is_defined`
+ * - Other comparisons are false when an operand isn't defined (like `undefined < 3` in JS)
+ * - Everything else is applied to the payloads, and the result is only defined when the operands
+ *   are (like `undefined + 1` being `NaN` in JS)
+ */
+pub fn override_binary_op(op: BinaryOp, left: &Val, right: &Val) -> Option<Result<Val, Val>> {
+  if val_dynamic_downcast::<OptionalSignal>(left).is_none()
+    && val_dynamic_downcast::<OptionalSignal>(right).is_none()
+  {
+    return None;
+  }
+
+  Some(apply_binary_op(op, Operand::new(left), Operand::new(right)))
+}
+
+fn apply_binary_op(op: BinaryOp, left: Operand, right: Operand) -> Result<Val, Val> {
+  match op {
+    BinaryOp::Eq | BinaryOp::LooseEq => optional_eq(op == BinaryOp::LooseEq, &left, &right),
+    BinaryOp::Ne | BinaryOp::LooseNe => {
+      op_not(&optional_eq(op == BinaryOp::LooseNe, &left, &right)?)
+    }
+    BinaryOp::Less | BinaryOp::LessEq | BinaryOp::Greater | BinaryOp::GreaterEq => {
+      if matches!(left, Operand::Nullish(_)) || matches!(right, Operand::Nullish(_)) {
+        return Ok(false.to_val());
+      }
+
+      let both_defined = op_and(&left.is_defined(), &right.is_defined())?;
+      op_and(
+        &both_defined,
+        &binary_op_fn(op)(left.value(), right.value())?,
+      )
+    }
+    _ => {
+      let nullish = match (left.nullish(), right.nullish()) {
+        (Some(nullish), _) | (None, Some(nullish)) => nullish,
+        (None, None) => Val::Undefined,
+      };
+
+      if matches!(left, Operand::Nullish(_)) || matches!(right, Operand::Nullish(_)) {
+        return Err(
+          format!(
+            "Cannot combine a possibly-undefined value with {}",
+            nullish.codify()
+          )
+          .to_type_error(),
+        );
+      }
+
+      let both_defined = op_and(&left.is_defined(), &right.is_defined())?;
+      let value = binary_op_fn(op)(left.value(), right.value())?;
+
+      OptionalSignal::new_val(both_defined, value, nullish)
+    }
+  }
+}
+
+/// `left === right` (or `left == right` when `loose`).
+fn optional_eq(loose: bool, left: &Operand, right: &Operand) -> Result<Val, Val> {
+  let nullish_eq = match (left.nullish(), right.nullish()) {
+    (Some(left), Some(right)) => loose || op_triple_eq(&left, &right)?.is_truthy(),
+    _ => false,
+  };
+
+  let both_nullish = op_and(&op_not(&left.is_defined())?, &op_not(&right.is_defined())?)?;
+  let both_nullish = op_and(&nullish_eq.to_val(), &both_nullish)?;
+
+  if matches!(left, Operand::Nullish(_)) || matches!(right, Operand::Nullish(_)) {
+    return Ok(both_nullish);
+  }
+
+  let eq = match loose {
+    true => op_eq(left.value(), right.value())?,
+    false => op_triple_eq(left.value(), right.value())?,
+  };
+
+  let both_defined_and_eq = op_and(&op_and(&left.is_defined(), &right.is_defined())?, &eq)?;
+
+  op_or(&both_nullish, &both_defined_and_eq)
+}
+
+fn binary_op_fn(op: BinaryOp) -> fn(&Val, &Val) -> Result<Val, Val> {
+  match op {
+    BinaryOp::Plus => op_plus,
+    BinaryOp::Minus => op_minus,
+    BinaryOp::Mul => op_mul,
+    BinaryOp::Div => op_div,
+    BinaryOp::Mod => op_mod,
+    BinaryOp::Exp => op_exp,
+    BinaryOp::LooseEq => op_eq,
+    BinaryOp::LooseNe => op_ne,
+    BinaryOp::Eq => op_triple_eq,
+    BinaryOp::Ne => op_triple_ne,
+    BinaryOp::And => op_and,
+    BinaryOp::Or => op_or,
+    BinaryOp::Less => op_less,
+    BinaryOp::LessEq => op_less_eq,
+    BinaryOp::Greater => op_greater,
+    BinaryOp::GreaterEq => op_greater_eq,
+    BinaryOp::BitAnd => op_bit_and,
+    BinaryOp::BitOr => op_bit_or,
+    BinaryOp::BitXor => op_bit_xor,
+    BinaryOp::LeftShift => op_left_shift,
+    BinaryOp::RightShift => op_right_shift,
+    BinaryOp::RightShiftUnsigned => op_right_shift_unsigned,
+  }
+}
+
+/// `left ?? right`
+pub fn op_nullish_coalesce_optional(left: &Val, right: &Val) -> Result<Val, Val> {
+  match val_dynamic_downcast::<OptionalSignal>(left) {
+    Some(optional) => select(&optional.is_defined, &optional.value, right),
+    None => valuescript_vm::operations::op_nullish_coalesce(left, right),
+  }
+}
+
+/// `typeof input`. The type of an optional value depends on whether it is defined, which is a
+/// signal, so this is an error.
+pub fn op_typeof_optional(input: &Val) -> Result<Val, Val> {
+  match val_dynamic_downcast::<OptionalSignal>(input) {
+    Some(_) => Err(
+      "Cannot use typeof on a value that is only undefined in some branches (compare it with \
+      undefined instead)"
+        .to_type_error(),
+    ),
+    None => valuescript_vm::operations::op_typeof(input),
+  }
+}
+
+/// `left?.[right]`
+pub fn op_optional_chain_optional(left: &mut Val, right: &Val) -> Result<Val, Val> {
+  match val_dynamic_downcast::<OptionalSignal>(left) {
+    Some(optional) => {
+      let mut value = optional.value.clone();
+      let sub_value = read_property(op_sub(&mut value, right)?);
+
+      OptionalSignal::new_val(optional.is_defined.clone(), sub_value, Val::Undefined)
+    }
+    None => Ok(read_property(
+      valuescript_vm::operations::op_optional_chain(left, right)?,
+    )),
+  }
+}
+
+impl ValTrait for OptionalSignal {
+  fn typeof_(&self) -> VsType {
+    // The type of the payload, which is what merging needs. The `typeof` operator uses
+    // `op_typeof_optional` instead.
+    self.value.typeof_()
+  }
+
+  fn to_number(&self) -> f64 {
+    f64::NAN
+  }
+
+  fn to_index(&self) -> Option<usize> {
+    None
+  }
+
+  fn is_primitive(&self) -> bool {
+    self.value.is_primitive()
+  }
+
+  fn is_truthy(&self) -> bool {
+    // Conditions are converted using `truthiness`, so this shouldn't be reached
+    defer_error(CircuitError::Internal(
+      "Optional value was used as a condition without converting it".to_string(),
+    ));
+
+    false
+  }
+
+  fn is_nullish(&self) -> bool {
+    // `??` and `?.` are handled by `op_nullish_coalesce_optional` and `op_optional_chain_optional`
+    false
+  }
+
+  fn bind(&self, _params: Vec<Val>) -> Option<Val> {
+    None
+  }
+
+  fn as_bigint_data(&self) -> Option<num_bigint::BigInt> {
+    None
+  }
+
+  fn as_array_data(&self) -> Option<Rc<VsArray>> {
+    None
+  }
+
+  fn as_class_data(&self) -> Option<Rc<VsClass>> {
+    None
+  }
+
+  fn load_function(&self) -> LoadFunctionResult {
+    LoadFunctionResult::NotAFunction
+  }
+
+  fn sub(&self, _key: &Val) -> Result<Val, Val> {
+    Err("Cannot access a property of a possibly-undefined value (use ?. instead)".to_type_error())
+  }
+
+  fn has(&self, _key: &Val) -> Option<bool> {
+    None
+  }
+
+  fn submov(&mut self, _key: &Val, _value: Val) -> Result<(), Val> {
+    Err("Cannot assign a property of a possibly-undefined value".to_type_error())
+  }
+
+  fn override_binary_op(&self, op: BinaryOp, left: &Val, right: &Val) -> Option<Result<Val, Val>> {
+    override_binary_op(op, left, right)
+  }
+
+  fn override_unary_op(&self, op: UnaryOp, _input: &Val) -> Option<Result<Val, Val>> {
+    let value = match op {
+      UnaryOp::Not => return Some(self.truthiness().and_then(|truthy| op_not(&truthy))),
+      UnaryOp::Plus => op_unary_plus(&self.value),
+      UnaryOp::Minus => op_unary_minus(&self.value),
+      UnaryOp::BitNot => op_bit_not(&self.value),
+    };
+
+    Some(value.and_then(|value| {
+      OptionalSignal::new_val(self.is_defined.clone(), value, nullish_kind(&self.nullish))
+    }))
+  }
+
+  fn pretty_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "OptionalSignal({} | {})",
+      self.value.pretty(),
+      self.nullish.pretty()
+    )
+  }
+
+  fn codify(&self) -> String {
+    format!(
+      "OptionalSignal({} | {})",
+      self.value.codify(),
+      self.nullish.codify()
+    )
+  }
+}
+
+impl std::fmt::Display for OptionalSignal {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[OptionalSignal]")
+  }
+}
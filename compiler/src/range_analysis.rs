@@ -0,0 +1,548 @@
+use std::collections::{HashMap, HashSet};
+
+use valuescript_vm::{
+  binary_op::BinaryOp,
+  unary_op::UnaryOp,
+  vs_value::{Val, VsType},
+  ValTrait,
+};
+
+use crate::{
+  circuit_signal::{CircuitSignal, CircuitSignalData},
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/// Values are tracked as i128 so that intermediate results of 64-bit wire operations can be
+/// represented exactly. Anything outside of this is treated as unbounded.
+const LIMIT: i128 = u64::MAX as i128;
+
+/// How many nested branch flags we will use to narrow ranges. Each level re-evaluates the gated
+/// subexpression, so this keeps the analysis roughly linear in the size of the signal DAG.
+const MAX_NARROWING_DEPTH: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueRange {
+  pub min: i128,
+  pub max: i128,
+}
+
+impl ValueRange {
+  pub fn new(min: i128, max: i128) -> Option<Self> {
+    if min > max || min < -LIMIT || max > LIMIT {
+      return None;
+    }
+
+    Some(ValueRange { min, max })
+  }
+
+  pub fn constant(value: i128) -> Option<Self> {
+    ValueRange::new(value, value)
+  }
+
+  pub fn bool() -> Self {
+    ValueRange { min: 0, max: 1 }
+  }
+
+  /// The number of bits needed to represent every value in the range. Ranges that include negative
+  /// numbers need an extra bit for two's complement.
+  pub fn bit_width(&self) -> usize {
+    if self.min >= 0 {
+      return bits_needed(self.max);
+    }
+
+    1 + bits_needed(self.max.max(-self.min - 1))
+  }
+
+  /// Whether `value` is an integer in the range.
+  pub fn contains(&self, value: f64) -> bool {
+    value == value.trunc() && value >= self.min as f64 && value <= self.max as f64
+  }
+
+  pub fn intersect(&self, other: &ValueRange) -> Option<ValueRange> {
+    ValueRange::new(self.min.max(other.min), self.max.min(other.max))
+  }
+
+  fn from_candidates(candidates: &[Option<i128>]) -> Option<ValueRange> {
+    let mut min = i128::MAX;
+    let mut max = i128::MIN;
+
+    for candidate in candidates {
+      let candidate = (*candidate)?;
+      min = min.min(candidate);
+      max = max.max(candidate);
+    }
+
+    ValueRange::new(min, max)
+  }
+}
+
+fn bits_needed(max: i128) -> usize {
+  match max {
+    0 => 1,
+    _ => (128 - max.leading_zeros()) as usize,
+  }
+}
+
+/**
+ * Propagates value ranges through the signal DAG, starting from the (optionally annotated) ranges
+ * of the inputs.
+ *
+ * Merging branches produces expressions like `flag * value` where `value` was computed under the
+ * assumption that `flag` is true. When `flag` is a comparison against a constant, such as
+ * `x < 10`, we evaluate `value` again with `x` narrowed accordingly.
+ */
+pub struct RangeAnalysis {
+  input_ranges: HashMap<usize, ValueRange>, // CircuitSignal.id -> range
+  cache: HashMap<usize, Option<ValueRange>>,
+  visited: HashSet<usize>,
+
+  /// The widest constant (or constant output) that becomes a wire, or `None` if one of them isn't
+  /// an integer. These aren't signals, so they aren't in `cache`.
+  constant_bit_width: Option<usize>,
+}
+
+struct Narrowing {
+  overrides: HashMap<usize, ValueRange>,
+  cache: HashMap<usize, Option<ValueRange>>,
+  depth: usize,
+}
+
+impl RangeAnalysis {
+  pub fn new(input_ranges: HashMap<usize, ValueRange>) -> Self {
+    RangeAnalysis {
+      input_ranges,
+      cache: HashMap::new(),
+      visited: HashSet::new(),
+      constant_bit_width: Some(1),
+    }
+  }
+
+  /// Returns the range of `val`, or `None` if it is unbounded (or not a number).
+  pub fn range_of(&mut self, val: &Val) -> Option<ValueRange> {
+    let range = self.range_of_impl(val, &mut None);
+    self.analyze_dependencies(val);
+
+    range
+  }
+
+  /// The widest bit width of any wire analyzed so far (signals including their dependencies, and
+  /// the constants they use), or `None` if any of them are unbounded.
+  pub fn max_bit_width(&self) -> Option<usize> {
+    let mut res = self.constant_bit_width?;
+
+    for range in self.cache.values() {
+      res = res.max(range.as_ref()?.bit_width());
+    }
+
+    Some(res)
+  }
+
+  fn include_constant(&mut self, val: &Val) {
+    let range = self.range_of_impl(val, &mut None);
+
+    self.constant_bit_width = match (self.constant_bit_width, range) {
+      (Some(width), Some(range)) => Some(width.max(range.bit_width())),
+      _ => None,
+    };
+  }
+
+  /// Subexpressions may have only been evaluated under narrowing, but every signal becomes a wire,
+  /// so we also need their unconditional ranges. Constants that signals use (and constant outputs)
+  /// also become wires, eg the `200` in `x > 200`.
+  fn analyze_dependencies(&mut self, val: &Val) {
+    let mut stack = vec![val.clone()];
+
+    while let Some(val) = stack.pop() {
+      let signal = match val_dynamic_downcast::<CircuitSignal>(&val) {
+        Some(signal) => signal,
+        None => {
+          self.include_constant(&val);
+          continue;
+        }
+      };
+
+      if !self.visited.insert(signal.id) {
+        continue;
+      }
+
+      if !self.cache.contains_key(&signal.id) {
+        self.range_of_impl(&val, &mut None);
+      }
+
+      match &signal.data {
+        CircuitSignalData::Input => {}
+        CircuitSignalData::UnaryOp(_, input) => stack.push(input.clone()),
+        CircuitSignalData::BinaryOp(_, left, right) => {
+          stack.push(left.clone());
+          stack.push(right.clone());
+        }
+      }
+    }
+  }
+
+  fn range_of_impl(&mut self, val: &Val, narrowing: &mut Option<Narrowing>) -> Option<ValueRange> {
+    let signal = match val {
+      Val::Bool(b) => return ValueRange::constant(*b as i128),
+      Val::Number(n) => {
+        if *n != n.trunc() {
+          return None;
+        }
+
+        return ValueRange::constant(*n as i128);
+      }
+      _ => val_dynamic_downcast::<CircuitSignal>(val)?,
+    };
+
+    let cached = match narrowing {
+      Some(narrowing) => narrowing.cache.get(&signal.id),
+      None => self.cache.get(&signal.id),
+    };
+
+    if let Some(cached) = cached {
+      return *cached;
+    }
+
+    let mut range = self.compute_range(signal, narrowing);
+
+    if let Some(narrowing) = narrowing {
+      if let Some(override_) = narrowing.overrides.get(&signal.id) {
+        range = match range {
+          Some(range) => range.intersect(override_),
+          None => Some(*override_),
+        };
+      }
+
+      narrowing.cache.insert(signal.id, range);
+    } else {
+      self.cache.insert(signal.id, range);
+    }
+
+    range
+  }
+
+  fn compute_range(
+    &mut self,
+    signal: &CircuitSignal,
+    narrowing: &mut Option<Narrowing>,
+  ) -> Option<ValueRange> {
+    match &signal.data {
+      CircuitSignalData::Input => self.input_ranges.get(&signal.id).copied(),
+      CircuitSignalData::UnaryOp(op, input) => {
+        let input = self.range_of_impl(input, narrowing);
+
+        match op {
+          UnaryOp::Not => Some(ValueRange::bool()),
+          UnaryOp::Plus => input,
+          UnaryOp::Minus => input.and_then(|r| ValueRange::new(-r.max, -r.min)),
+          UnaryOp::BitNot => input.and_then(|r| ValueRange::new(-r.max - 1, -r.min - 1)),
+        }
+      }
+      CircuitSignalData::BinaryOp(op, left, right) => {
+        if *op == BinaryOp::Plus {
+          if let Some(range) = self.select_range(left, right, narrowing) {
+            return range;
+          }
+
+          if let Some(range) = self.select_range(right, left, narrowing) {
+            return range;
+          }
+        }
+
+        if *op == BinaryOp::Mul {
+          if let Some(range) = self.narrowed_mul_range(left, right, narrowing) {
+            return range;
+          }
+        }
+
+        let left = self.range_of_impl(left, narrowing);
+        let right = self.range_of_impl(right, narrowing);
+
+        binary_op_range(*op, left, right)
+      }
+    }
+  }
+
+  /// Merging branches with complementary flags produces `a + flag * (b - a)` (see
+  /// `arithmetic_merge`). Evaluating this directly loses the correlation between the two `a`s, so
+  /// instead we take the union of `a` when `flag` is false and `b` when `flag` is true.
+  fn select_range(
+    &mut self,
+    base: &Val,
+    term: &Val,
+    narrowing: &mut Option<Narrowing>,
+  ) -> Option<Option<ValueRange>> {
+    let (flag, diff) = match &val_dynamic_downcast::<CircuitSignal>(term)?.data {
+      CircuitSignalData::BinaryOp(BinaryOp::Mul, l, r) if l.typeof_() == VsType::Bool => (l, r),
+      CircuitSignalData::BinaryOp(BinaryOp::Mul, l, r) if r.typeof_() == VsType::Bool => (r, l),
+      _ => return None,
+    };
+
+    let selected = match &val_dynamic_downcast::<CircuitSignal>(diff)?.data {
+      CircuitSignalData::BinaryOp(BinaryOp::Minus, b, a) if same_val(a, base) => b,
+      _ => return None,
+    };
+
+    let when_true = flag_constraints(flag, true).unwrap_or_default();
+    let when_false = flag_constraints(flag, false).unwrap_or_default();
+
+    let mut res: Option<ValueRange> = None;
+
+    for (val, constraints) in [(selected, when_true), (base, when_false)] {
+      let range = match self.range_under(val, constraints, narrowing) {
+        Some(range) => range,
+        None => continue, // This side is impossible
+      };
+
+      let range = match range {
+        Some(range) => range,
+        None => return Some(None),
+      };
+
+      res = Some(match res {
+        Some(res) => ValueRange::new(res.min.min(range.min), res.max.max(range.max))?,
+        None => range,
+      });
+    }
+
+    Some(res.or_else(|| ValueRange::constant(0)))
+  }
+
+  /// For `flag * value` where `flag` constrains some signals, evaluate `value` under those
+  /// constraints. Outside of the constrained region the product is zero, so the result is the
+  /// narrowed range extended to include zero.
+  fn narrowed_mul_range(
+    &mut self,
+    left: &Val,
+    right: &Val,
+    narrowing: &mut Option<Narrowing>,
+  ) -> Option<Option<ValueRange>> {
+    let (constraints, value) = match (flag_constraints(left, true), flag_constraints(right, true)) {
+      (Some(constraints), _) if !constraints.is_empty() => (constraints, right),
+      (_, Some(constraints)) if !constraints.is_empty() => (constraints, left),
+      _ => return None,
+    };
+
+    Some(match self.range_under(value, constraints, narrowing) {
+      Some(Some(range)) => ValueRange::new(range.min.min(0), range.max.max(0)),
+      Some(None) => None,
+
+      // The flag contradicts the constraints we're already under, so it must be false here
+      None => ValueRange::constant(0),
+    })
+  }
+
+  /// Evaluates `val` with the given constraints added to the current ones. Returns `None` if the
+  /// constraints are contradictory.
+  fn range_under(
+    &mut self,
+    val: &Val,
+    constraints: Vec<(usize, ValueRange)>,
+    narrowing: &mut Option<Narrowing>,
+  ) -> Option<Option<ValueRange>> {
+    let depth = narrowing.as_ref().map_or(0, |n| n.depth);
+
+    if depth >= MAX_NARROWING_DEPTH || constraints.is_empty() {
+      return Some(self.range_of_impl(val, narrowing));
+    }
+
+    let mut overrides = narrowing
+      .as_ref()
+      .map(|n| n.overrides.clone())
+      .unwrap_or_default();
+
+    for (id, range) in constraints {
+      let range = match overrides.get(&id) {
+        Some(existing) => existing.intersect(&range)?,
+        None => range,
+      };
+
+      overrides.insert(id, range);
+    }
+
+    let mut inner = Some(Narrowing {
+      overrides,
+      cache: HashMap::new(),
+      depth: depth + 1,
+    });
+
+    Some(self.range_of_impl(val, &mut inner))
+  }
+}
+
+fn same_val(left: &Val, right: &Val) -> bool {
+  match (left, right) {
+    (Val::Number(left), Val::Number(right)) => left == right,
+    (Val::Bool(left), Val::Bool(right)) => left == right,
+    _ => match (
+      val_dynamic_downcast::<CircuitSignal>(left),
+      val_dynamic_downcast::<CircuitSignal>(right),
+    ) {
+      (Some(left), Some(right)) => left.id == right.id,
+      _ => false,
+    },
+  }
+}
+
+/// Finds the constraints implied by `flag` being `positive`. Returns `None` if `flag` isn't a
+/// boolean signal.
+fn flag_constraints(flag: &Val, positive: bool) -> Option<Vec<(usize, ValueRange)>> {
+  let signal = val_dynamic_downcast::<CircuitSignal>(flag)?;
+
+  let (op, left, right) = match &signal.data {
+    CircuitSignalData::UnaryOp(UnaryOp::Not, input) => return flag_constraints(input, !positive),
+    CircuitSignalData::BinaryOp(op, left, right) => (*op, left, right),
+    _ => return None,
+  };
+
+  match (op, positive) {
+    (BinaryOp::And, true) | (BinaryOp::Or, false) => {
+      let mut res = flag_constraints(left, positive).unwrap_or_default();
+      res.append(&mut flag_constraints(right, positive).unwrap_or_default());
+      return Some(res);
+    }
+    (BinaryOp::And, false) | (BinaryOp::Or, true) => return Some(vec![]),
+    _ => {}
+  }
+
+  let (id, op, c) = match (
+    val_dynamic_downcast::<CircuitSignal>(left),
+    constant_of(right),
+    constant_of(left),
+    val_dynamic_downcast::<CircuitSignal>(right),
+  ) {
+    (Some(signal), Some(c), _, _) => (signal.id, op, c),
+    (_, _, Some(c), Some(signal)) => (signal.id, mirror_comparison(op)?, c),
+    _ => return Some(vec![]),
+  };
+
+  let op = if positive { op } else { negate_comparison(op)? };
+
+  let range = match op {
+    BinaryOp::Less => ValueRange::new(-LIMIT, c - 1),
+    BinaryOp::LessEq => ValueRange::new(-LIMIT, c),
+    BinaryOp::Greater => ValueRange::new(c + 1, LIMIT),
+    BinaryOp::GreaterEq => ValueRange::new(c, LIMIT),
+    BinaryOp::Eq | BinaryOp::LooseEq => ValueRange::constant(c),
+    _ => None,
+  };
+
+  Some(range.map(|range| vec![(id, range)]).unwrap_or_default())
+}
+
+fn constant_of(val: &Val) -> Option<i128> {
+  match val {
+    Val::Number(n) if *n == n.trunc() => Some(*n as i128),
+    Val::Bool(b) => Some(*b as i128),
+    _ => None,
+  }
+}
+
+/// `c < x` is `x > c` etc.
+fn mirror_comparison(op: BinaryOp) -> Option<BinaryOp> {
+  Some(match op {
+    BinaryOp::Less => BinaryOp::Greater,
+    BinaryOp::LessEq => BinaryOp::GreaterEq,
+    BinaryOp::Greater => BinaryOp::Less,
+    BinaryOp::GreaterEq => BinaryOp::LessEq,
+    BinaryOp::Eq | BinaryOp::LooseEq | BinaryOp::Ne | BinaryOp::LooseNe => op,
+    _ => return None,
+  })
+}
+
+fn negate_comparison(op: BinaryOp) -> Option<BinaryOp> {
+  Some(match op {
+    BinaryOp::Less => BinaryOp::GreaterEq,
+    BinaryOp::LessEq => BinaryOp::Greater,
+    BinaryOp::Greater => BinaryOp::LessEq,
+    BinaryOp::GreaterEq => BinaryOp::Less,
+    BinaryOp::Ne => BinaryOp::Eq,
+    BinaryOp::LooseNe => BinaryOp::LooseEq,
+    _ => return None,
+  })
+}
+
+fn binary_op_range(
+  op: BinaryOp,
+  left: Option<ValueRange>,
+  right: Option<ValueRange>,
+) -> Option<ValueRange> {
+  match op {
+    BinaryOp::LooseEq
+    | BinaryOp::LooseNe
+    | BinaryOp::Eq
+    | BinaryOp::Ne
+    | BinaryOp::And
+    | BinaryOp::Or
+    | BinaryOp::Less
+    | BinaryOp::LessEq
+    | BinaryOp::Greater
+    | BinaryOp::GreaterEq => return Some(ValueRange::bool()),
+    _ => {}
+  }
+
+  let (l, r) = (left?, right?);
+
+  match op {
+    BinaryOp::Plus => ValueRange::new(l.min + r.min, l.max + r.max),
+    BinaryOp::Minus => ValueRange::new(l.min - r.max, l.max - r.min),
+    BinaryOp::Mul => ValueRange::from_candidates(&[
+      l.min.checked_mul(r.min),
+      l.min.checked_mul(r.max),
+      l.max.checked_mul(r.min),
+      l.max.checked_mul(r.max),
+    ]),
+    BinaryOp::Div => {
+      if l.min < 0 || r.min <= 0 {
+        return None;
+      }
+
+      ValueRange::new(l.min / r.max, l.max / r.min)
+    }
+    BinaryOp::Mod => {
+      if l.min < 0 || r.min <= 0 {
+        return None;
+      }
+
+      ValueRange::new(0, l.max.min(r.max - 1))
+    }
+    BinaryOp::Exp => {
+      if l.min < 0 || r.min < 0 || r.max > u32::MAX as i128 {
+        return None;
+      }
+
+      ValueRange::from_candidates(&[
+        l.min.checked_pow(r.min as u32),
+        l.max.checked_pow(r.max as u32),
+      ])
+    }
+    BinaryOp::BitAnd => {
+      if l.min < 0 || r.min < 0 {
+        return None;
+      }
+
+      ValueRange::new(0, l.max.min(r.max))
+    }
+    BinaryOp::BitOr | BinaryOp::BitXor => {
+      if l.min < 0 || r.min < 0 {
+        return None;
+      }
+
+      ValueRange::new(0, (1i128 << bits_needed(l.max.max(r.max))) - 1)
+    }
+    BinaryOp::LeftShift => {
+      if l.min < 0 || r.min < 0 || r.max >= 64 {
+        return None;
+      }
+
+      ValueRange::new(l.min << r.min, l.max << r.max)
+    }
+    BinaryOp::RightShift | BinaryOp::RightShiftUnsigned => {
+      if l.min < 0 || r.min < 0 || r.max >= 64 {
+        return None;
+      }
+
+      ValueRange::new(l.min >> r.max, l.max >> r.min)
+    }
+    _ => None,
+  }
+}
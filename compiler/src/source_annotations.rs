@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+
+use crate::{range_analysis::ValueRange, source_tokens::tokenize};
+
+/**
+ * Annotations that are written in comments of the entry point's source, since they have no
+ * representation in the bytecode.
+ *
+ * ```ts
+ * /**
+ *  * @range y 0 51
+ *  * @validate gate
+ *  */
+ * export default function main(x: number /* 0..100 */, y: number) {
+ *   // ...
+ * }
+ * ```
+ *
+ * Other functions in the entry file can also have a `@maxDepth <n>` tag, which bounds their
+ * recursion (see `RecursionLimits`).
+ */
+#[derive(Default)]
+pub struct EntryAnnotations {
+  /// Ranges the inputs are assumed to be in (param name -> range).
+  pub input_ranges: HashMap<String, ValueRange>,
+
+  /// Ranges the inputs are required to be in (param name -> range), from `@range` tags. Unlike
+  /// `input_ranges`, these generate circuitry to check the inputs.
+  pub input_domains: HashMap<String, ValueRange>,
+
+  pub validation: Validation,
+
+  /// Recursion bounds from `@maxDepth` tags.
+  pub max_depths: Vec<MaxDepthTag>,
+
+  /// Problems with the annotations, as (position, message).
+  pub errors: Vec<(usize, String)>,
+}
+
+/// A `@maxDepth <n>` tag on a function declaration.
+pub struct MaxDepthTag {
+  pub fn_name: String,
+
+  /// The names of the function's parameters, which tell it apart from other functions with the same
+  /// name.
+  pub param_names: Vec<String>,
+
+  /// Position of the tag.
+  pub pos: usize,
+
+  pub max_depth: usize,
+}
+
+/// How the circuit should respond to inputs outside their `@range` domains.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Validation {
+  /// Add an `inputs_valid` output.
+  #[default]
+  Output,
+
+  /// Add an `inputs_valid` output and also zero every other output when the inputs are invalid.
+  Gate,
+}
+
+pub fn parse_entry_annotations(source: &str, fn_name: &str) -> EntryAnnotations {
+  let mut annotations = EntryAnnotations::default();
+
+  parse_max_depths(source, &mut annotations);
+
+  let found = match find_function(source, fn_name) {
+    Some(found) => found,
+    None => return annotations,
+  };
+
+  if let Some(doc_start) = find_doc_comment(source, found.start) {
+    parse_doc_tags(source, doc_start, &mut annotations);
+  }
+
+  for param in parse_params(source, found.open_paren) {
+    for comment in &param.comments {
+      if let Some(range) = parse_range(comment) {
+        annotations.input_ranges.insert(param.name.clone(), range);
+      }
+    }
+  }
+
+  annotations
+}
+
+pub struct FoundFunction {
+  /// Position of the `function` keyword.
+  pub start: usize,
+
+  /// Position of the `(` that starts the parameter list.
+  pub open_paren: usize,
+}
+
+/// Finds `function name(` (or `function* name(`) in the source.
+pub fn find_function(source: &str, fn_name: &str) -> Option<FoundFunction> {
+  for (start, rest) in function_keywords(source) {
+    let rest = match rest.strip_prefix(fn_name) {
+      Some(rest) => rest,
+      None => continue,
+    };
+
+    if rest.chars().next().is_some_and(is_ident_char) {
+      continue;
+    }
+
+    let rest = rest.trim_start();
+    let rest = skip_type_params(rest);
+
+    if rest.starts_with('(') {
+      return Some(FoundFunction {
+        start,
+        open_paren: source.len() - rest.len(),
+      });
+    }
+  }
+
+  None
+}
+
+/// The positions of `function` keywords, along with the source after the keyword (and `*`).
+/// Comments and literals are skipped, so `// function main` doesn't count.
+fn function_keywords(source: &str) -> Vec<(usize, &str)> {
+  let tokens = tokenize(source);
+
+  tokens
+    .iter()
+    .enumerate()
+    .filter(|(i, token)| token.is_ident("function") && !(*i > 0 && tokens[i - 1].is_punct(".")))
+    .map(|(_, token)| {
+      let rest = source[token.end()..].trim_start();
+      let rest = rest.strip_prefix('*').unwrap_or(rest).trim_start();
+
+      (token.pos, rest)
+    })
+    .collect()
+}
+
+/// Finds the `/** ... */` comment preceding the function, returning the position of its `/**`.
+fn find_doc_comment(source: &str, fn_start: usize) -> Option<usize> {
+  let mut before = source[..fn_start].trim_end();
+
+  for keyword in ["async", "default", "export"] {
+    if let Some(rest) = before.strip_suffix(keyword) {
+      before = rest.trim_end();
+    }
+  }
+
+  if !before.ends_with("*/") {
+    return None;
+  }
+
+  before.rfind("/**")
+}
+
+/// Parses the tags of a doc comment:
+/// - `@range <param> <min> <max>`: declares the domain of an input
+/// - `@validate output|gate`: see [`Validation`]
+fn parse_doc_tags(source: &str, doc_start: usize, annotations: &mut EntryAnnotations) {
+  for (pos, line) in doc_lines(source, doc_start) {
+    let mut words = line.split_whitespace();
+
+    match words.next() {
+      Some("@range") => {
+        let parts = words.collect::<Vec<_>>();
+
+        let range = match parts.as_slice() {
+          [_, min, max] => min
+            .parse()
+            .ok()
+            .zip(max.parse().ok())
+            .and_then(|(min, max)| ValueRange::new(min, max)),
+          _ => None,
+        };
+
+        match range {
+          Some(range) => {
+            annotations
+              .input_domains
+              .insert(parts[0].to_string(), range);
+          }
+          None => annotations.errors.push((
+            pos,
+            format!(
+              "Invalid @range (expected `@range <param> <min> <max>`): {}",
+              line
+            ),
+          )),
+        }
+      }
+      Some("@validate") => match words.next() {
+        Some("output") => annotations.validation = Validation::Output,
+        Some("gate") => annotations.validation = Validation::Gate,
+        _ => annotations.errors.push((
+          pos,
+          format!("Invalid @validate (expected `output` or `gate`): {}", line),
+        )),
+      },
+      _ => {}
+    }
+  }
+}
+
+/// Parses `@maxDepth <n>` tags, which can be on any function declaration in the source.
+fn parse_max_depths(source: &str, annotations: &mut EntryAnnotations) {
+  for (start, rest) in function_keywords(source) {
+    let fn_name = rest
+      .chars()
+      .take_while(|c| is_ident_char(*c))
+      .collect::<String>();
+
+    let doc_start = match find_doc_comment(source, start) {
+      Some(doc_start) if !fn_name.is_empty() => doc_start,
+      _ => continue,
+    };
+
+    for (pos, line) in doc_lines(source, doc_start) {
+      let mut words = line.split_whitespace();
+
+      if words.next() != Some("@maxDepth") {
+        continue;
+      }
+
+      match words.next().and_then(|depth| depth.parse::<usize>().ok()) {
+        Some(max_depth) if max_depth > 0 => annotations.max_depths.push(MaxDepthTag {
+          fn_name: fn_name.clone(),
+          param_names: param_names(source, &rest[fn_name.len()..]),
+          pos,
+          max_depth,
+        }),
+        _ => annotations.errors.push((
+          pos,
+          format!("Invalid @maxDepth (expected a positive integer): {}", line),
+        )),
+      }
+    }
+  }
+}
+
+/// The names of the parameters of a function declaration, given the source after its name.
+fn param_names(source: &str, after_name: &str) -> Vec<String> {
+  let rest = skip_type_params(after_name.trim_start());
+
+  if !rest.starts_with('(') {
+    return vec![];
+  }
+
+  parse_params(source, source.len() - rest.len())
+    .into_iter()
+    .map(|param| param.name)
+    .collect()
+}
+
+/// The lines of a doc comment (without the leading `*`), along with their positions.
+fn doc_lines(source: &str, doc_start: usize) -> Vec<(usize, &str)> {
+  let doc_end = match source[doc_start..].find("*/") {
+    Some(end) => doc_start + end,
+    None => return vec![],
+  };
+
+  let mut line_start = doc_start + "/**".len();
+  let mut lines = vec![];
+
+  for line in source[line_start..doc_end].split('\n') {
+    lines.push((line_start, line.trim().trim_start_matches('*').trim()));
+    line_start += line.len() + 1;
+  }
+
+  lines
+}
+
+/// Converts a byte position into a 1-based (line, column).
+pub fn line_col(source: &str, pos: usize) -> (usize, usize) {
+  let before = &source[..pos];
+  let line = before.matches('\n').count() + 1;
+  let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+  (line, before[line_start..].chars().count() + 1)
+}
+
+fn skip_type_params(text: &str) -> &str {
+  if !text.starts_with('<') {
+    return text;
+  }
+
+  let mut depth = 0;
+
+  for (i, c) in text.char_indices() {
+    match c {
+      '<' => depth += 1,
+      '>' => {
+        depth -= 1;
+
+        if depth == 0 {
+          return text[i + 1..].trim_start();
+        }
+      }
+      _ => {}
+    }
+  }
+
+  text
+}
+
+pub struct Param {
+  pub name: String,
+
+  /// The contents of any `/* ... */` comments attached to the parameter.
+  pub comments: Vec<String>,
+}
+
+fn parse_params(source: &str, open_paren: usize) -> Vec<Param> {
+  let mut params = Vec::<Param>::new();
+
+  let mut depth = 0;
+  let mut text = String::new();
+  let mut comments = Vec::<String>::new();
+
+  let mut finish_param = |text: &str, comments: &mut Vec<String>| {
+    let trimmed = text.trim_start().trim_start_matches("...");
+
+    let name = trimmed
+      .chars()
+      .take_while(|c| is_ident_char(*c))
+      .collect::<String>();
+
+    if !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) {
+      params.push(Param {
+        name,
+        comments: std::mem::take(comments),
+      });
+    }
+
+    comments.clear();
+  };
+
+  let mut chars = source[open_paren + 1..].char_indices().peekable();
+
+  while let Some((i, c)) = chars.next() {
+    let pos = open_paren + 1 + i;
+
+    match c {
+      '/' if chars.peek().map(|(_, c)| *c) == Some('*') => {
+        let end = match source[pos + 2..].find("*/") {
+          Some(end) => pos + 2 + end,
+          None => break,
+        };
+
+        comments.push(source[pos + 2..end].to_string());
+
+        while chars
+          .peek()
+          .is_some_and(|(j, _)| open_paren + 1 + j < end + 2)
+        {
+          chars.next();
+        }
+      }
+      '/' if chars.peek().map(|(_, c)| *c) == Some('/') => {
+        while chars.peek().is_some_and(|(_, c)| *c != '\n') {
+          chars.next();
+        }
+      }
+      '(' | '[' | '{' | '<' => {
+        depth += 1;
+        text.push(c);
+      }
+      '>' if text.ends_with('=') => text.push(c),
+      ')' | ']' | '}' | '>' => {
+        if depth == 0 {
+          finish_param(&text, &mut comments);
+          break;
+        }
+
+        depth -= 1;
+        text.push(c);
+      }
+      ',' if depth == 0 => {
+        finish_param(&text, &mut comments);
+        text.clear();
+      }
+      _ => text.push(c),
+    }
+  }
+
+  params
+}
+
+/// Parses `min..max`, eg `0..100` or `-5..5`.
+fn parse_range(comment: &str) -> Option<ValueRange> {
+  let (min, max) = comment.trim().split_once("..")?;
+
+  ValueRange::new(min.trim().parse().ok()?, max.trim().parse().ok()?)
+}
+
+pub fn is_ident_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '_' || c == '$'
+}
@@ -0,0 +1,392 @@
+use std::{collections::HashSet, ops::Range, rc::Rc};
+
+use valuescript_common::InstructionByte;
+use valuescript_vm::vs_value::Val;
+
+use crate::{
+  bytecode::{Bytecode, DecoderMaker},
+  source_tokens::{is_close, is_open, is_punct_at, tokenize, Groups, Token},
+};
+
+/**
+ * A condition in the source that ValueScript compiles to a conditional jump: an `if`, a loop
+ * condition, a `? :` or a `case`.
+ *
+ * The bytecode doesn't have a source map, so these are matched up with the conditional jumps of the
+ * function's bytecode by their order (see `function_branches` and `conditional_jumps`).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceBranch {
+  /// Byte range of the keyword or operator, eg `if` or `?`.
+  pub at: Range<usize>,
+
+  /// Byte range of the last token of the statement or expression, which is where its branches
+  /// meet again, eg the `}` that ends an `if` statement.
+  pub end: Range<usize>,
+
+  /// How many of the following branches are inside this one.
+  pub nested: usize,
+}
+
+/**
+ * The conditions in the function whose parameter list starts at `open_paren`, in the order that
+ * their jumps appear in the bytecode. Conditions are ordered by where their jump is evaluated, which
+ * is after the condition itself (eg at the `)` of `if (...)`), and the `case` tests of a `switch`
+ * are all evaluated before any of the cases run. Nested functions are skipped, since they have
+ * their own bytecode.
+ *
+ * This is found from the tokens rather than by parsing, so it can be wrong for unusual code. That
+ * is caught by comparing the number of conditions with the number of conditional jumps.
+ */
+pub fn function_branches(source: &str, open_paren: usize) -> Vec<SourceBranch> {
+  let tokens = tokenize(source);
+  let groups = Groups::new(source, &tokens);
+
+  let params = match tokens.iter().position(|token| token.pos == open_paren) {
+    Some(params) => params,
+    None => return vec![],
+  };
+
+  let body_end = match groups.function_range(params) {
+    Some((_, end)) => end,
+    None => return vec![],
+  };
+
+  let body = groups.after(params)..tokens.partition_point(|token| token.end() <= body_end);
+  let nested_fns = nested_functions(&tokens, &groups, body.clone());
+  let is_nested = |i: usize| {
+    nested_fns
+      .iter()
+      .any(|range| range.contains(&tokens[i].pos))
+  };
+
+  // (position of the jump, branch)
+  let mut branches = Vec::<(usize, SourceBranch)>::new();
+  let mut do_while_conditions = HashSet::<usize>::new();
+
+  let range = |i: usize| tokens[i].pos..tokens[i].end();
+
+  for i in body {
+    if is_nested(i) {
+      continue;
+    }
+
+    let token = &tokens[i];
+    let has_parens = is_punct_at(&tokens, i + 1, "(");
+    let close_paren = groups.after(i + 1).saturating_sub(1);
+
+    if token.is_ident("do") {
+      let body_last = statement_last(&tokens, &groups, i + 1);
+      do_while_conditions.insert(body_last + 1);
+    } else if token.is_ident("if") && has_parens {
+      branches.push((
+        tokens[close_paren].pos,
+        SourceBranch {
+          at: range(i),
+          end: range(statement_last(&tokens, &groups, i)),
+          nested: 0,
+        },
+      ));
+    } else if token.is_ident("while") && has_parens {
+      let last = match do_while_conditions.contains(&i) {
+        true => close_paren,
+        false => statement_last(&tokens, &groups, i),
+      };
+
+      branches.push((
+        tokens[close_paren].pos,
+        SourceBranch {
+          at: range(i),
+          end: range(last),
+          nested: 0,
+        },
+      ));
+    } else if token.is_ident("for") && has_parens {
+      let semicolons = (i + 2..close_paren)
+        .filter(|k| groups.parent[*k] == Some(i + 1) && tokens[*k].is_punct(";"))
+        .collect::<Vec<_>>();
+
+      // `for (...; cond; ...)` jumps after the condition, and `for (... of ...)` jumps when the
+      // iterator is done. `for (...; ; ...)` doesn't jump.
+      let jump = match semicolons.as_slice() {
+        [] => close_paren,
+        [first, second] if second - first > 1 => *second,
+        _ => continue,
+      };
+
+      branches.push((
+        tokens[jump].pos,
+        SourceBranch {
+          at: range(i),
+          end: range(statement_last(&tokens, &groups, i)),
+          nested: 0,
+        },
+      ));
+    } else if token.is_ident("switch") && has_parens {
+      let open = close_paren + 1;
+
+      if !is_punct_at(&tokens, open, "{") {
+        continue;
+      }
+
+      let close = groups.after(open).saturating_sub(1);
+
+      for case in open + 1..close {
+        if groups.parent[case] == Some(open) && tokens[case].is_ident("case") {
+          branches.push((
+            tokens[open].pos,
+            SourceBranch {
+              at: range(case),
+              end: range(close),
+              nested: 0,
+            },
+          ));
+        }
+      }
+    } else if is_conditional_operator(&tokens, i) {
+      branches.push((
+        token.pos,
+        SourceBranch {
+          at: range(i),
+          end: range(expression_last(&tokens, &groups, i + 1)),
+          nested: 0,
+        },
+      ));
+    }
+  }
+
+  // Stable, so the cases of a `switch` stay in order
+  branches.sort_by_key(|(jump, _)| *jump);
+
+  let jumps = branches.iter().map(|(jump, _)| *jump).collect::<Vec<_>>();
+
+  branches
+    .into_iter()
+    .enumerate()
+    .map(|(k, (_, mut branch))| {
+      branch.nested = jumps[k + 1..]
+        .iter()
+        .take_while(|jump| **jump < branch.end.end)
+        .count();
+
+      branch
+    })
+    .collect()
+}
+
+/// Whether the `?` at `i` is a `? :` operator, rather than part of `??` or an optional parameter or
+/// property like `x?: number`.
+fn is_conditional_operator(tokens: &[Token], i: usize) -> bool {
+  if !tokens[i].is_punct("?") {
+    return false;
+  }
+
+  let is_nullish = (i > 0 && tokens[i - 1].is_punct("?")) || is_punct_at(tokens, i + 1, "?");
+
+  let is_optional = [":", ")", ",", "=", ";", "}"]
+    .iter()
+    .any(|punct| is_punct_at(tokens, i + 1, punct));
+
+  !is_nullish && !is_optional
+}
+
+/// The byte ranges of the functions (including arrow functions) inside the tokens in `body`.
+fn nested_functions(tokens: &[Token], groups: &Groups, body: Range<usize>) -> Vec<Range<usize>> {
+  let mut ranges = Vec::<Range<usize>>::new();
+
+  for i in body {
+    let token = &tokens[i];
+
+    // `(...) {` after `catch` is a block rather than a function
+    let function = if token.is_punct("(") && !(i > 0 && tokens[i - 1].is_ident("catch")) {
+      groups.function_range(i)
+    } else if !is_punct_at(tokens, i, "=>") && is_punct_at(tokens, i + 1, "=>") {
+      // `x => ...`
+      match is_punct_at(tokens, i + 2, "{") {
+        true => Some((token.pos, groups.range(i + 2).1)),
+        false => Some((token.pos, groups.expression_end(i + 2))),
+      }
+    } else {
+      None
+    };
+
+    if let Some((start, end)) = function {
+      ranges.push(start..end);
+    }
+  }
+
+  ranges
+}
+
+/// The index of the last token of the statement that starts at token `i`. Statements that rely on
+/// automatic semicolon insertion run on into the next statement.
+fn statement_last(tokens: &[Token], groups: &Groups, i: usize) -> usize {
+  let last_token = tokens.len().saturating_sub(1);
+
+  let token = match tokens.get(i) {
+    Some(token) => token,
+    None => return last_token,
+  };
+
+  let header_end = || groups.after(i + 1);
+
+  if token.is_punct("{") {
+    return groups.after(i).saturating_sub(1).min(last_token);
+  }
+
+  if token.is_ident("if") && is_punct_at(tokens, i + 1, "(") {
+    let last = statement_last(tokens, groups, header_end());
+
+    return match tokens.get(last + 1) {
+      Some(next) if next.is_ident("else") => statement_last(tokens, groups, last + 2),
+      _ => last,
+    };
+  }
+
+  if (token.is_ident("for") || token.is_ident("while")) && is_punct_at(tokens, i + 1, "(") {
+    return statement_last(tokens, groups, header_end());
+  }
+
+  if token.is_ident("do") {
+    // `do ... while (...);`
+    let body_last = statement_last(tokens, groups, i + 1);
+    let last = groups
+      .after(body_last + 2)
+      .saturating_sub(1)
+      .min(last_token);
+
+    return match is_punct_at(tokens, last + 1, ";") {
+      true => last + 1,
+      false => last,
+    };
+  }
+
+  let mut k = i;
+
+  while let Some(token) = tokens.get(k) {
+    if token.is_punct(";") {
+      return k;
+    }
+
+    if is_close(token) {
+      return k.saturating_sub(1).max(i);
+    }
+
+    k = match is_open(token) {
+      true => groups.after(k),
+      false => k + 1,
+    };
+  }
+
+  last_token
+}
+
+/// The index of the last token of the expression that starts at token `i`, which ends at the first
+/// `;` or `,` outside of brackets, or the end of the enclosing group.
+fn expression_last(tokens: &[Token], groups: &Groups, i: usize) -> usize {
+  let mut k = i;
+
+  while let Some(token) = tokens.get(k) {
+    if token.is_punct(";") || token.is_punct(",") || is_close(token) {
+      break;
+    }
+
+    k = match is_open(token) {
+      true => groups.after(k),
+      false => k + 1,
+    };
+  }
+
+  k.saturating_sub(1)
+    .max(i)
+    .min(tokens.len().saturating_sub(1))
+}
+
+/// The conditional jumps of a function's bytecode.
+pub struct FunctionJumps {
+  /// Positions of the `JmpIf` and `JmpIfNot` instructions, in order.
+  pub jumps: Vec<usize>,
+
+  /// Position of the function's final `End`.
+  pub end: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+  Val,
+  Register,
+  Pos,
+}
+
+/**
+ * Finds the conditional jumps of the function whose instructions start at `start`. Each instruction
+ * is decoded in turn (the same way `BytecodeStackFrame::step` decodes it), so that operand bytes
+ * aren't mistaken for instructions. Returns `None` if the function has an instruction that isn't
+ * supported.
+ */
+pub fn conditional_jumps(bytecode: &Rc<Bytecode>, start: usize) -> Option<FunctionJumps> {
+  let mut decoder = bytecode.decoder(start);
+  let mut registers = vec![Val::Void; 256];
+  let mut jumps = Vec::<usize>::new();
+
+  // `End` is also used to return early, so the function only ends at an `End` that nothing jumps
+  // past
+  let mut furthest_jump = start;
+
+  while decoder.pos < bytecode.code.len() {
+    let pos = decoder.pos;
+    let instruction = decoder.decode_instruction();
+
+    if instruction == InstructionByte::End && pos >= furthest_jump {
+      return Some(FunctionJumps { jumps, end: pos });
+    }
+
+    if matches!(
+      instruction,
+      InstructionByte::JmpIf | InstructionByte::JmpIfNot
+    ) {
+      jumps.push(pos);
+    }
+
+    for operand in operands(instruction)? {
+      match operand {
+        Operand::Val => {
+          decoder.decode_val(&mut registers);
+        }
+        Operand::Register => {
+          decoder.decode_register_index();
+        }
+        Operand::Pos => furthest_jump = furthest_jump.max(decoder.decode_pos()),
+      }
+    }
+  }
+
+  None
+}
+
+/// The operands of each instruction, as decoded by `BytecodeStackFrame::step`.
+fn operands(instruction: InstructionByte) -> Option<&'static [Operand]> {
+  use InstructionByte::*;
+  use Operand::{Pos as P, Register as R, Val as V};
+
+  Some(match instruction {
+    End | UnsetCatch | RequireMutableThis => &[],
+    OpInc | OpDec => &[R],
+    Throw => &[V],
+    Jmp => &[P],
+    JmpIf | JmpIfNot => &[V, P],
+    SetCatch => &[P, R],
+    Next => &[R, R],
+    UnpackIterRes => &[R, R, R],
+    Mov | OpNot | OpBitNot | TypeOf | UnaryPlus | UnaryMinus | Cat | Yield | YieldStar => &[V, R],
+    OpPlus | OpMinus | OpMul | OpDiv | OpMod | OpExp | OpEq | OpNe | OpTripleEq | OpTripleNe
+    | OpAnd | OpOr | OpLess | OpLessEq | OpGreater | OpGreaterEq | OpNullishCoalesce
+    | OpOptionalChain | OpBitAnd | OpBitOr | OpBitXor | OpLeftShift | OpRightShift
+    | OpRightShiftUnsigned | InstanceOf | In | Call | Bind | Sub | SubMov | New => &[V, V, R],
+    Delete => &[R, V, R],
+    Apply | ConstApply => &[V, R, V, R],
+    ConstSubCall | Jsx => &[V, V, V, R],
+    SubCall | ThisSubCall => &[R, V, V, R],
+    Import | ImportStar => return None,
+  })
+}
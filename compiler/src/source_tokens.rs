@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+
+use crate::source_annotations::is_ident_char;
+
+/**
+ * A token of TypeScript source, for finding things in the source without parsing it.
+ *
+ * Comments and whitespace are skipped, and literals (strings, numbers, regular expressions and the
+ * text parts of template literals) are single tokens, so identifiers and punctuation are only
+ * found in code. The `${...}` parts of template literals are code, so they are tokenized too.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token<'a> {
+  pub kind: TokenKind,
+  pub text: &'a str,
+
+  /// Byte position in the source.
+  pub pos: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+  Ident,
+
+  /// A single character, or one of `=>`, `?.` and `...`.
+  Punct,
+
+  Literal,
+}
+
+impl Token<'_> {
+  pub fn is_ident(&self, text: &str) -> bool {
+    self.kind == TokenKind::Ident && self.text == text
+  }
+
+  pub fn is_punct(&self, text: &str) -> bool {
+    self.kind == TokenKind::Punct && self.text == text
+  }
+
+  /// Position after the token.
+  pub fn end(&self) -> usize {
+    self.pos + self.text.len()
+  }
+}
+
+/// Keywords after which a `/` starts a regular expression rather than being a division.
+const REGEX_KEYWORDS: [&str; 14] = [
+  "return",
+  "typeof",
+  "instanceof",
+  "in",
+  "of",
+  "new",
+  "delete",
+  "void",
+  "throw",
+  "case",
+  "do",
+  "else",
+  "yield",
+  "await",
+];
+
+pub fn tokenize(source: &str) -> Vec<Token<'_>> {
+  let mut tokens = Vec::<Token>::new();
+
+  // For each open `{`, whether it is the `${` of a template literal
+  let mut braces = Vec::<bool>::new();
+
+  let mut i = 0;
+
+  while i < source.len() {
+    let rest = &source[i..];
+
+    let c = match rest.chars().next() {
+      Some(c) => c,
+      None => break,
+    };
+
+    if c.is_whitespace() {
+      i += c.len_utf8();
+      continue;
+    }
+
+    if rest.starts_with("//") {
+      i += rest.find('\n').unwrap_or(rest.len());
+      continue;
+    }
+
+    if rest.starts_with("/*") {
+      i += rest.find("*/").map_or(rest.len(), |end| end + 2);
+      continue;
+    }
+
+    let (kind, len) = match c {
+      '"' | '\'' => (TokenKind::Literal, string_len(rest, c)),
+      '`' => {
+        let (len, opens_expr) = template_len(rest);
+
+        if opens_expr {
+          braces.push(true);
+        }
+
+        (TokenKind::Literal, len)
+      }
+      '}' if braces.last() == Some(&true) => {
+        braces.pop();
+        let (len, opens_expr) = template_len(rest);
+
+        if opens_expr {
+          braces.push(true);
+        }
+
+        (TokenKind::Literal, len)
+      }
+      '/' if starts_regex(tokens.last()) => match regex_len(rest) {
+        Some(len) => (TokenKind::Literal, len),
+        None => (TokenKind::Punct, 1),
+      },
+      _ if c.is_ascii_digit() => (
+        TokenKind::Literal,
+        rest
+          .find(|c: char| !is_ident_char(c) && c != '.')
+          .unwrap_or(rest.len()),
+      ),
+      _ if is_ident_char(c) => (
+        TokenKind::Ident,
+        rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len()),
+      ),
+      _ => {
+        match c {
+          '{' => braces.push(false),
+          '}' => {
+            braces.pop();
+          }
+          _ => {}
+        }
+
+        let len = ["=>", "?.", "..."]
+          .iter()
+          .find(|punct| rest.starts_with(*punct))
+          .map_or(c.len_utf8(), |punct| punct.len());
+
+        (TokenKind::Punct, len)
+      }
+    };
+
+    tokens.push(Token {
+      kind,
+      text: &rest[..len],
+      pos: i,
+    });
+
+    i += len;
+  }
+
+  tokens
+}
+
+/// The length of the string literal at the start of `text`.
+fn string_len(text: &str, quote: char) -> usize {
+  let mut chars = text.char_indices().skip(1);
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' => {
+        chars.next();
+      }
+      _ if c == quote => return i + 1,
+      _ => {}
+    }
+  }
+
+  text.len()
+}
+
+/// The length of the template literal text at the start of `text` (which starts with the `` ` ``
+/// or the `}` that ends an expression), and whether it ends by opening an expression with `${`.
+fn template_len(text: &str) -> (usize, bool) {
+  let mut chars = text.char_indices().skip(1).peekable();
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' => {
+        chars.next();
+      }
+      '`' => return (i + 1, false),
+      '$' if chars.peek().map(|(_, c)| *c) == Some('{') => return (i + 2, true),
+      _ => {}
+    }
+  }
+
+  (text.len(), false)
+}
+
+/// Whether a `/` after `prev` starts a regular expression. It is a division after anything that
+/// ends an expression.
+fn starts_regex(prev: Option<&Token>) -> bool {
+  match prev {
+    None => true,
+    Some(prev) => match prev.kind {
+      TokenKind::Ident => REGEX_KEYWORDS.contains(&prev.text),
+      TokenKind::Literal => false,
+      TokenKind::Punct => !matches!(prev.text, ")" | "]" | "}"),
+    },
+  }
+}
+
+/// The length of the regular expression at the start of `text`, including its flags, or `None` if
+/// it isn't terminated on the same line.
+fn regex_len(text: &str) -> Option<usize> {
+  let mut chars = text.char_indices().skip(1);
+  let mut in_class = false;
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' => {
+        chars.next();
+      }
+      '\n' => return None,
+      '[' => in_class = true,
+      ']' => in_class = false,
+      '/' if !in_class => {
+        let flags = text[i + 1..]
+          .find(|c| !is_ident_char(c))
+          .unwrap_or(text.len() - i - 1);
+
+        return Some(i + 1 + flags);
+      }
+      _ => {}
+    }
+  }
+
+  None
+}
+
+/// The brackets of the source: for each `(`, `[` and `{`, the index of the token that closes it,
+/// and for each token, the bracket it is directly inside of.
+pub struct Groups<'a> {
+  pub tokens: &'a [Token<'a>],
+  pub source_len: usize,
+  pub close: HashMap<usize, usize>,
+  pub open: HashMap<usize, usize>,
+  pub parent: Vec<Option<usize>>,
+}
+
+impl<'a> Groups<'a> {
+  pub fn new(source: &str, tokens: &'a [Token<'a>]) -> Self {
+    let mut groups = Groups {
+      tokens,
+      source_len: source.len(),
+      close: HashMap::new(),
+      open: HashMap::new(),
+      parent: Vec::with_capacity(tokens.len()),
+    };
+
+    let mut stack = Vec::<usize>::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+      if is_close(token) {
+        if let Some(open) = stack.pop() {
+          groups.close.insert(open, i);
+          groups.open.insert(i, open);
+        }
+      }
+
+      groups.parent.push(stack.last().copied());
+
+      if is_open(token) {
+        stack.push(i);
+      }
+    }
+
+    groups
+  }
+
+  /// The index of the token after the group that starts at `open`.
+  pub fn after(&self, open: usize) -> usize {
+    self
+      .close
+      .get(&open)
+      .map_or(self.tokens.len(), |close| close + 1)
+  }
+
+  /// The byte range of the group that starts at `open`.
+  pub fn range(&self, open: usize) -> (usize, usize) {
+    let end = self
+      .close
+      .get(&open)
+      .map_or(self.source_len, |close| self.tokens[*close].end());
+
+    (self.tokens[open].pos, end)
+  }
+
+  /// The byte range of the group that token `i` is in (or the whole source), extended over the
+  /// body of a `for (...)` or `catch (...)`.
+  pub fn enclosing(&self, i: usize) -> (usize, usize) {
+    let parent = match self.parent[i] {
+      Some(parent) => parent,
+      None => return (0, self.source_len),
+    };
+
+    let (start, mut end) = self.range(parent);
+    let after = self.after(parent);
+
+    if self.tokens[parent].is_punct("(") && is_punct_at(self.tokens, after, "{") {
+      end = self.range(after).1;
+    }
+
+    (start, end)
+  }
+
+  /// The byte position where the expression starting at token `i` ends, which is at the first `;`
+  /// or `,` outside of brackets, or the end of the enclosing group. Without a `;`, this can include
+  /// the following statements.
+  pub fn expression_end(&self, mut i: usize) -> usize {
+    while let Some(token) = self.tokens.get(i) {
+      if token.is_punct(";") || token.is_punct(",") || is_close(token) {
+        return token.pos;
+      }
+
+      i = match is_open(token) {
+        true => self.after(i),
+        false => i + 1,
+      };
+    }
+
+    self.source_len
+  }
+
+  /// The byte range of a function, given the `(` of its parameters. This is `None` if the group
+  /// isn't followed by a function body (eg it is a call).
+  pub fn function_range(&self, params: usize) -> Option<(usize, usize)> {
+    let is_condition = params.checked_sub(1).is_some_and(|before| {
+      ["if", "while", "for", "switch", "with"]
+        .iter()
+        .any(|keyword| self.tokens[before].is_ident(keyword))
+    });
+
+    if is_condition {
+      return None;
+    }
+
+    let mut i = self.after(params);
+
+    // Skip the return type
+    if is_punct_at(self.tokens, i, ":") {
+      while i < self.tokens.len() && !is_punct_at(self.tokens, i, "{") {
+        if is_punct_at(self.tokens, i, "=>") {
+          break;
+        }
+
+        i = match is_open(&self.tokens[i]) {
+          true => self.after(i),
+          false => i + 1,
+        };
+      }
+    }
+
+    if is_punct_at(self.tokens, i, "=>") {
+      i += 1;
+
+      if !is_punct_at(self.tokens, i, "{") {
+        return Some((self.tokens[params].pos, self.expression_end(i)));
+      }
+    }
+
+    if !is_punct_at(self.tokens, i, "{") {
+      return None;
+    }
+
+    Some((self.tokens[params].pos, self.range(i).1))
+  }
+}
+
+pub fn is_open(token: &Token) -> bool {
+  ["(", "[", "{"].iter().any(|punct| token.is_punct(punct))
+}
+
+pub fn is_close(token: &Token) -> bool {
+  [")", "]", "}"].iter().any(|punct| token.is_punct(punct))
+}
+
+pub fn is_punct_at(tokens: &[Token], i: usize, punct: &str) -> bool {
+  tokens.get(i).is_some_and(|token| token.is_punct(punct))
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashMap, fmt};
+
+use valuescript_vm::{vs_value::Val, ValTrait};
+
+use crate::{
+  circuit_signal::CircuitSignal,
+  compile_error::{Location, SourceLocation},
+  optional_signal::OptionalSignal,
+  val_dynamic_downcast::val_dynamic_downcast,
+};
+
+/// How many levels of a signal's expression are shown by `summon.log`.
+const LOG_DEPTH: usize = 4;
+
+/// A `summon.log` call that was reached while running the program. Each branch that reaches the
+/// call produces its own record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+  /// Where `summon.log` was called, when it was called through the name that the summon module is
+  /// imported as (see call_sites.rs).
+  pub call_site: Option<SourceLocation>,
+
+  /// The function and bytecode position of the call, for when the call site isn't known.
+  pub location: Location,
+
+  /// The logged values (see `format_log`).
+  pub message: String,
+
+  /// The condition for reaching the call, when it depends on signals.
+  pub condition: Option<String>,
+}
+
+impl fmt::Display for LogRecord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.call_site {
+      Some(call_site) => write!(f, "{}", call_site)?,
+      None => write!(f, "{}", self.location)?,
+    }
+
+    write!(f, ": summon.log: {}", self.message)?;
+
+    if let Some(condition) = &self.condition {
+      write!(f, " (when {})", condition)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Formats the values passed to `summon.log`. Like `console.log`, strings are shown without
+/// quotes, so that they can be used as labels.
+pub fn format_log(args: &[Val], input_names: &HashMap<usize, String>) -> String {
+  args
+    .iter()
+    .map(|arg| match arg {
+      Val::String(string) => string.to_string(),
+      _ => describe(arg, input_names),
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Describes a value, showing the expressions of any signals it contains (see
+/// `CircuitSignal::describe`).
+pub fn describe(val: &Val, input_names: &HashMap<usize, String>) -> String {
+  if let Some(signal) = val_dynamic_downcast::<CircuitSignal>(val) {
+    return signal.describe(input_names, LOG_DEPTH);
+  }
+
+  if let Some(optional) = val_dynamic_downcast::<OptionalSignal>(val) {
+    return format!(
+      "{} if {} else {}",
+      describe(&optional.value, input_names),
+      describe(&optional.is_defined, input_names),
+      optional.nullish.codify(),
+    );
+  }
+
+  match val {
+    Val::Array(array) => format!(
+      "[{}]",
+      array
+        .elements
+        .iter()
+        .map(|element| describe(element, input_names))
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    Val::Object(object) if !object.string_map.is_empty() => format!(
+      "{{ {} }}",
+      object
+        .string_map
+        .iter()
+        .map(|(key, value)| format!("{}: {}", key, describe(value, input_names)))
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    _ => val.codify(),
+  }
+}
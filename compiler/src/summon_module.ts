@@ -0,0 +1,66 @@
+// The `summon` module, which provides compiler intrinsics:
+//
+//   import summon from "summon";
+//
+// Functions named `summon$<name>` are replaced with the corresponding intrinsic when the bytecode is
+// decoded, so these bodies are never run by the circuit compiler.
+//
+// Calls to `summon.assert` and `summon.log` are rewritten to `summon.assertAt` and `summon.logAt`
+// when modules are loaded, which also take the id of the call site (see call_sites.rs).
+
+function summon$assert(cond: unknown, message?: string) {
+  throw new Error("summon.assert is a compiler intrinsic");
+}
+
+function summon$assertAt(site: number, cond: unknown, message?: string) {
+  throw new Error("summon.assert is a compiler intrinsic");
+}
+
+/**
+ * A vector with a fixed number of slots and a length that can depend on signals (see
+ * oblivious_vec.rs). Unlike an array, `length` can be a signal, and elements can only be read and
+ * assigned by constant index.
+ */
+export interface SummonVec<T> {
+  readonly length: number;
+  readonly capacity: number;
+
+  [index: number]: T;
+
+  /** Appends the values, and returns the new length. Pushing to a full vector does nothing. */
+  push(...values: T[]): number;
+
+  /**
+   * Removes the last element and returns it. When the vector is empty, this is `undefined` (or `0`
+   * when the length is a signal).
+   */
+  pop(): T | undefined;
+
+  /** Each slot as `[valid, value]`, where `valid` is whether the slot is before the length. */
+  slots(): [boolean, T][];
+}
+
+function summon$vec<T>(capacity: number, initialValues?: T[]): SummonVec<T> {
+  throw new Error("summon.vec is a compiler intrinsic");
+}
+
+function summon$select<T>(cond: unknown, a: T, b: T): T {
+  throw new Error("summon.select is a compiler intrinsic");
+}
+
+function summon$log(...values: unknown[]) {
+  throw new Error("summon.log is a compiler intrinsic");
+}
+
+function summon$logAt(site: number, ...values: unknown[]) {
+  throw new Error("summon.log is a compiler intrinsic");
+}
+
+export default {
+  assert: summon$assert,
+  assertAt: summon$assertAt,
+  vec: summon$vec,
+  select: summon$select,
+  log: summon$log,
+  logAt: summon$logAt,
+};
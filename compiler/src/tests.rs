@@ -1,8 +1,24 @@
 #[cfg(test)]
 mod tests_ {
-  use std::{collections::HashMap, fs, path::PathBuf};
+  use std::{cell::RefCell, collections::HashMap, fs, path::PathBuf, rc::Rc};
 
-  use crate::{compile, resolve_entry_path::resolve_entry_path, CompileOk};
+  use valuescript_vm::{
+    binary_op::BinaryOp,
+    operations::{op_and, op_greater, op_mul, op_plus},
+    vs_value::{ToDynamicVal, ToVal, Val, VsType},
+  };
+
+  use crate::{
+    bristol_depth,
+    call_sites::CallSites,
+    circuit_signal::{CircuitSignal, CircuitSignalData},
+    compile, compile_all_exports, compile_with_options,
+    id_generator::IdGenerator,
+    resolve_entry_path::resolve_entry_path,
+    summon_log::{describe, LogRecord},
+    CircuitError, CompileErr, CompileLimits, CompileOk, CompileOptions, Diagnostic,
+    DiagnosticLevel, Limit, ResolvedPath, SchedulingStrategy, ValueRange,
+  };
 
   #[test]
   fn test_annotations() {
@@ -21,6 +37,7 @@ mod tests_ {
       let CompileOk {
         circuit,
         diagnostics: _,
+        logs: _,
       } = compile(path, |p| fs::read_to_string(p).map_err(|e| e.to_string()))
         .expect("Compile failed");
 
@@ -48,6 +65,1128 @@ mod tests_ {
     }
   }
 
+  #[test]
+  fn test_range_analysis() {
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(resolve_entry_path("../examples/clamp.ts"), |p| {
+      fs::read_to_string(p).map_err(|e| e.to_string())
+    })
+    .expect("Compile failed");
+
+    assert_eq!(circuit.output_ranges["main"], ValueRange::new(0, 100));
+    assert!(circuit.bit_width.is_some());
+  }
+
+  #[test]
+  fn test_bit_width_includes_constants() {
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(resolve_entry_path("../examples/wideConstant.ts"), |p| {
+      fs::read_to_string(p).map_err(|e| e.to_string())
+    })
+    .expect("Compile failed");
+
+    assert_eq!(circuit.output_ranges["main"], ValueRange::new(0, 1));
+    assert_eq!(circuit.bit_width, Some(8));
+  }
+
+  #[test]
+  fn test_annotations_skip_comments_and_strings() {
+    let source = "
+// See function main(y: number) below
+const about = 'function main(z: number /* 0..1 */)';
+
+/** @range x 0 10 */
+export default function main(x: number /* 0..100 */) {
+  return x;
+}";
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("virtual.ts", source)]),
+    )
+    .expect("Compile failed");
+
+    assert_eq!(circuit.input_domains.keys().collect::<Vec<_>>(), vec!["x"]);
+    assert_eq!(circuit.output_ranges["main"], ValueRange::new(0, 10));
+  }
+
+  #[test]
+  fn test_assertion_locations() {
+    let helper = "
+import summon from 'summon';
+
+export default function check(x: number) {
+  summon.assert(x > 0, 'x is ' + 'small');
+}";
+
+    let main = "
+import summon from 'summon';
+import check from './helper.ts';
+
+export default function main(x: number) {
+  summon.assert(x < 10, 'x is small'); check(x); summon.assert(x < 20, 'x is small');
+  return x;
+}";
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("helper.ts", helper), ("virtual.ts", main)]),
+    )
+    .expect("Compile failed");
+
+    let locations = circuit
+      .assertions
+      .iter()
+      .map(|assertion| assertion.location.clone())
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      locations,
+      vec![
+        Some(("virtual.ts".to_string(), 6, 3)),
+        Some(("helper.ts".to_string(), 5, 3)),
+        Some(("virtual.ts".to_string(), 6, 50)),
+      ]
+    );
+
+    let helper = "
+import summon from 'summon';
+
+export default function check() {
+  summon.assert(false, `never ${1}`);
+}";
+
+    let main = "
+import summon from 'summon';
+import check from './helper.ts';
+
+export default function main(x: number) {
+  summon.assert(true, 'never 1');
+  check();
+  return x;
+}";
+
+    let err = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("helper.ts", helper), ("virtual.ts", main)]),
+    )
+    .err()
+    .expect("Should fail");
+
+    let span = err.span().cloned().expect("Should have a span");
+    let start = helper.find("summon.assert").unwrap();
+
+    assert!(span.path.ends_with("helper.ts"));
+    assert_eq!(
+      (span.start, span.end),
+      (start, start + "summon.assert".len())
+    );
+  }
+
+  #[test]
+  fn test_call_site_rewriting() {
+    let source =
+      "import s from 'summon';\n// s.assert(x)\ns.assert(a, \"s.assert(\");\nx.s.assert(b);\n";
+
+    let mut call_sites = CallSites::default();
+    let rewritten = call_sites.rewrite("file.ts", source);
+
+    assert_eq!(
+      rewritten,
+      "import s from 'summon';\n// s.assert(x)\ns.assertAt(0, a, \"s.assert(\");\nx.s.assert(b);\n"
+    );
+
+    let call_site = call_sites.get(0).expect("Should find the call");
+    assert_eq!((call_site.line, call_site.column), (3, 1));
+    assert!(call_sites.get(1).is_none());
+
+    // Diagnostics of the rewritten source are mapped back to the original
+    let a_pos = |text: &str| text.find("a, ").unwrap() as u32;
+
+    let mut diagnostic = Diagnostic {
+      level: DiagnosticLevel::Error,
+      message: "".to_string(),
+      span: Default::default(),
+    };
+
+    diagnostic.span.lo.0 = a_pos(&rewritten);
+    diagnostic.span.hi.0 = a_pos(&rewritten);
+
+    let path = ResolvedPath {
+      path: "file.ts".to_string(),
+    };
+
+    let mut diagnostics = HashMap::from([(path.clone(), vec![diagnostic])]);
+    call_sites.fix_diagnostics(&mut diagnostics);
+
+    assert_eq!(diagnostics[&path][0].span.lo.0, a_pos(source));
+  }
+
+  #[test]
+  fn test_call_site_rewriting_skips_shadowed_names() {
+    let source = "import summon from 'summon';
+function a(summon) { summon.assert(x); }
+const b = (summon) => summon.assert(x);
+const c = summon => summon.log(x);
+function d({ s: summon }) { summon.assert(x); }
+try {} catch (summon) { summon.assert(x); }
+{ const summon = other; summon.assert(x); }
+for (const summon of list) { summon.assert(x); }
+let e = 1, summon2 = 2;
+summon.assert(y);
+";
+
+    let rewritten = CallSites::default().rewrite("file.ts", source);
+
+    assert_eq!(
+      rewritten,
+      source.replace("summon.assert(y)", "summon.assertAt(0, y)")
+    );
+  }
+
+  #[test]
+  fn test_call_site_rewriting_skips_literals() {
+    let source = "import summon from 'summon';
+const r = /[\"'`]/g;
+summon.assert(a);
+const t = `${b ? \"`\" : ''} summon.assert(no)`;
+const u = `${summon.log(c)}`;
+const d = e / f; summon.assert(g);
+";
+
+    let rewritten = CallSites::default().rewrite("file.ts", source);
+
+    assert_eq!(
+      rewritten,
+      source
+        .replace("summon.assert(a)", "summon.assertAt(0, a)")
+        .replace("summon.log(c)", "summon.logAt(1, c)")
+        .replace("summon.assert(g)", "summon.assertAt(2, g)")
+    );
+  }
+
+  #[test]
+  fn test_error_codes() {
+    let options = CompileOptions {
+      error_codes: true,
+      ..Default::default()
+    };
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile_with_options(
+      resolve_entry_path("../examples/uncaughtThrow.ts"),
+      &options,
+      |p| fs::read_to_string(p).map_err(|e| e.to_string()),
+    )
+    .expect("Compile failed");
+
+    assert_eq!(circuit.error_codes.len(), 1);
+    assert_eq!(circuit.error_codes[0].message, "x is too big");
+
+    for (x, error_code) in [(5, 0), (50, 1)] {
+      let outputs = circuit.eval(&HashMap::from([("x".to_string(), x)]));
+      assert_eq!(outputs["error_code"], error_code);
+    }
+  }
+
+  #[test]
+  fn test_every_branch_throws() {
+    let err = compile_err(
+      "export default function main(x: number) {
+        if (x > 10) { throw new Error('too big'); }
+        throw new Error('too small');
+      }",
+      &CompileOptions::default(),
+    );
+
+    let uncaught = match err {
+      CompileErr::UncaughtException(uncaught) => uncaught,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    let mut messages = uncaught
+      .throws
+      .iter()
+      .map(|(message, _)| message.as_str())
+      .collect::<Vec<_>>();
+
+    messages.sort();
+    assert_eq!(messages, vec!["too big", "too small"]);
+
+    for (_, site) in &uncaught.throws {
+      assert_eq!(
+        site.as_ref().map(|site| site.fn_name.as_str()),
+        Some("main")
+      );
+    }
+  }
+
+  #[test]
+  fn test_consts() {
+    let options = CompileOptions {
+      consts: HashMap::from([("iterations".to_string(), 4.0)]),
+      ..Default::default()
+    };
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile_with_options(resolve_entry_path("../examples/consts.ts"), &options, |p| {
+      fs::read_to_string(p).map_err(|e| e.to_string())
+    })
+    .expect("Compile failed");
+
+    assert_eq!(circuit.inputs.keys().collect::<Vec<_>>(), vec!["input"]);
+
+    let outputs = circuit.eval(&HashMap::from([("input".to_string(), 3)]));
+    assert_eq!(outputs["main"], 12);
+
+    let options = CompileOptions {
+      consts: HashMap::from([("n".to_string(), 200.0)]),
+      ..Default::default()
+    };
+
+    let err = compile_err(
+      "/** @range n 0 100 */
+      export default function main(n: number, x: number) { return n + x; }",
+      &options,
+    );
+
+    let messages = match err {
+      CompileErr::Diagnostics(diagnostics) => diagnostics
+        .into_values()
+        .flatten()
+        .map(|diagnostic| diagnostic.message)
+        .collect::<Vec<_>>(),
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert_eq!(
+      messages,
+      vec!["Can't bind n to 200 because it is outside its @range 0..100"]
+    );
+  }
+
+  #[test]
+  fn test_max_recursion_depth() {
+    let options = CompileOptions {
+      max_recursion_depth: Some(10),
+      ..Default::default()
+    };
+
+    let err = compile_err(
+      "function forever(n: number): number { return forever(n + 1); }
+      export default function main() { return forever(0); }",
+      &options,
+    );
+
+    let failure = match err {
+      CompileErr::Circuit(CircuitError::RecursionLimit(failure)) => failure,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert_eq!(failure.fn_name, "forever");
+    assert_eq!(failure.max_depth, 10);
+    assert_eq!(failure.call_chain.first().map(String::as_str), Some("main"));
+    assert_eq!(failure.call_chain.len(), 12);
+  }
+
+  #[test]
+  fn test_max_depth_is_per_function() {
+    let main = "
+import countDown from './other.ts';
+
+/** @maxDepth 2 */
+function helper(n: number): number {
+  if (n <= 0) { return 0; }
+  return 1 + helper(n - 1);
+}
+
+export default function main(n: number) {
+  return helper(n) + countDown(10);
+}";
+
+    // Recurses deeper than the @maxDepth on the other `helper`, which shouldn't apply to it
+    let other = "
+export default function helper(count: number): number {
+  if (count <= 0) { return 0; }
+  return 1 + helper(count - 1);
+}";
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("other.ts", other), ("virtual.ts", main)]),
+    )
+    .expect("Compile failed");
+
+    let outputs = circuit.eval(&HashMap::from([("n".to_string(), 1usize)]));
+    assert_eq!(outputs["main"], 11);
+
+    // Functions with the same name and parameters can't be told apart
+    let other = other.replace("count", "n");
+
+    let err = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("other.ts", &other), ("virtual.ts", main)]),
+    )
+    .err()
+    .expect("Should fail");
+
+    assert!(err
+      .to_string()
+      .contains("@maxDepth can't tell which function helper it is for"));
+  }
+
+  #[test]
+  fn test_step_limit() {
+    let options = CompileOptions {
+      limits: CompileLimits {
+        max_steps: Some(10_000),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    let err = compile_err(
+      "export default function main(input: number) {
+        let sum = 0;
+        for (let i = 0; i < input; i++) { sum += i; }
+        return sum;
+      }",
+      &options,
+    );
+
+    // "function" in "export default function main"
+    assert_eq!(
+      err.span().map(|span| (span.start, span.end)),
+      Some((15, 23))
+    );
+
+    // Hot spots are bytecode positions, so the source only says where their function is declared
+    assert!(err.to_string().contains("main (declared at "));
+
+    let limit_exceeded = match err {
+      CompileErr::LimitExceeded(limit_exceeded) => limit_exceeded,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert!(matches!(limit_exceeded.limit, Limit::Steps(10_000)));
+    assert_eq!(limit_exceeded.hot_spots[0].0.fn_name, "main");
+    assert!(!limit_exceeded.fork_sites.is_empty());
+
+    // The bytecode doesn't have a source map, so hot spots are located by their function
+    let source = limit_exceeded.hot_spots[0].0.source.as_ref().unwrap();
+    assert_eq!((source.line, source.column), (1, 16));
+  }
+
+  #[test]
+  fn test_non_converging_loop() {
+    let options = CompileOptions {
+      limits: CompileLimits {
+        max_loop_forks: Some(20),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    let err = compile_err(
+      "export default function main(x: number, n: number) {
+        let sum = 0;
+        for (let i = 0; i < 3; i++) { if (x > i) { sum += x; } }
+        for (let i = 0; i < n; i++) { sum += i; }
+        return sum;
+      }",
+      &options,
+    );
+
+    let non_converging_loop = match err {
+      CompileErr::NonConvergingLoop(non_converging_loop) => non_converging_loop,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert_eq!(non_converging_loop.location.fn_name, "main");
+    assert_eq!(non_converging_loop.inputs, vec!["n".to_string()]);
+    assert_eq!(non_converging_loop.forks, 21);
+  }
+
+  #[test]
+  fn test_long_loop_with_early_return() {
+    // This forks 2000 times in a row, but the loop condition doesn't depend on a signal, so the
+    // forks aren't counted
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[(
+        "virtual.ts",
+        "export default function main(x: number) {
+          for (let i = 0; i < 2000; i++) {
+            if (i === x) { return i; }
+          }
+
+          return 5000;
+        }",
+      )]),
+    )
+    .expect("Compile failed");
+
+    for (x, expected) in [(1500, 1500), (3000, 5000)] {
+      let outputs = circuit.eval(&HashMap::from([("x".to_string(), x)]));
+      assert_eq!(outputs["main"], expected);
+    }
+  }
+
+  #[test]
+  fn test_merge_divergent_callers() {
+    // Each branch of `main` and of `pick` calls a different helper, at different depths, so the
+    // branches only meet again after returning to their callers
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[(
+        "virtual.ts",
+        "function square(a: number) {
+          return a * a;
+        }
+
+        function viaOuter(a: number) {
+          const b = a + 1;
+          return square(b) + b;
+        }
+
+        function pick(a: number, b: number) {
+          const k = a - b;
+          return a > 2 * b ? viaOuter(k) : square(k);
+        }
+
+        export default function main(x: number, y: number) {
+          const k = x + y;
+          const r = x > y ? pick(x, y) : square(y - x);
+          return r * 10 + k;
+        }",
+      )]),
+    )
+    .expect("Compile failed");
+
+    for (x, y, expected) in [(9, 3, 572), (5, 3, 48), (2, 6, 168), (4, 4, 8)] {
+      let outputs = circuit.eval(&HashMap::from([("x".to_string(), x), ("y".to_string(), y)]));
+
+      assert_eq!(outputs["main"], expected);
+    }
+  }
+
+  #[test]
+  fn test_loop_with_constant_bound() {
+    // The fix suggested by `NonConvergingLoop`, with a bound above the old default of 1000
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[(
+        "virtual.ts",
+        "export default function main(n: number) {
+          let sum = 0;
+
+          for (let i = 0; i < 2000 && i < n; i++) {
+            sum += i;
+          }
+
+          return sum;
+        }",
+      )]),
+    )
+    .expect("Compile failed");
+
+    for (n, expected) in [(10, 45), (1500, 1124250), (3000, 1999000)] {
+      let outputs = circuit.eval(&HashMap::from([("n".to_string(), n)]));
+      assert_eq!(outputs["main"], expected);
+    }
+  }
+
+  #[test]
+  fn test_scheduling_strategies() {
+    let read_file = |p: &str| fs::read_to_string(p).map_err(|e| e.to_string());
+
+    for scheduling_strategy in [
+      SchedulingStrategy::EarliestPosition,
+      SchedulingStrategy::MostForksFirst,
+      SchedulingStrategy::MergeEagerly,
+    ] {
+      let options = CompileOptions {
+        scheduling_strategy,
+        ..Default::default()
+      };
+
+      let CompileOk {
+        circuit,
+        diagnostics: _,
+        logs: _,
+      } = compile_with_options(
+        resolve_entry_path("../examples/earlyReturnLoop.ts"),
+        &options,
+        read_file,
+      )
+      .expect("Compile failed");
+
+      for (values, expected) in [([3, 5, 7, 5], 1), ([5, 5, 7, 5], 0), ([3, 5, 7, 9], 3)] {
+        let inputs = ["a", "b", "c", "target"]
+          .iter()
+          .zip(values)
+          .map(|(name, value)| (name.to_string(), value))
+          .collect::<HashMap<_, _>>();
+
+        assert_eq!(
+          circuit.eval(&inputs)["main"],
+          expected,
+          "{:?}",
+          scheduling_strategy
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_merge_eagerly_circuit_size() {
+    // The branches that return merge with each other before the loop finishes, so only one
+    // selection between 1 and 0 is needed, instead of one for each return
+    let source =
+      "export default function main(a: number, b: number, c: number, d: number, x: number) {
+      const values = [a, b, c, d];
+
+      for (let i = 0; i < values.length; i++) {
+        if (values[i] === x) { return 1; }
+      }
+
+      return 0;
+    }";
+
+    let gate_count = |scheduling_strategy| {
+      let options = CompileOptions {
+        scheduling_strategy,
+        ..Default::default()
+      };
+
+      let CompileOk {
+        circuit,
+        diagnostics: _,
+        logs: _,
+      } = compile_with_options(
+        resolve_entry_path("virtual.ts"),
+        &options,
+        virtual_files(&[("virtual.ts", source)]),
+      )
+      .expect("Compile failed");
+
+      circuit.to_bristol().gates.len()
+    };
+
+    let eager_gates = gate_count(SchedulingStrategy::MergeEagerly);
+    let default_gates = gate_count(SchedulingStrategy::EarliestPosition);
+
+    assert!(
+      eager_gates < default_gates,
+      "{} >= {}",
+      eager_gates,
+      default_gates
+    );
+  }
+
+  #[test]
+  fn test_describe_signal() {
+    let id_gen = Rc::new(RefCell::new(IdGenerator::new()));
+    let x = CircuitSignal::new(&id_gen, VsType::Number, CircuitSignalData::Input);
+    let input_names = HashMap::from([(x.id, "x".to_string())]);
+    let x = x.to_dynamic_val();
+
+    let val = op_mul(&op_plus(&x, &1.0.to_val()).unwrap(), &x).unwrap();
+    assert_eq!(describe(&val, &input_names), "((x + 1) * x)");
+
+    let arr = vec![val, 3.0.to_val()].to_val();
+    assert_eq!(describe(&arr, &input_names), "[((x + 1) * x), 3]");
+  }
+
+  #[test]
+  fn test_incompatible_logical_types() {
+    let id_gen = Rc::new(RefCell::new(IdGenerator::new()));
+    let x = CircuitSignal::new(&id_gen, VsType::Number, CircuitSignalData::Input).to_dynamic_val();
+    let is_positive = op_greater(&x, &0.0.to_val()).unwrap();
+
+    let is_incompatible = |err: &Val| {
+      matches!(
+        CircuitError::from_val(err),
+        Some(CircuitError::IncompatibleTypes(
+          VsType::Bool,
+          VsType::Number
+        ))
+      )
+    };
+
+    let err = op_and(&is_positive, &x).expect_err("Should fail");
+    assert!(is_incompatible(&err));
+
+    // Signals built by rewrites (eg `(y && a) || (y && b)`) are checked too
+    let err = CircuitSignal::from_op(
+      &id_gen,
+      CircuitSignalData::BinaryOp(BinaryOp::And, is_positive, x),
+    )
+    .expect_err("Should fail");
+
+    assert!(is_incompatible(&err));
+  }
+
+  #[test]
+  fn test_log_records() {
+    let helper = "
+import summon from 'summon';
+
+export default function double(x: number) {
+  summon.log('x', x);
+  return 2 * x;
+}";
+
+    let main = "
+import summon from 'summon';
+import double from './helper.ts';
+
+export default function main(x: number) {
+  if (x < 10) {
+    summon.log('x', x);
+  }
+
+  return double(x);
+}";
+
+    let CompileOk {
+      circuit: _,
+      diagnostics: _,
+      logs,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[("helper.ts", helper), ("virtual.ts", main)]),
+    )
+    .expect("Compile failed");
+
+    let location = |log: &LogRecord| {
+      let call_site = log.call_site.as_ref().expect("Should have a call site");
+      (
+        call_site.span.path.clone(),
+        call_site.line,
+        call_site.column,
+      )
+    };
+
+    // The label is in both files, so only the call site can tell the calls apart
+    let first = logs.first().expect("Should log");
+    assert!(location(first).0.ends_with("virtual.ts"));
+    assert_eq!((location(first).1, location(first).2), (7, 5));
+    assert_eq!(first.message, "x x");
+
+    let condition = first.condition.as_deref().expect("Should be conditional");
+    assert!(condition.contains("x") && condition.contains("10"));
+    assert!(first
+      .to_string()
+      .ends_with(&format!(": summon.log: x x (when {})", condition)));
+
+    let last = logs.last().unwrap();
+    assert!(location(last).0.ends_with("helper.ts"));
+    assert_eq!((location(last).1, location(last).2), (5, 3));
+    assert_eq!(last.condition, None);
+    assert!(last.to_string().ends_with("helper.ts:5:3: summon.log: x x"));
+
+    // Logs from before a failure are kept
+    let err = compile_err(
+      "
+import summon from 'summon';
+
+export default function main(x: number) {
+  summon.log('before', x);
+  throw new Error('fails');
+}",
+      &CompileOptions::default(),
+    );
+
+    assert!(matches!(err, CompileErr::Logged(..)));
+    assert_eq!(
+      err
+        .logs()
+        .iter()
+        .map(|log| log.message.as_str())
+        .collect::<Vec<_>>(),
+      vec!["before x"]
+    );
+  }
+
+  #[test]
+  fn test_exports() {
+    let read_file = |p: &str| fs::read_to_string(p).map_err(|e| e.to_string());
+    let inputs = HashMap::from([("a".to_string(), 3), ("b".to_string(), 4)]);
+
+    let options = CompileOptions {
+      entry: Some("mul".to_string()),
+      ..Default::default()
+    };
+
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile_with_options(
+      resolve_entry_path("../examples/multipleExports.ts"),
+      &options,
+      read_file,
+    )
+    .expect("Compile failed");
+
+    assert_eq!(circuit.eval(&inputs)["mul"], 12);
+
+    let entries = compile_all_exports(
+      resolve_entry_path("../examples/multipleExports.ts"),
+      &CompileOptions::default(),
+      read_file,
+    )
+    .expect("Compile failed")
+    .entries;
+
+    let outputs = entries
+      .into_iter()
+      .map(|(export_name, result)| {
+        let circuit = result.expect("Compile failed").circuit;
+        (export_name, circuit.eval(&inputs))
+      })
+      .collect::<HashMap<_, _>>();
+
+    assert_eq!(outputs["default"]["main"], 7);
+    assert_eq!(outputs["add"]["add"], 7);
+    assert_eq!(outputs["mul"]["mul"], 12);
+  }
+
+  #[test]
+  fn test_compile_errors() {
+    let compile_source = |source: &str| compile_err(source, &CompileOptions::default());
+
+    let err =
+      compile_source("export default function main(x: number) { throw new Error('nope'); }");
+
+    let uncaught = match err {
+      CompileErr::UncaughtException(uncaught) => uncaught,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert_eq!(uncaught.throws.len(), 1);
+    assert_eq!(uncaught.throws[0].0, "nope");
+    assert!(uncaught.throws[0].1.is_some());
+
+    let err = compile_source("export default function main(x: number) { return [1, 2][x]; }");
+
+    assert!(matches!(
+      err,
+      CompileErr::Circuit(CircuitError::SignalAsIndex)
+    ));
+
+    // `some` isn't replaced with a signal-aware version, so it can't use the signal it gets back
+    let err = compile_source(
+      "export default function main(x: number) { return [1, 2].some(v => v === x); }",
+    );
+
+    assert!(matches!(
+      err,
+      CompileErr::Circuit(CircuitError::SignalAsCondition)
+    ));
+
+    // The number of keys depends on a signal
+    let err = compile_source(
+      "export default function main(x: number) { const obj: { a?: number } = {}; if (x > 1) { obj.a = x; } return Object.keys(obj).length; }",
+    );
+
+    assert!(err
+      .to_string()
+      .contains("Object.keys can't list the key \"a\""));
+
+    // Whether `y` is defined is a signal, so its type isn't known
+    let err = compile_source(
+      "export default function main(x: number) { let y = undefined; if (x > 1) { y = x; } return typeof y === 'undefined'; }",
+    );
+
+    assert!(err.to_string().contains("Cannot use typeof"));
+
+    let err = compile_source("export default function main(x: number) { return x * 0.5; }");
+
+    assert!(matches!(
+      err,
+      CompileErr::Circuit(CircuitError::NonIntegerConstant(_))
+    ));
+
+    let err = compile_source(
+      "export default function main(x: number) { let y: any = 0; if (x > 1) { y = [1]; } return y; }",
+    );
+
+    assert!(matches!(
+      err,
+      CompileErr::Circuit(CircuitError::CouldNotMerge(_))
+    ));
+
+    let err = compile_source(
+      "export default function main(x: number) {
+        let arr = [1];
+        if (x > 1) { arr = [1, 2]; }
+        return arr[0];
+      }",
+    );
+
+    let failure = match err {
+      CompileErr::Circuit(CircuitError::CouldNotMerge(failure)) => failure,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    assert_eq!(failure.reason, "array of length 1 vs array of length 2");
+    assert!(failure.fork_pos.is_some());
+    assert!(failure.merge_pos.is_some());
+    assert_eq!(failure.variable.as_deref(), Some("arr"));
+    assert_eq!(failure.fn_name.as_deref(), Some("main"));
+
+    let location = failure.location.as_ref().unwrap();
+    assert_eq!((location.line, location.column), (1, 16));
+
+    // The fork is the `if`, and the branches merge at the end of the `if` statement
+    let fork_location = failure.fork_location.as_ref().unwrap();
+    assert_eq!((fork_location.line, fork_location.column), (3, 9));
+
+    let merge_location = failure.merge_location.as_ref().unwrap();
+    assert_eq!((merge_location.line, merge_location.column), (3, 36));
+  }
+
+  #[test]
+  fn test_merge_failure_location() {
+    let err = compile_err(
+      "export default function main(x: number, y: number) {
+        const z = x > y ? x : y;
+        let arr = [z];
+
+        if (
+          z > 10
+        ) {
+          arr = [z, z];
+        }
+
+        return arr[0];
+      }",
+      &CompileOptions::default(),
+    );
+
+    let failure = match &err {
+      CompileErr::Circuit(CircuitError::CouldNotMerge(failure)) => failure,
+      err => panic!("Unexpected error: {}", err),
+    };
+
+    let fork_location = failure.fork_location.as_ref().unwrap();
+    assert!(fork_location.span.path.ends_with("virtual.ts"));
+    assert_eq!((fork_location.line, fork_location.column), (5, 9));
+
+    let merge_location = failure.merge_location.as_ref().unwrap();
+    assert_eq!((merge_location.line, merge_location.column), (9, 9));
+
+    assert!(err.to_string().contains("virtual.ts:5:9"));
+  }
+
+  #[test]
+  fn test_sort_network_size() {
+    let compile_sort = |main: &str| {
+      let CompileOk {
+        circuit,
+        diagnostics: _,
+        logs: _,
+      } = compile(
+        resolve_entry_path("virtual.ts"),
+        virtual_files(&[
+          (
+            "lib/batcherSort.ts",
+            include_str!("../../examples/lib/batcherSort.ts"),
+          ),
+          ("virtual.ts", main),
+        ]),
+      )
+      .expect("Compile failed");
+
+      let bristol_circuit = circuit.to_bristol();
+
+      (bristol_depth(&bristol_circuit), bristol_circuit.gates.len())
+    };
+
+    let (depth, gates) = compile_sort(
+      "export default function main(a: number, b: number, c: number, d: number, e: number, f: number, g: number, h: number) {
+        return [a, b, c, d, e, f, g, h].sort((x, y) => x - y);
+      }",
+    );
+
+    let (library_depth, library_gates) = compile_sort(
+      "import batcherSort from './lib/batcherSort.ts';
+
+      export default function main(a: number, b: number, c: number, d: number, e: number, f: number, g: number, h: number) {
+        return batcherSort([a, b, c, d, e, f, g, h]);
+      }",
+    );
+
+    assert!(depth <= library_depth, "{} > {}", depth, library_depth);
+    assert!(gates <= library_gates, "{} > {}", gates, library_gates);
+  }
+
+  #[test]
+  fn test_switch_rule_table_size() {
+    let gate_count = |main: &str| {
+      let CompileOk {
+        circuit,
+        diagnostics: _,
+        logs: _,
+      } = compile(
+        resolve_entry_path("virtual.ts"),
+        virtual_files(&[("virtual.ts", main)]),
+      )
+      .expect("Compile failed");
+
+      circuit.to_bristol().gates.len()
+    };
+
+    // The cases of the switch are merged at once, with a flag for each case
+    let switch_gates = gate_count(include_str!(
+      "../../examples/rockPaperScissorsLizardSpock.ts"
+    ));
+
+    // The same rules checked in a loop, which merges each early return separately
+    let loop_gates = gate_count(
+      "const ROCK = 1;
+      const PAPER = 2;
+      const SCISSORS = 3;
+      const LIZARD = 4;
+      const SPOCK = 5;
+
+      const winConditions = [
+        [SCISSORS, /* cuts */ PAPER],
+        [PAPER, /* covers */ ROCK],
+        [ROCK, /* crushes */ LIZARD],
+        [LIZARD, /* poisons */ SPOCK],
+        [SPOCK, /* smashes */ SCISSORS],
+        [SCISSORS, /* decapitates */ LIZARD],
+        [LIZARD, /* eats */ PAPER],
+        [PAPER, /* disproves */ SPOCK],
+        [SPOCK, /* vaporizes */ ROCK],
+        [ROCK, /* crushes */ SCISSORS],
+      ];
+
+      export default function main(player1: number, player2: number) {
+        const player1Invalid = player1 < 1 || player1 > 5;
+        const player2Invalid = player2 < 1 || player2 > 5;
+
+        if (player1Invalid && player2Invalid) {
+          return 0;
+        }
+
+        if (player1Invalid) {
+          return 2;
+        }
+
+        if (player2Invalid) {
+          return 1;
+        }
+
+        for (const [winningChoice, losingChoice] of winConditions) {
+          if (player1 === winningChoice && player2 === losingChoice) {
+            return 1;
+          }
+
+          if (player2 === winningChoice && player1 === losingChoice) {
+            return 2;
+          }
+        }
+
+        return 0;
+      }",
+    );
+
+    assert!(
+      switch_gates < loop_gates,
+      "{} >= {}",
+      switch_gates,
+      loop_gates
+    );
+  }
+
+  #[test]
+  fn test_constant_sort_is_stable() {
+    let CompileOk {
+      circuit,
+      diagnostics: _,
+      logs: _,
+    } = compile(
+      resolve_entry_path("virtual.ts"),
+      virtual_files(&[(
+        "virtual.ts",
+        "export default function main(x: number) {
+          const items = [{ k: 1, id: 0 }, { k: 0, id: 1 }, { k: 1, id: 2 }, { k: 0, id: 3 }];
+          const sorted = items.sort((a, b) => a.k - b.k);
+          return x + 1000 * sorted[0].id + 100 * sorted[1].id + 10 * sorted[2].id + sorted[3].id;
+        }",
+      )]),
+    )
+    .expect("Compile failed");
+
+    let outputs = circuit.eval(&HashMap::from([("x".to_string(), 0usize)]));
+
+    assert_eq!(outputs["main"], 1302);
+  }
+
+  /// Compiles `source` as the entry point, expecting it to fail.
+  fn compile_err(source: &str, options: &CompileOptions) -> CompileErr {
+    compile_with_options(
+      resolve_entry_path("virtual.ts"),
+      options,
+      virtual_files(&[("virtual.ts", source)]),
+    )
+    .err()
+    .expect("Should fail")
+  }
+
+  /// Reads files from `files` (file name -> source) instead of the file system.
+  fn virtual_files<'a>(
+    files: &'a [(&'a str, &'a str)],
+  ) -> impl Fn(&str) -> Result<String, String> + 'a {
+    move |path| {
+      files
+        .iter()
+        .find(|(name, _)| path.ends_with(&format!("/{}", name)))
+        .map(|(_, source)| source.to_string())
+        .ok_or_else(|| format!("No such file: {}", path))
+    }
+  }
+
   #[derive(Debug)]
   struct TestCase {
     path: String,
@@ -0,0 +1,121 @@
+use std::collections::{BinaryHeap, HashMap};
+
+use valuescript_vm::vs_value::Val;
+
+use crate::{
+  circuit_vm::merge_branches, circuit_vm_branch::CircuitVMBranch, loop_detector::LoopDetector,
+};
+
+/**
+ * The branches that are waiting for their turn, ordered so that the next one to run is first (see
+ * `CircuitVMBranch`'s `Ord`).
+ *
+ * When the scheduler merges eagerly, a branch that arrives where another branch is already waiting
+ * is merged with it straight away. Finding the waiting branch in the heap would need a linear
+ * search, so instead the branches that arrive are merged with each other in `arrivals`, and then
+ * with the waiting branch when it is popped. This doesn't change the order of the heap, since
+ * schedulers that merge eagerly only compare positions.
+ */
+#[derive(Default)]
+pub struct WaitingBranches {
+  heap: BinaryHeap<CircuitVMBranch>,
+
+  /// The number of branches in `heap` at each location (see `CircuitVMBranch::location_key`), for
+  /// branches that merge eagerly.
+  locations: HashMap<Vec<usize>, usize>,
+
+  /// Branches that arrived at the location of a branch in `heap`, merged together.
+  arrivals: HashMap<Vec<usize>, CircuitVMBranch>,
+}
+
+impl WaitingBranches {
+  /// The branch to run next. Branches that arrived at its location are merged into it when it is
+  /// popped.
+  pub fn peek(&self) -> Option<&CircuitVMBranch> {
+    self.heap.peek()
+  }
+
+  pub fn pop(&mut self, loop_detector: &mut LoopDetector) -> Result<Option<CircuitVMBranch>, Val> {
+    let mut branch = match self.heap.pop() {
+      Some(branch) => branch,
+      None => return Ok(None),
+    };
+
+    let location = match eager_location(&branch) {
+      Some(location) => location,
+      None => return Ok(Some(branch)),
+    };
+
+    if let Some(count) = self.locations.get_mut(&location) {
+      *count -= 1;
+
+      if *count == 0 {
+        self.locations.remove(&location);
+      }
+    }
+
+    if let Some(arrival) = self.arrivals.remove(&location) {
+      if !merge_branches(&mut branch, &arrival, loop_detector)? {
+        self.push(arrival);
+      }
+    }
+
+    Ok(Some(branch))
+  }
+
+  /// Adds a branch without merging it.
+  pub fn push(&mut self, branch: CircuitVMBranch) {
+    if let Some(location) = eager_location(&branch) {
+      *self.locations.entry(location).or_default() += 1;
+    }
+
+    self.heap.push(branch);
+  }
+
+  /// Adds a branch, merging it with a waiting branch at the same location if the scheduler merges
+  /// eagerly.
+  pub fn push_merging(
+    &mut self,
+    mut branch: CircuitVMBranch,
+    loop_detector: &mut LoopDetector,
+  ) -> Result<(), Val> {
+    let location = match eager_location(&branch) {
+      Some(location) if self.locations.contains_key(&location) => location,
+      _ => {
+        self.push(branch);
+        return Ok(());
+      }
+    };
+
+    match self.arrivals.remove(&location) {
+      Some(arrival) => {
+        if merge_branches(&mut branch, &arrival, loop_detector)? {
+          self.arrivals.insert(location, branch);
+        } else {
+          self.arrivals.insert(location, arrival);
+          self.push(branch);
+        }
+      }
+      None => {
+        self.arrivals.insert(location, branch);
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn len(&self) -> usize {
+    self.heap.len() + self.arrivals.len()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &CircuitVMBranch> {
+    self.heap.iter().chain(self.arrivals.values())
+  }
+}
+
+fn eager_location(branch: &CircuitVMBranch) -> Option<Vec<usize>> {
+  match branch.scheduler.merges_eagerly() {
+    true => branch.location_key(),
+    false => None,
+  }
+}
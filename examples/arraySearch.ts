@@ -0,0 +1,14 @@
+//! test [7] => [2421]
+//! test [4] => [2005]
+//! test [20] => [3000]
+
+const primes = [2, 3, 5, 7, 11];
+
+export default function main(x: number) {
+  const firstSquareAbove = primes.findIndex((p) => p * p > x);
+  const firstAbove = primes.find((p) => p > x) ?? 0;
+  const position = primes.indexOf(x) + 1;
+  const isPrime = primes.includes(x) ? 1 : 0;
+
+  return 1000 * (firstSquareAbove + 1) + 100 * position + 10 * isPrime + firstAbove;
+}
@@ -0,0 +1,10 @@
+//! test [5] => [10, 1]
+//! test [50] => [100, 0]
+
+import summon from "summon";
+
+export default function main(x: number) {
+  summon.assert(x < 20, "x must be less than 20");
+
+  return 2 * x;
+}
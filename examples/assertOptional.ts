@@ -0,0 +1,17 @@
+//! test [5, 3] => [5, 1]
+//! test [2, 3] => [0, 0]
+
+import summon from "summon";
+
+export default function main(x: number, y: number) {
+  let best: number | undefined = undefined;
+
+  if (x > y) {
+    best = x;
+  }
+
+  // Asserts that `best` is defined (and non-zero)
+  summon.assert(best, "x must be greater than y");
+
+  return best ?? 0;
+}
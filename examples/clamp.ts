@@ -0,0 +1,10 @@
+//! test [42] => [42]
+//! test [150] => [100]
+
+export default function main(x: number /* 0..1000 */) {
+  if (x > 100) {
+    return 100;
+  }
+
+  return x;
+}
@@ -0,0 +1,11 @@
+// Compile with `--const iterations=N` to choose the number of iterations.
+
+export default function main(iterations: number, input: number) {
+  let res = 0;
+
+  for (let i = 0; i < iterations; i++) {
+    res += input;
+  }
+
+  return res;
+}
@@ -0,0 +1,24 @@
+//! test [3, 4] => [7]
+//! test [5, 4] => [20]
+
+class Adder {
+  apply(a: number, b: number) {
+    return a + b;
+  }
+}
+
+class Multiplier {
+  apply(a: number, b: number) {
+    return a * b;
+  }
+}
+
+export default function main(a: number, b: number) {
+  let op: Adder | Multiplier = new Adder();
+
+  if (a > b) {
+    op = new Multiplier();
+  }
+
+  return op.apply(a, b);
+}
@@ -0,0 +1,16 @@
+//! test [3, 5, 7, 5] => [1]
+//! test [3, 5, 7, 3] => [0]
+//! test [5, 5, 7, 5] => [0]
+//! test [3, 5, 7, 9] => [3]
+
+export default function main(a: number, b: number, c: number, target: number) {
+  const values = [a, b, c];
+
+  for (let i = 0; i < values.length; i++) {
+    if (values[i] === target) {
+      return i;
+    }
+  }
+
+  return values.length;
+}
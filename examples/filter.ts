@@ -0,0 +1,20 @@
+//! test [3, 12, 15] => [2027]
+//! test [3, 5, 7] => [0]
+//! test [20, 30, 40] => [3090]
+
+import type { SummonVec } from "summon";
+
+export default function main(a: number, b: number, c: number) {
+  // When the predicate depends on signals, filter returns a summon.vec
+  const bigInputs = [a, b, c].filter((x) => x > 10) as unknown as SummonVec<number>;
+
+  let sum = 0;
+
+  for (const [valid, x] of bigInputs.slots()) {
+    if (valid) {
+      sum += x;
+    }
+  }
+
+  return 1000 * bigInputs.length + sum;
+}
@@ -0,0 +1,19 @@
+//! test [2] => [2, 1, 1, 1]
+//! test [3] => [3, 1, 1, 1]
+//! test [5] => [0, 1, 1, 1]
+//! test [0] => [0, 0, 0, 0]
+
+import summon from "summon";
+
+export default function main(x: number) {
+  const values = [1, 2, 3];
+
+  // The predicate is only called until an element matches, so the assertion isn't checked for
+  // the elements after x
+  const i = values.findIndex((v) => {
+    summon.assert(v <= x, "reached an element after x");
+    return v === x;
+  });
+
+  return i + 1;
+}
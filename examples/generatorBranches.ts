@@ -0,0 +1,22 @@
+//! test [3, 12, 15] => [23]
+//! test [1, 2, 3] => [6]
+
+function* clamped(values: number[], max: number) {
+  for (let i = 0; i < values.length; i++) {
+    if (values[i] > max) {
+      yield max;
+    } else {
+      yield values[i];
+    }
+  }
+}
+
+export default function main(a: number, b: number, c: number) {
+  let sum = 0;
+
+  for (const x of clamped([a, b, c], 10)) {
+    sum += x;
+  }
+
+  return sum;
+}
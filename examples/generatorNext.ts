@@ -0,0 +1,20 @@
+//! test [3, 4] => [7]
+//! test [10, 5] => [15]
+
+function* runningTotal() {
+  let total = 0;
+
+  while (true) {
+    const x: number = yield total;
+    total += x;
+  }
+}
+
+export default function main(a: number, b: number) {
+  let totals = runningTotal();
+
+  totals.next();
+  totals.next(a);
+
+  return totals.next(b).value;
+}
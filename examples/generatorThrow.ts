@@ -0,0 +1,35 @@
+//! test [3] => [5, 0, 0]
+//! test [15] => [15, 1, 1]
+
+function* countdown(n: number) {
+  yield n;
+
+  if (n > 10) {
+    throw new Error("too high");
+  }
+
+  yield n - 1;
+}
+
+function* withZero(n: number) {
+  yield* countdown(n);
+  yield 0;
+}
+
+export default function main(n: number) {
+  let gen = withZero(n);
+  let sum = 0;
+  let caught = 0;
+
+  try {
+    sum += gen.next().value ?? 0;
+    sum += gen.next().value ?? 0;
+  } catch {
+    caught = 1;
+  }
+
+  // The generator is finished after throwing, so it doesn't yield 0
+  const after = gen.next();
+
+  return [sum, caught, after.done ? 1 : 0];
+}
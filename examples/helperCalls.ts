@@ -0,0 +1,16 @@
+//! test [5, 3] => [11]
+//! test [2, 3] => [10]
+
+function double(x: number) {
+  return 2 * x;
+}
+
+function square(x: number) {
+  return x * x;
+}
+
+export default function main(x: number, y: number) {
+  const res = x > y ? double(x) : square(y);
+
+  return res + 1;
+}
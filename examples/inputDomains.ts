@@ -0,0 +1,11 @@
+//! test [3, 5] => [1, 8]
+//! test [3, 60] => [0, 0]
+
+/**
+ * @range a 0 51
+ * @range b 0 51
+ * @validate gate
+ */
+export default function main(a: number, b: number) {
+  return a + b;
+}
@@ -0,0 +1,17 @@
+//! test [3, 4] => [12]
+//! test [5, 2] => [5]
+
+import summon from "summon";
+
+export default function main(a: number, b: number) {
+  let res = a;
+
+  if (a < b) {
+    res = a * b;
+    summon.log("product", res);
+  }
+
+  summon.log("res", res);
+
+  return res;
+}
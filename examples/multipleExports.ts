@@ -0,0 +1,15 @@
+//! test [3, 4] => [7]
+
+// Compile other exports with `--entry mul` or `--all-exports`.
+
+export default function main(a: number, b: number) {
+  return add(a, b);
+}
+
+export function add(a: number, b: number) {
+  return a + b;
+}
+
+export function mul(a: number, b: number) {
+  return a * b;
+}
@@ -0,0 +1,16 @@
+//! test [5, 3] => [1, 0]
+//! test [2, 3] => [0, 0]
+
+export default function main(x: number, y: number) {
+  const flags: { seen?: undefined } = {};
+
+  if (x > y) {
+    flags.seen = undefined;
+  }
+
+  // `in` checks whether the key is present, even though its value is undefined in both branches
+  const hasSeen = "seen" in flags ? 1 : 0;
+  const seenIsDefined = flags.seen !== undefined ? 1 : 0;
+
+  return [hasSeen, seenIsDefined];
+}
@@ -0,0 +1,14 @@
+//! test [5, 3] => [113]
+//! test [2, 3] => [5]
+
+export default function main(x: number, y: number) {
+  const result: { max?: number; sum: number } = { sum: x + y };
+
+  if (x > y) {
+    result.max = x;
+  }
+
+  const hasMax = "max" in result ? 100 : 0;
+
+  return hasMax + (result.max ?? 0) + result.sum;
+}
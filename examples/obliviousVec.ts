@@ -0,0 +1,26 @@
+//! test [3, 12, 15] => [2, 27]
+//! test [3, 5, 7] => [0, 0]
+//! test [20, 30, 40] => [3, 90]
+
+import summon from "summon";
+
+export default function main(a: number, b: number, c: number) {
+  const inputs = [a, b, c];
+  let bigInputs = summon.vec<number>(3);
+
+  for (let i = 0; i < inputs.length; i++) {
+    if (inputs[i] > 10) {
+      bigInputs.push(inputs[i]);
+    }
+  }
+
+  let sum = 0;
+
+  for (const [valid, x] of bigInputs.slots()) {
+    if (valid) {
+      sum += x;
+    }
+  }
+
+  return [bigInputs.length, sum];
+}
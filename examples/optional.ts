@@ -0,0 +1,12 @@
+//! test [5, 3] => [1, 5]
+//! test [2, 3] => [0, 0]
+
+export default function main(x: number, y: number) {
+  let best: number | undefined = undefined;
+
+  if (x > y) {
+    best = x;
+  }
+
+  return best;
+}
@@ -0,0 +1,20 @@
+//! test [5, 3] => [6]
+//! test [2, 3] => [100]
+
+export default function main(x: number, y: number) {
+  const best = findGreater(x, y);
+
+  if (best === undefined) {
+    return 100;
+  }
+
+  return (best ?? 0) + 1;
+}
+
+function findGreater(x: number, y: number) {
+  if (x > y) {
+    return x;
+  }
+
+  return undefined;
+}
@@ -0,0 +1,17 @@
+//! test [3] => [0, 6]
+//! test [4] => [1, 0]
+
+/**
+ * @maxDepth 4
+ */
+function sumTo(n: number): number {
+  if (n <= 0) {
+    return 0;
+  }
+
+  return n + sumTo(n - 1);
+}
+
+export default function main(n: number) {
+  return sumTo(n);
+}
@@ -0,0 +1,19 @@
+//! test [3, 12, 15] => [30, 23]
+//! test [1, 2, 3] => [6, 6]
+
+export default function main(a: number, b: number, c: number) {
+  const scores = [a, b, c];
+
+  const capped = scores.map((s) => {
+    if (s > 10) {
+      return 10;
+    }
+
+    return s;
+  });
+
+  return [
+    scores.reduce((x, y) => x + y, 0),
+    capped.reduce((x, y) => x + y),
+  ];
+}
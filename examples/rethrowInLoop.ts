@@ -0,0 +1,30 @@
+//! test [1, 2, 3] => [0, 6]
+//! test [1, 12, 3] => [0, 16]
+//! test [1, 20, 3] => [2, 1]
+//! test [20, 1, 1] => [1, 0]
+
+export default function main(a: number, b: number, c: number) {
+  const values = [a, b, c];
+  let sum = 0;
+
+  try {
+    for (let i = 0; i < values.length; i++) {
+      try {
+        if (values[i] > 10) {
+          throw { index: i };
+        }
+      } catch (e) {
+        // Small overflows are ok
+        if (values[i] > 15) {
+          throw e;
+        }
+      }
+
+      sum += values[i];
+    }
+  } catch (e) {
+    return [e.index + 1, sum];
+  }
+
+  return [0, sum];
+}
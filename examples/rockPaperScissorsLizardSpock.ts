@@ -3,24 +3,7 @@
 //! test [2, 5] => [1]
 //! test [5, 2] => [2]
 
-const ROCK = 1;
-const PAPER = 2;
-const SCISSORS = 3;
-const LIZARD = 4;
-const SPOCK = 5;
-
-const winConditions = [
-  [SCISSORS, /* cuts */ PAPER],
-  [PAPER, /* covers */ ROCK],
-  [ROCK, /* crushes */ LIZARD],
-  [LIZARD, /* poisons */ SPOCK],
-  [SPOCK, /* smashes */ SCISSORS],
-  [SCISSORS, /* decapitates */ LIZARD],
-  [LIZARD, /* eats */ PAPER],
-  [PAPER, /* disproves */ SPOCK],
-  [SPOCK, /* vaporizes */ ROCK],
-  [ROCK, /* crushes */ SCISSORS],
-];
+// ROCK = 1, PAPER = 2, SCISSORS = 3, LIZARD = 4, SPOCK = 5
 
 export default function main(player1: number, player2: number) {
   const player1Invalid = player1 < 1 || player1 > 5;
@@ -38,15 +21,36 @@ export default function main(player1: number, player2: number) {
     return 1;
   }
 
-  for (const [winningChoice, losingChoice] of winConditions) {
-    if (player1 === winningChoice && player2 === losingChoice) {
-      return 1;
-    }
-
-    if (player2 === winningChoice && player1 === losingChoice) {
-      return 2;
-    }
+  let winner = 0;
+
+  // Each case is a pair of choices, eg 32 is SCISSORS (player 1) against PAPER (player 2)
+  switch (10 * player1 + player2) {
+    case 32: // scissors cuts paper
+    case 21: // paper covers rock
+    case 14: // rock crushes lizard
+    case 45: // lizard poisons spock
+    case 53: // spock smashes scissors
+    case 34: // scissors decapitates lizard
+    case 42: // lizard eats paper
+    case 25: // paper disproves spock
+    case 51: // spock vaporizes rock
+    case 13: // rock crushes scissors
+      winner = 1;
+      break;
+
+    case 23:
+    case 12:
+    case 41:
+    case 54:
+    case 35:
+    case 43:
+    case 24:
+    case 52:
+    case 15:
+    case 31:
+      winner = 2;
+      break;
   }
 
-  return 0;
+  return winner;
 }
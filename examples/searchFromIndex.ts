@@ -0,0 +1,15 @@
+//! test [2] => [0, 0, 2]
+//! test [7] => [4, 1, 2]
+
+const primes = [2, 3, 5, 7, 11];
+
+export default function main(x: number) {
+  // The search starts at index 2, so 2 and 3 aren't found
+  const position = primes.indexOf(x, 2) + 1;
+  const isLaterPrime = primes.includes(x, -3) ? 1 : 0;
+
+  // Arrays without signals are searched the same way
+  const constantIndex = [1, 2, 1].indexOf(1, 1);
+
+  return [position, isLaterPrime, constantIndex];
+}
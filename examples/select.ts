@@ -0,0 +1,11 @@
+//! test [3, 8] => [5]
+//! test [9, 2] => [7]
+
+import summon from "summon";
+
+export default function main(a: number, b: number) {
+  const max = summon.select(a > b, a, b);
+  const min = a < b ? a : b;
+
+  return max - min;
+}
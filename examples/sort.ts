@@ -0,0 +1,6 @@
+//! test [3, 1, 4, 2] => [1, 2, 3, 4]
+//! test [9, 9, 0, 5] => [0, 5, 9, 9]
+
+export default function main(a: number, b: number, c: number, d: number) {
+  return [a, b, c, d].sort((x, y) => x - y);
+}
@@ -0,0 +1,14 @@
+//! test [30, 10, 20] => [1, 2, 0]
+//! test [5, 7, 6] => [0, 2, 1]
+
+export default function main(a: number, b: number, c: number) {
+  let people = [
+    { id: 0, age: a },
+    { id: 1, age: b },
+    { id: 2, age: c },
+  ];
+
+  people = people.sort((x, y) => x.age - y.age);
+
+  return [people[0].id, people[1].id, people[2].id];
+}
@@ -0,0 +1,34 @@
+//! test [1] => [10]
+//! test [2] => [20]
+//! test [3] => [25]
+//! test [4] => [50]
+//! test [5] => [50]
+//! test [7] => [1]
+
+export default function main(x: number) {
+  let points = 0;
+
+  switch (x) {
+    case 1:
+      points = 10;
+      break;
+
+    case 2:
+      points = 20;
+      break;
+
+    case 3:
+      points = 25;
+      break;
+
+    case 4:
+    case 5:
+      points = 50;
+      break;
+
+    default:
+      points = 1;
+  }
+
+  return points;
+}
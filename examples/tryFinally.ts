@@ -0,0 +1,22 @@
+//! test [3] => [101]
+//! test [15] => [115]
+
+export default function main(x: number) {
+  let log = 0;
+
+  try {
+    try {
+      if (x > 10) {
+        throw { code: x };
+      }
+
+      log += 1;
+    } finally {
+      log += 100;
+    }
+  } catch (e) {
+    log += e.code;
+  }
+
+  return log;
+}
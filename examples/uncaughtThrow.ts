@@ -0,0 +1,10 @@
+//! test [5] => [0, 10]
+//! test [50] => [1, 0]
+
+export default function main(x: number) {
+  if (x > 10) {
+    throw new Error("x is too big");
+  }
+
+  return 2 * x;
+}
@@ -0,0 +1,7 @@
+//! test [50] => [0]
+//! test [100] => [0]
+
+export default function main(x: number /* 0..100 */) {
+  // x fits in 7 bits, but 200 needs 8, so the circuit does too
+  return x > 200;
+}